@@ -1,17 +1,70 @@
-use std::sync::atomic::AtomicUsize;
+use std::{
+    fmt,
+    future::Future,
+    io::SeekFrom,
+    net::SocketAddr,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
+use futures_util::{stream::FuturesUnordered, StreamExt};
 use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::{broadcast, mpsc, watch, Mutex, Semaphore},
+    task::JoinSet,
+};
+use tokio_util::sync::CancellationToken;
 
-use crate::{peer::Peer, torrent::Torrent};
+use crate::{
+    dns::{Resolver, SharedResolver},
+    lsd::{self, Lsd},
+    magnet::Magnet,
+    peer::{from_bitfield, to_bitfield, Peer, PeerChoking, PeerOptions, PeerStatus, SharedPieces},
+    picker::{PiecePicker, PieceStrategy},
+    pool::{IpFamily, PeerPool, PeerState},
+    rate::RateLimiter,
+    source::{discover_all, PeerSource},
+    torrent::Torrent,
+    tracker::{Event, TrackerRequest, TrackerState},
+    webseed,
+};
 
 pub struct Client<'a> {
+    torrent: &'a Torrent,
+    config: Config,
+    // Shared by every tracker request so connections get pooled
+    http: reqwest::Client,
+    tracker: TrackerState,
+    pool: PeerPool,
     peers: Vec<Peer>,
-    file: File<'a>,
+    file: File,
     data: Data,
+    // Pieces zero-filled by a best effort download
+    failed_pieces: Vec<usize>,
+    // Observed by every spawned peer task
+    shutdown: CancellationToken,
+    // Handed out to callers who want to stop a download between two pieces
+    cancel: CancellationToken,
+    events: broadcast::Sender<PieceCompleted>,
+    paused: PauseHandle,
+    // Peers the periodic local discovery announcer heard of since we last looked
+    lsd_peers: Option<mpsc::Receiver<SocketAddr>>,
 }
-pub struct File<'a> {
-    file_name: &'a str,
+
+impl Drop for Client<'_> {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+pub struct File {
+    file_name: String,
     total_size: usize,
     downloaded: AtomicUsize,
 }
@@ -20,21 +73,488 @@ pub struct Data {
     piece_count: usize,
     piece_hashes: Vec<String>,
     plength: usize,
+    // Pieces downloaded and verified so far
+    have: Vec<bool>,
 }
 
-impl<'a> Client<'a> {
-    pub async fn new(torrent: &'a Torrent) -> anyhow::Result<Self> {
-        let peer_addrs = torrent.peers().await?;
+pub struct Config {
+    max_retries: u32,
+    backoff: Duration,
+    deadline: Option<Duration>,
+    best_effort: bool,
+    max_connecting: usize,
+    // Connected peers the client keeps at most, extra addresses wait in the pool
+    max_peers: usize,
+    // Peer addresses given by the user, tried before any discovered one
+    explicit_peers: Vec<SocketAddr>,
+    no_tracker: bool,
+    lsd: bool,
+    // Re-announces when not a single peer could be connected to
+    connect_retries: u32,
+    numwant: Option<usize>,
+    user_agent: String,
+    tracker_proxy: Option<String>,
+    offload_hashing: bool,
+    compact: bool,
+    rotate_peer_id_after: Option<u32>,
+    verify_on_complete: bool,
+    peer_ip_family: IpFamily,
+    // Extra peer discovery on top of the torrent's own tracker
+    sources: Vec<Box<dyn PeerSource>>,
+    max_buffered_pieces: usize,
+    // Order pieces are fetched in, the streaming download is always sequential
+    picker: Box<dyn PiecePicker>,
+    dns_resolver: Arc<dyn reqwest::dns::Resolve>,
+    // Open connections allowed across every client of a session
+    connection_budget: Option<Arc<Semaphore>>,
+    // Pieces being fetched at once, possibly shared with the other clients of a session
+    piece_slots: Option<Arc<Semaphore>>,
+    // Port other peers can reach us on, announced to trackers and LSD
+    port: u16,
+    mismatch_policy: MismatchPolicy,
+    peer: PeerOptions,
+}
+
+pub const DEFAULT_USER_AGENT: &str = "codecrafters-torrent/0.1";
+
+const WEBSEED_ATTEMPTS: usize = 2;
+
+// The stopped announce is a courtesy, shutdown doesn't wait on a slow tracker for longer
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(2);
+// Peers drop connections that stay silent for two minutes
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(60);
+// A seeder whose announce failed tries again after this, instead of right away
+const SEED_ANNOUNCE_RETRY: Duration = Duration::from_secs(60);
+// Announced when no listener port is given, nothing accepts connections on it
+const DEFAULT_PORT: u16 = 6681;
+
+#[derive(Debug)]
+pub struct DeadlineExceeded(pub Duration);
+
+impl fmt::Display for DeadlineExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download didn't finish within {:?}", self.0)
+    }
+}
+
+impl std::error::Error for DeadlineExceeded {}
+
+// Returned when a download is stopped through `Client::cancel_handle`, with what was fetched so far
+#[derive(Debug)]
+pub struct Cancelled(pub Vec<u8>);
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Download was cancelled after {} bytes", self.0.len())
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+// Can be cloned out of the client, so another task can pause a download that holds it
+#[derive(Debug, Clone)]
+pub struct PauseHandle(watch::Sender<bool>);
+
+impl PauseHandle {
+    /// The download stops requesting pieces after the current one, connections stay open
+    pub fn pause(&self) {
+        self.0.send_replace(true);
+    }
+    pub fn resume(&self) {
+        self.0.send_replace(false);
+    }
+    pub fn is_paused(&self) -> bool {
+        *self.0.borrow()
+    }
+}
+
+// What to do with a peer whose piece fails the hash check
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MismatchPolicy {
+    // Asks the same peer again up to n more times, a single bad block may be a fluke
+    RetrySamePeer(u32),
+    // Drops the peer and asks another one, it may come back from the pool later
+    #[default]
+    SwitchPeer,
+    // Drops the peer and never connects to it again
+    BanPeer,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadOutcome {
+    pub data: Vec<u8>,
+    // Pieces that couldn't be fetched, zero-filled in `data`
+    pub missing: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceCompleted {
+    pub index: usize,
+    // Peer the piece came from, `None` when a web seed served it
+    pub peer: Option<SocketAddr>,
+}
+
+pub struct ClientBuilder<'a> {
+    torrent: &'a Torrent,
+    config: Config,
+}
+
+impl<'a> ClientBuilder<'a> {
+    pub fn new(torrent: &'a Torrent) -> Self {
+        Self {
+            torrent,
+            config: Config {
+                max_retries: 5,
+                backoff: Duration::from_secs(1),
+                deadline: None,
+                best_effort: false,
+                max_connecting: 20,
+                max_peers: 50,
+                explicit_peers: Vec::new(),
+                no_tracker: false,
+                lsd: false,
+                connect_retries: 3,
+                numwant: None,
+                user_agent: DEFAULT_USER_AGENT.to_string(),
+                tracker_proxy: None,
+                offload_hashing: true,
+                compact: true,
+                rotate_peer_id_after: None,
+                verify_on_complete: false,
+                peer_ip_family: IpFamily::Both,
+                sources: Vec::new(),
+                max_buffered_pieces: 4,
+                picker: PieceStrategy::default().picker(),
+                dns_resolver: Arc::new(Resolver),
+                connection_budget: None,
+                piece_slots: None,
+                port: DEFAULT_PORT,
+                mismatch_policy: MismatchPolicy::default(),
+                peer: PeerOptions::default(),
+            },
+        }
+    }
+    /// How many times a piece nobody can serve is retried before giving up
+    pub fn max_retries(mut self, max_retries: u32) -> Self {
+        self.config.max_retries = max_retries;
+        self
+    }
+    /// Initial wait between retries, doubled after every failed attempt
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.config.backoff = backoff;
+        self
+    }
+    /// Overall time limit for a download, after which it fails with `DeadlineExceeded`
+    pub fn deadline(mut self, deadline: Duration) -> Self {
+        self.config.deadline = Some(deadline);
+        self
+    }
+    /// How a peer that served data failing the piece hash is dealt with
+    pub fn mismatch_policy(mut self, policy: MismatchPolicy) -> Self {
+        self.config.mismatch_policy = policy;
+        self
+    }
+    /// Zero-fill pieces that can't be downloaded and verified instead of failing the download
+    pub fn best_effort(mut self, best_effort: bool) -> Self {
+        self.config.best_effort = best_effort;
+        self
+    }
+    /// How many peer connection attempts may be in flight at once
+    pub fn max_connecting(mut self, max_connecting: usize) -> Self {
+        self.config.max_connecting = max_connecting.max(1);
+        self
+    }
+    /// How many peers the client stays connected to at most
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.config.max_peers = max_peers.max(1);
+        self
+    }
+    /// Starts from a magnet link for `torrent`, its `x.pe` peers are queued ahead of anything
+    /// the trackers or other sources find
+    pub fn from_magnet(torrent: &'a Torrent, magnet: &Magnet) -> anyhow::Result<Self> {
+        if magnet.info_hash != torrent.info_hash()? {
+            bail!("Magnet link is for another torrent than the one given");
+        }
+        Ok(Self::new(torrent).peers(magnet.peers.iter().copied()))
+    }
+    /// Connect to this peer directly, on top of the ones trackers hand out
+    pub fn peer(mut self, addr: SocketAddr) -> Self {
+        self.config.explicit_peers.push(addr);
+        self
+    }
+    /// Connect to all of these directly, e.g. the `x.pe` peers of a magnet link
+    pub fn peers(mut self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        self.config.explicit_peers.extend(addrs);
+        self
+    }
+    /// Never announce, peers only come from `peer` and other peer sources
+    pub fn no_tracker(mut self, no_tracker: bool) -> Self {
+        self.config.no_tracker = no_tracker;
+        self
+    }
+    /// Look for peers on the local network too, re-announcing there every 5 minutes. Ignored
+    /// for private torrents
+    pub fn lsd(mut self, lsd: bool) -> Self {
+        self.config.lsd = lsd;
+        self
+    }
+    /// How many times to re-announce for fresh peers when none of them can be reached
+    pub fn connect_retries(mut self, connect_retries: u32) -> Self {
+        self.config.connect_retries = connect_retries;
+        self
+    }
+    /// How many peers to ask trackers for, they use their own default otherwise
+    pub fn numwant(mut self, numwant: usize) -> Self {
+        self.config.numwant = Some(numwant);
+        self
+    }
+    /// User-Agent sent with every tracker request
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.config.user_agent = user_agent.into();
+        self
+    }
+    /// Proxy URL tracker requests go through, e.g. `http://proxy:3128`
+    pub fn tracker_proxy(mut self, url: impl Into<String>) -> Self {
+        self.config.tracker_proxy = Some(url.into());
+        self
+    }
+    /// SOCKS5 proxy peer connections are tunnelled through
+    pub fn socks5_proxy(mut self, addr: SocketAddr) -> Self {
+        self.config.peer.socks5_proxy = Some(addr);
+        self
+    }
+    /// Verify piece hashes on the blocking thread pool instead of the async runtime
+    pub fn offload_hashing(mut self, offload_hashing: bool) -> Self {
+        self.config.offload_hashing = offload_hashing;
+        self
+    }
+    /// Ask trackers for the compact peer list, some only support the dictionary form
+    pub fn compact(mut self, compact: bool) -> Self {
+        self.config.compact = compact;
+        self
+    }
+    /// Announce with a fresh peer_id after this many tracker failures in a row.
+    /// Non-standard, only meant for trackers that rate-limit a peer_id after a hiccup
+    pub fn rotate_peer_id_after(mut self, failures: u32) -> Self {
+        self.config.rotate_peer_id_after = Some(failures.max(1));
+        self
+    }
+    /// Re-check every piece hash against the assembled content once a full download finishes
+    pub fn verify_on_complete(mut self, verify_on_complete: bool) -> Self {
+        self.config.verify_on_complete = verify_on_complete;
+        self
+    }
+    /// Only connect to peers of this address family, e.g. to avoid a broken IPv6 path
+    pub fn peer_ip_family(mut self, family: IpFamily) -> Self {
+        self.config.peer_ip_family = family;
+        self
+    }
+    /// Also look for peers through this source, on every announce
+    pub fn peer_source(mut self, source: impl PeerSource + 'static) -> Self {
+        self.config.sources.push(Box::new(source));
+        self
+    }
+    /// How many finished pieces a streaming download holds before waiting on the consumer
+    pub fn max_buffered_pieces(mut self, max_buffered_pieces: usize) -> Self {
+        self.config.max_buffered_pieces = max_buffered_pieces.max(1);
+        self
+    }
+    /// Order `download_to_vec` and `download_file` fetch pieces in
+    pub fn piece_strategy(mut self, strategy: PieceStrategy) -> Self {
+        self.config.picker = strategy.picker();
+        self
+    }
+    /// Picks the fetch order with a custom picker instead of one of the built-in strategies
+    pub fn piece_picker(mut self, picker: impl PiecePicker + 'static) -> Self {
+        self.config.picker = Box::new(picker);
+        self
+    }
+    /// Resolver for tracker hostnames, the default one resolves asynchronously
+    pub fn dns_resolver(mut self, resolver: impl reqwest::dns::Resolve + 'static) -> Self {
+        self.config.dns_resolver = Arc::new(resolver);
+        self
+    }
+    /// Hard cap on open peer connections over the whole download, reconnects included
+    pub fn max_connections(self, max: usize) -> Self {
+        self.connection_budget(Arc::new(Semaphore::new(max.max(1))))
+    }
+    /// Every open peer connection holds a permit of `budget` until it's closed
+    pub fn connection_budget(mut self, budget: Arc<Semaphore>) -> Self {
+        self.config.connection_budget = Some(budget);
+        self
+    }
+    /// Port announced to trackers, the one a listener for incoming peers is bound to, e.g.
+    /// `listener.local_addr()?.port()` after binding to port 0
+    pub fn announce_port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+    /// Every piece being fetched holds a permit of `slots` until it's verified or given up on.
+    /// A lone client fetches one piece at a time anyway, this bounds the clients sharing `slots`,
+    /// see `Session::max_concurrent_pieces`
+    pub fn piece_slots(mut self, slots: Arc<Semaphore>) -> Self {
+        self.config.piece_slots = Some(slots);
+        self
+    }
+    /// Drops peers that send nothing within `timeout` of the handshake, so a silent peer
+    /// costs little of the startup
+    pub fn bitfield_timeout(mut self, timeout: Duration) -> Self {
+        self.config.peer.bitfield_timeout = Some(timeout);
+        self
+    }
+    /// How long a peer may sit on requested blocks before it gets fewer requests at a time,
+    /// 30 seconds by default
+    pub fn block_timeout(mut self, timeout: Duration) -> Self {
+        self.config.peer.block_timeout = Some(timeout);
+        self
+    }
+    /// How long a choking peer gets to unchoke us before the piece is asked of another one,
+    /// 5 seconds by default. The peer is kept and asked again after the next backoff
+    pub fn unchoke_timeout(mut self, timeout: Duration) -> Self {
+        self.config.peer.unchoke_timeout = Some(timeout);
+        self
+    }
+    /// Initial size of each peer connection's read buffer
+    pub fn read_buffer(mut self, bytes: usize) -> Self {
+        self.config.peer.read_buffer = Some(bytes);
+        self
+    }
+    /// Kernel receive and send buffer sizes of every peer socket, the OS may round them
+    pub fn socket_buffers(mut self, recv: u32, send: u32) -> Self {
+        self.config.peer.socket_recv_buffer = Some(recv);
+        self.config.peer.socket_send_buffer = Some(send);
+        self
+    }
+    /// Answers peers' requests with the pieces verified by this client, kept in memory for
+    /// it. Pieces restored from a resume state aren't served
+    pub fn serve_pieces(mut self, serve: bool) -> Self {
+        self.config.peer.serve_pieces = serve.then(SharedPieces::default);
+        self
+    }
+    /// Caps upload bandwidth across all peer connections, unlimited by default
+    pub fn upload_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.config.peer.upload_limit = Some(Arc::new(RateLimiter::new(bytes_per_sec)));
+        self
+    }
+    /// Shares `limit` with every other client given the same one, e.g. across a session
+    pub fn upload_limit(mut self, limit: Arc<RateLimiter>) -> Self {
+        self.config.peer.upload_limit = Some(limit);
+        self
+    }
+    /// Local address to bind outbound peer connections to
+    pub fn bind_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.peer.bind_addr = Some(addr);
+        self
+    }
+    /// Negotiate MSE encrypted connections with peers that support it
+    pub fn prefer_encryption(mut self, prefer_encryption: bool) -> Self {
+        self.config.peer.prefer_encryption = prefer_encryption;
+        self
+    }
+    pub async fn build(self) -> anyhow::Result<Client<'a>> {
+        let torrent = self.torrent;
+        torrent.validate()?;
         let info_hash = torrent.info_hash()?;
         let total_size = torrent.length();
-
-        let mut peers = Vec::new();
-        for addr in peer_addrs {
-            let peer = Peer::new(addr, &info_hash).await?;
-            peers.push(peer);
+        let mut config = self.config;
+        // Nothing to announce to, peers can only come from the other sources
+        if torrent.trackers().is_empty() {
+            config.no_tracker = true;
+        }
+        config.peer.metadata = Some(Arc::new(torrent.info_bytes()?));
+        if config.lsd && !torrent.is_private() {
+            config.sources.push(Box::new(Lsd::new()));
+        }
+        // Tracker redirects are followed by hand so the announce parameters survive them
+        let mut http = reqwest::Client::builder()
+            .user_agent(&config.user_agent)
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(SharedResolver(config.dns_resolver.clone())));
+        if let Some(proxy) = &config.tracker_proxy {
+            http = http.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        let http = http.build()?;
+        let mut tracker = TrackerState::default();
+        let mut request = torrent.tracker_request();
+        request.compact = config.compact as u8;
+        request.numwant = config.numwant;
+        request.port = config.port;
+        // Empty content is complete from the start, there is nothing to ask peers for
+        let mut pool = PeerPool::with_family(config.peer_ip_family);
+        if total_size > 0 {
+            // Explicit peers go first, they're usually the reason they were given
+            pool.extend(config.explicit_peers.iter().copied());
+            if !config.no_tracker {
+                request.event = Some(Event::Started);
+                match torrent.announce(&http, &request).await {
+                    Ok(response) => {
+                        tracker.record(&response);
+                        pool.extend(response.resolve_addrs_with(&*config.dns_resolver).await);
+                    }
+                    // Given peers, other sources or web seeds may do without the tracker
+                    Err(err) => {
+                        tracker.record_failure(config.rotate_peer_id_after);
+                        let other_sources = !config.explicit_peers.is_empty()
+                            || !config.sources.is_empty()
+                            || !torrent.webseeds().is_empty();
+                        if !other_sources {
+                            return Err(err);
+                        }
+                    }
+                }
+            }
+            pool.extend(discover_all(&config.sources, info_hash, &request).await);
+        }
+        let shutdown = CancellationToken::new();
+        let have = vec![false; torrent.piece_count()];
+        let peers = connect_peers(
+            &mut pool,
+            info_hash,
+            &have,
+            &config,
+            config.max_peers,
+            &shutdown,
+        )
+        .await;
+        let mut peers: Vec<Peer> = peers.into_iter().flatten().collect();
+        // Stale tracker data is common, ask for fresh peers before giving up
+        request.event = None;
+        let mut announces = 1;
+        while peers.is_empty() && total_size > 0 && torrent.webseeds().is_empty() {
+            if config.no_tracker {
+                bail!("None of the given peers is reachable");
+            }
+            if announces > config.connect_retries {
+                bail!("No reachable peers after {} announces", announces);
+            }
+            announces += 1;
+            tracker.wait_for_announce().await;
+            match torrent.announce(&http, &request).await {
+                Ok(response) => {
+                    tracker.record(&response);
+                    pool.extend(response.resolve_addrs_with(&*config.dns_resolver).await);
+                }
+                // Counts against `connect_retries` like an announce with only dead peers
+                Err(_) => {
+                    if tracker.record_failure(config.rotate_peer_id_after) {
+                        request.peer_id = tracker.peer_id().unwrap_or_default().to_string();
+                    }
+                    continue;
+                }
+            }
+            let retried = connect_peers(
+                &mut pool,
+                info_hash,
+                &have,
+                &config,
+                config.max_peers,
+                &shutdown,
+            )
+            .await;
+            peers.extend(retried.into_iter().flatten());
         }
         let file = File {
-            file_name: &torrent.info.name,
+            file_name: torrent.display_name(),
             total_size,
             downloaded: AtomicUsize::new(0),
         };
@@ -44,36 +564,2461 @@ impl<'a> Client<'a> {
                 piece_count: hashes.len(),
                 piece_hashes: hashes,
                 plength: torrent.info.plength,
+                have,
             }
         };
-        Ok(Self { peers, file, data })
+        // Room for every piece, so a subscriber that reads late doesn't miss any
+        let (events, _) = broadcast::channel(data.piece_count.max(1));
+        // The first announce went out with the discovery above, this keeps it going
+        let lsd_peers = (config.lsd && !torrent.is_private()).then(|| {
+            Lsd::new().spawn_announcer(
+                info_hash,
+                config.port,
+                lsd::ANNOUNCE_INTERVAL,
+                shutdown.child_token(),
+            )
+        });
+        Ok(Client {
+            torrent,
+            config,
+            http,
+            tracker,
+            pool,
+            peers,
+            file,
+            data,
+            failed_pieces: Vec::new(),
+            shutdown,
+            cancel: CancellationToken::new(),
+            events,
+            paused: PauseHandle(watch::channel(false).0),
+            lsd_peers,
+        })
+    }
+}
+
+impl<'a> Client<'a> {
+    pub async fn new(torrent: &'a Torrent) -> anyhow::Result<Self> {
+        ClientBuilder::new(torrent).build().await
+    }
+    /// Connects only to `peers`, without ever announcing to a tracker
+    pub async fn with_peers(torrent: &'a Torrent, peers: Vec<SocketAddr>) -> anyhow::Result<Self> {
+        ClientBuilder::new(torrent)
+            .peers(peers)
+            .no_tracker(true)
+            .build()
+            .await
+    }
+    /// Stops every peer task and closes all connections, the client can't download afterwards
+    pub async fn shutdown(&mut self) {
+        let first = !self.shutdown.is_cancelled();
+        self.shutdown.cancel();
+        if first && self.tracker.has_announced() {
+            let mut request = self.tracker_request();
+            request.event = Some(Event::Stopped);
+            request.numwant = Some(0);
+            // Best effort, an unreachable tracker mustn't fail the shutdown
+            let _ =
+                tokio::time::timeout(STOPPED_TIMEOUT, self.torrent.announce(&self.http, &request))
+                    .await;
+        }
+        for mut peer in self.peers.drain(..) {
+            let _ = peer.shutdown().await;
+        }
+    }
+    /// Cancelling the token stops the running download right away, dropping the piece in flight,
+    /// closes every connection and makes the download fail with `Cancelled`
+    pub fn cancel_handle(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+    pub fn total_pieces(&self) -> usize {
+        self.data.piece_count
+    }
+    pub fn total_size(&self) -> usize {
+        self.file.total_size
+    }
+    /// Size of piece `idx`, the last one is usually shorter, 0 past the last piece
+    pub fn piece_length(&self, idx: usize) -> usize {
+        if idx >= self.data.piece_count {
+            0
+        } else if idx == self.data.piece_count - 1 {
+            self.file.total_size - self.data.plength * idx
+        } else {
+            self.data.plength
+        }
+    }
+    pub fn pause_handle(&self) -> PauseHandle {
+        self.paused.clone()
+    }
+    pub fn pause(&self) {
+        self.paused.pause();
+    }
+    pub fn resume(&self) {
+        self.paused.resume();
     }
-    pub async fn download_file(&mut self) -> anyhow::Result<Vec<u8>> {
+    /// Receives a `PieceCompleted` for every piece verified from then on
+    pub fn subscribe(&self) -> broadcast::Receiver<PieceCompleted> {
+        self.events.subscribe()
+    }
+    /// How many connected peers advertise each piece
+    pub fn availability(&self) -> Vec<u16> {
+        let mut counts = vec![0u16; self.data.piece_count];
+        for peer in &self.peers {
+            for &idx in &peer.pieces {
+                if let Some(count) = counts.get_mut(idx as usize) {
+                    *count = count.saturating_add(1);
+                }
+            }
+        }
+        counts
+    }
+    /// Bytes verified so far and total size of every file, in torrent order
+    pub fn file_progress(&self) -> Vec<(PathBuf, usize, usize)> {
+        let plength = self.data.plength;
+        self.torrent
+            .files()
+            .into_iter()
+            .map(|file| {
+                let end = file.offset + file.length;
+                // A piece straddling a file boundary only counts for the bytes inside the file
+                let done = (file.offset / plength..end.div_ceil(plength))
+                    .filter(|idx| self.data.have.get(*idx).copied().unwrap_or(false))
+                    .map(|idx| {
+                        let start = (idx * plength).max(file.offset);
+                        let stop = ((idx + 1) * plength).min(end);
+                        stop.saturating_sub(start)
+                    })
+                    .sum();
+                (file.path, done, file.length)
+            })
+            .collect()
+    }
+    /// Seeders and leechers reported by the last announce
+    pub fn swarm(&self) -> (Option<usize>, Option<usize>) {
+        self.tracker.swarm()
+    }
+    /// Warning message the tracker sent with its last announce
+    pub fn tracker_warning(&self) -> Option<&str> {
+        self.tracker.warning()
+    }
+    /// The peer_id announced in place of the torrent's, once `rotate_peer_id_after` kicked in
+    pub fn rotated_peer_id(&self) -> Option<&str> {
+        self.tracker.peer_id()
+    }
+    /// Peers that are never connected to again, for corrupt data or failing to serve pieces
+    pub fn banned_peers(&self) -> Vec<SocketAddr> {
+        self.pool.addrs_in(PeerState::Unreliable)
+    }
+    pub fn have_bitfield(&self) -> Vec<u8> {
+        to_bitfield(&self.data.have)
+    }
+    pub fn peers_status(&self) -> Vec<PeerStatus> {
+        self.peers.iter().map(Peer::status).collect()
+    }
+    pub fn failed_pieces(&self) -> &[usize] {
+        &self.failed_pieces
+    }
+    /// Bytes not downloaded and verified yet, what the tracker gets as `left`
+    pub fn bytes_left(&self) -> usize {
+        self.file
+            .total_size
+            .saturating_sub(self.file.downloaded.load(Ordering::Relaxed))
+    }
+    pub fn file_name(&self) -> &str {
+        &self.file.file_name
+    }
+    /// Downloads the whole content into memory without touching the filesystem
+    pub async fn download_to_vec(&mut self) -> anyhow::Result<Vec<u8>> {
+        let buffer = self
+            .fetch_pieces(0..self.data.piece_count, self.config.best_effort)
+            .await?;
+        self.check_length(buffer.len())?;
+        // Zero-filled pieces are known to be wrong, only check what was downloaded
+        if self.config.verify_on_complete && self.failed_pieces.is_empty() {
+            if let Err(failed) = self.verify_complete(&buffer) {
+                bail!("Assembled content fails the hash of pieces {:?}", failed);
+            }
+        }
+        Ok(buffer)
+    }
+    /// Never fails over pieces nobody could provide, they're zero-filled in the data and listed
+    /// in the outcome so the caller keeps everything else
+    pub async fn download_partial(&mut self) -> anyhow::Result<DownloadOutcome> {
+        let before = self.failed_pieces.len();
+        let data = self.fetch_pieces(0..self.data.piece_count, true).await?;
+        Ok(DownloadOutcome {
+            data,
+            missing: self.failed_pieces[before..].to_vec(),
+        })
+    }
+    /// Re-slices the assembled content at piece boundaries and checks every piece in order
+    pub fn verify_complete(&self, data: &[u8]) -> Result<(), Vec<usize>> {
+        if data.len() != self.file.total_size {
+            return Err((0..self.data.piece_count).collect());
+        }
+        let failed: Vec<usize> = data
+            .chunks(self.data.plength)
+            .zip(&self.data.piece_hashes)
+            .enumerate()
+            .filter(|(_, (piece, hash))| sha1_hex(piece) != **hash)
+            .map(|(idx, _)| idx)
+            .collect();
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(failed)
+        }
+    }
+    /// Hands every piece to `consume` in order as soon as it's verified, fetching pauses
+    /// while `max_buffered_pieces` pieces are waiting to be consumed
+    pub async fn download_streaming<F, Fut>(&mut self, mut consume: F) -> anyhow::Result<()>
+    where
+        F: FnMut(usize, Vec<u8>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let (tx, mut rx) = mpsc::channel(self.config.max_buffered_pieces);
+        let shutdown = self.shutdown.clone();
         let piece_count = self.data.piece_count;
-        let mut buffer: Vec<u8> = Vec::new();
-        for idx in 0..piece_count {
-            let plength = if idx == piece_count - 1 {
-                self.file.total_size - self.data.plength * (piece_count as u64 - 1) as usize
-            } else {
-                self.data.plength
+        let fetch = async {
+            for idx in 0..piece_count {
+                self.wait_while_paused().await;
+                if self.cancel.is_cancelled() {
+                    bail!(Cancelled(Vec::new()));
+                }
+                let slice = self.fetch_piece(idx).await?;
+                // Only fails once the consumer gave up, which reports its own error
+                if tx.send((idx, slice)).await.is_err() {
+                    break;
+                }
+            }
+            drop(tx);
+            anyhow::Ok(())
+        };
+        let drain = async {
+            while let Some((idx, slice)) = rx.recv().await {
+                if let Err(err) = consume(idx, slice).await {
+                    rx.close();
+                    return Err(err);
+                }
+            }
+            Ok(())
+        };
+        let result = tokio::select! {
+            _ = shutdown.cancelled() => Err(anyhow!("Client was shut down")),
+            result = async { tokio::try_join!(fetch, drain) } => result.map(|_| ()),
+        };
+        if result.is_err() {
+            self.shutdown().await;
+        }
+        result
+    }
+    /// Writes to `<path>.part` and only moves it to `path` once every piece verified or, with
+    /// `best_effort`, was given up on. A multi file torrent becomes a directory there. The
+    /// pieces verified so far are tracked in `<path>.state`, so a later run picks up where an
+    /// interrupted one stopped. Returns where the content ended up, `path.N` when a directory
+    /// was in the way
+    pub async fn download_file(&mut self, path: impl AsRef<Path>) -> anyhow::Result<PathBuf> {
+        let path = self.target_path(path.as_ref()).await?;
+        let path = path.as_path();
+        let part = part_path(path);
+        let state = sidecar_path(path, "state");
+        let file = if self.load_resume_state(&part, &state).await {
+            tokio::fs::OpenOptions::new()
+                .write(true)
+                .open(&part)
+                .await?
+        } else {
+            tokio::fs::File::create(&part).await?
+        };
+        // Sparse where the filesystem allows it, every piece is written at its offset as soon
+        // as it's verified
+        file.set_len(self.file.total_size as u64).await?;
+        let info_hash = self.torrent.info_hash()?;
+        // Bytes of verified pieces in the file, compared with the torrent's length at the end
+        let restored = (0..self.data.piece_count)
+            .filter(|idx| self.data.have[*idx])
+            .map(|idx| self.piece_length(idx))
+            .sum::<usize>();
+        let file = Arc::new(Mutex::new((file, self.data.have.clone(), restored)));
+        let plength = self.data.plength;
+        let best_effort = self.config.best_effort;
+        let missing = (0..self.data.piece_count)
+            .filter(|idx| !self.data.have[*idx])
+            .collect();
+        let before = self.failed_pieces.len();
+        self.fetch_each(missing, best_effort, |idx, slice| {
+            let file = file.clone();
+            let state = state.clone();
+            async move {
+                let mut file = file.lock().await;
+                let (file, have, written) = &mut *file;
+                file.seek(SeekFrom::Start((idx * plength) as u64)).await?;
+                file.write_all(&slice).await?;
+                // The state may only claim pieces that are on disk
+                file.sync_data().await?;
+                have[idx] = true;
+                *written += slice.len();
+                let mut raw = info_hash.to_vec();
+                raw.extend(to_bitfield(have));
+                tokio::fs::write(&state, raw).await?;
+                Ok(())
+            }
+        })
+        .await?;
+        let (file, _, written) = &*file.lock().await;
+        file.sync_all().await?;
+        // The file was sized up front, only the pieces written say whether the content is whole.
+        // Given up pieces are holes in it, so this only holds once every piece was written
+        if self.failed_pieces.len() == before {
+            self.check_length(*written)?;
+        }
+        // Like `download_to_vec`, a best effort download ends with the failed pieces zero-filled
+        // and listed in `failed_pieces`, only what was downloaded is checked
+        if self.config.verify_on_complete && self.failed_pieces.len() == before {
+            let written = tokio::fs::read(&part).await?;
+            if let Err(failed) = self.verify_complete(&written) {
+                bail!("Written content fails the hash of pieces {:?}", failed);
+            }
+        }
+        if self.torrent.is_multi_file() {
+            self.split_part(&part, path).await?;
+            tokio::fs::remove_file(&part).await?;
+        } else {
+            tokio::fs::rename(&part, path).await?;
+        }
+        let _ = tokio::fs::remove_file(&state).await;
+        Ok(path.to_path_buf())
+    }
+    // Where `download_file` writes to: a file in the way is an error, an existing directory is
+    // only merged into when it's the root of a multi file download we can resume, anything
+    // else in the way moves the download to the first free `path.N`
+    async fn target_path(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        match tokio::fs::metadata(path).await {
+            Ok(meta) if meta.is_file() => bail!(
+                "{} already exists, refusing to overwrite it",
+                path.display()
+            ),
+            Ok(_) if self.torrent.is_multi_file() && self.resume_state_matches(path).await => {
+                Ok(path.to_path_buf())
+            }
+            Ok(_) => {
+                for n in 1.. {
+                    let free = sidecar_path(path, &n.to_string());
+                    if tokio::fs::metadata(&free).await.is_err() {
+                        return Ok(free);
+                    }
+                }
+                unreachable!("Ran out of names next to {}", path.display())
+            }
+            Err(_) => Ok(path.to_path_buf()),
+        }
+    }
+    // Whether a previous run left a `.part` and `.state` of this torrent for `path`
+    async fn resume_state_matches(&self, path: &Path) -> bool {
+        let Ok(meta) = tokio::fs::metadata(part_path(path)).await else {
+            return false;
+        };
+        let Ok(info_hash) = self.torrent.info_hash() else {
+            return false;
+        };
+        let state = tokio::fs::read(sidecar_path(path, "state")).await;
+        meta.len() == self.file.total_size as u64
+            && state.is_ok_and(|raw| raw.starts_with(&info_hash))
+    }
+    // Copies each file's range of the assembled content to its place under `root`
+    async fn split_part(&self, part: &Path, root: &Path) -> anyhow::Result<()> {
+        let mut content = tokio::fs::File::open(part).await?;
+        let name = self.torrent.display_name();
+        for entry in self.torrent.files() {
+            let relative = entry.path.strip_prefix(&name).unwrap_or(&entry.path);
+            let target = root.join(relative);
+            if let Some(parent) = target.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::File::create(&target).await?;
+            content.seek(SeekFrom::Start(entry.offset as u64)).await?;
+            let copied =
+                tokio::io::copy(&mut (&mut content).take(entry.length as u64), &mut file).await?;
+            if copied != entry.length as u64 {
+                bail!("{} is short of {}", part.display(), target.display());
+            }
+            file.sync_all().await?;
+        }
+        Ok(())
+    }
+    /// Piece dump mode, writes every verified piece to `dir/piece-NNNN.bin` instead of
+    /// assembling the content
+    pub async fn dump_pieces(&mut self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        let dir = dir.as_ref().to_path_buf();
+        tokio::fs::create_dir_all(&dir).await?;
+        self.download_streaming(|idx, slice| {
+            let path = dir.join(format!("piece-{:04}.bin", idx));
+            async move {
+                tokio::fs::write(path, slice).await?;
+                Ok(())
+            }
+        })
+        .await
+    }
+    /// Stays in the swarm once the download is done, until cancelled or shut down. Peers'
+    /// requests are answered with `serve_pieces`, and the tracker gets an announce without an
+    /// event at its regular interval
+    pub async fn seed(&mut self) -> anyhow::Result<()> {
+        let shutdown = self.shutdown.clone();
+        let cancel = self.cancel.clone();
+        let mut retry_at = None;
+        loop {
+            let due = retry_at.or(self.tracker.announce_due_at());
+            let announce = async {
+                match due {
+                    Some(due) => tokio::time::sleep_until(due).await,
+                    None => std::future::pending().await,
+                }
             };
-            let peer = self
+            let mut messages: FuturesUnordered<_> = self
                 .peers
                 .iter_mut()
-                .find(|peer| peer.pieces.contains(&(idx as i32)));
-            if let Some(peer) = peer {
-                let slice = peer.download_piece(idx, plength).await?;
-                let piece_hash = {
-                    let mut hasher = Sha1::new();
-                    hasher.update(&slice);
-                    hex::encode(hasher.finalize())
+                .map(|peer| async {
+                    let addr = peer.addr;
+                    (addr, peer.serve_next().await)
+                })
+                .collect();
+            tokio::select! {
+                _ = shutdown.cancelled() => return Ok(()),
+                _ = cancel.cancelled() => return Ok(()),
+                _ = announce => {
+                    drop(messages);
+                    retry_at = self
+                        .refresh_peers()
+                        .await
+                        .is_err()
+                        .then(|| tokio::time::Instant::now() + SEED_ANNOUNCE_RETRY);
+                }
+                Some((addr, result)) = messages.next() => {
+                    drop(messages);
+                    if result.is_err() {
+                        self.peers.retain(|peer| peer.addr != addr);
+                        self.pool.mark_failed(addr);
+                    }
+                }
+            }
+        }
+    }
+    /// Fetches piece `idx` again from the swarm, whether we had it or not, and writes it over
+    /// its offset in the already downloaded file at `path`. For a multi file torrent `path` is
+    /// the directory, each file the piece overlaps gets its part of it
+    pub async fn redownload_piece(
+        &mut self,
+        idx: usize,
+        path: impl AsRef<Path>,
+    ) -> anyhow::Result<()> {
+        if idx >= self.data.piece_count {
+            bail!(
+                "Piece {} is out of range, there are {} pieces",
+                idx,
+                self.data.piece_count
+            );
+        }
+        // Counted again once it verifies
+        if std::mem::replace(&mut self.data.have[idx], false) {
+            self.file
+                .downloaded
+                .fetch_sub(self.piece_length(idx), Ordering::Relaxed);
+        }
+        let slice = self.fetch_piece(idx).await?;
+        let start = idx * self.data.plength;
+        if !self.torrent.is_multi_file() {
+            return write_at(path.as_ref(), start as u64, &slice).await;
+        }
+        // Laid out the way `split_part` wrote them
+        let name = self.torrent.display_name();
+        let end = start + slice.len();
+        for entry in self.torrent.files() {
+            let (from, to) = (
+                entry.offset.max(start),
+                (entry.offset + entry.length).min(end),
+            );
+            if from >= to {
+                continue;
+            }
+            let relative = entry.path.strip_prefix(&name).unwrap_or(&entry.path);
+            let target = path.as_ref().join(relative);
+            write_at(
+                &target,
+                (from - entry.offset) as u64,
+                &slice[from - start..to - start],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+    pub async fn download_range(&mut self, start: usize, end: usize) -> anyhow::Result<Vec<u8>> {
+        if start > end || end > self.file.total_size {
+            bail!(
+                "range {}..{} is out of bounds for {} bytes",
+                start,
+                end,
+                self.file.total_size
+            );
+        }
+        if start == end {
+            return Ok(Vec::new());
+        }
+        let first = start / self.data.plength;
+        let last = (end - 1) / self.data.plength;
+        let buffer = self
+            .fetch_pieces(first..last + 1, self.config.best_effort)
+            .await?;
+        // The buffer starts at the first covering piece, not at `start`
+        let offset = first * self.data.plength;
+        Ok(buffer[start - offset..end - offset].to_vec())
+    }
+    // Each piece is checked against the hash at its own index, never against the whole list
+    async fn verify_piece(&self, idx: usize, slice: Vec<u8>) -> (Vec<u8>, bool) {
+        if slice.len() != self.piece_length(idx) {
+            return (slice, false);
+        }
+        let (slice, piece_hash) = if self.config.offload_hashing {
+            // Hashing a large piece would otherwise stall every other task on this worker
+            tokio::task::spawn_blocking(move || {
+                let piece_hash = sha1_hex(&slice);
+                (slice, piece_hash)
+            })
+            .await
+            .expect("Hashing task panicked")
+        } else {
+            let piece_hash = sha1_hex(&slice);
+            (slice, piece_hash)
+        };
+        let valid = self.data.piece_hashes[idx] == piece_hash;
+        (slice, valid)
+    }
+    // Per piece checks only agree with our own piece sizes, this catches them being wrong
+    fn check_length(&self, assembled: usize) -> anyhow::Result<()> {
+        let expected = self.torrent.length();
+        if assembled != expected {
+            bail!(
+                "Assembled {} bytes but the torrent declares {}",
+                assembled,
+                expected
+            );
+        }
+        Ok(())
+    }
+    async fn fetch_pieces(
+        &mut self,
+        pieces: Range<usize>,
+        best_effort: bool,
+    ) -> anyhow::Result<Vec<u8>> {
+        let first = pieces.start;
+        // Pieces arrive in the picker's order but are assembled in index order
+        let mut slots: Vec<Option<Vec<u8>>> = vec![None; pieces.len()];
+        let result = self
+            .fetch_each(pieces.collect(), best_effort, |idx, slice| {
+                slots[idx - first] = Some(slice);
+                async { Ok(()) }
+            })
+            .await;
+        match result {
+            // Pieces nobody could provide are zero-filled
+            Ok(()) => Ok(slots
+                .into_iter()
+                .enumerate()
+                .flat_map(|(pos, slot)| {
+                    slot.unwrap_or_else(|| vec![0; self.piece_length(first + pos)])
+                })
+                .collect()),
+            // Only the pieces before the first gap make sense as partial content
+            Err(err) if err.is::<Cancelled>() => {
+                let partial = slots.into_iter().map_while(|slot| slot).flatten();
+                bail!(Cancelled(partial.collect()))
+            }
+            Err(err) => Err(err),
+        }
+    }
+    // Fetches `pieces` in the picker's order and hands each one to `store` once verified, with
+    // `best_effort` a piece nobody could provide is skipped instead of failing the download
+    async fn fetch_each<F, Fut>(
+        &mut self,
+        pieces: Vec<usize>,
+        best_effort: bool,
+        mut store: F,
+    ) -> anyhow::Result<()>
+    where
+        F: FnMut(usize, Vec<u8>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let deadline = self.config.deadline;
+        let shutdown = self.shutdown.clone();
+        let fetch = async {
+            let order = self.config.picker.order(&pieces, &self.availability());
+            for idx in order {
+                self.wait_while_paused().await;
+                if self.cancel.is_cancelled() {
+                    bail!(Cancelled(Vec::new()));
+                }
+                let slice = match self.fetch_piece(idx).await {
+                    Ok(slice) => slice,
+                    Err(err) if best_effort && !err.is::<Cancelled>() => {
+                        self.failed_pieces.push(idx);
+                        continue;
+                    }
+                    Err(err) => return Err(err),
                 };
-                assert!(self.data.piece_hashes.contains(&piece_hash));
-                buffer.extend(&slice);
+                store(idx, slice).await?;
+            }
+            Ok(())
+        };
+        let fetch = async {
+            tokio::select! {
+                _ = shutdown.cancelled() => bail!("Client was shut down"),
+                result = fetch => result,
+            }
+        };
+        let result = match deadline {
+            // Dropping the timed out future drops whatever request was in flight with it
+            Some(deadline) => tokio::time::timeout(deadline, fetch)
+                .await
+                .unwrap_or_else(|_| Err(DeadlineExceeded(deadline).into())),
+            None => fetch.await,
+        };
+        if result.is_err() {
+            self.shutdown().await;
+        }
+        result
+    }
+    // Marks the pieces a previous run already verified into `part`, as listed in its state file
+    // or, when that's missing or for another torrent, found by rehashing the file
+    async fn load_resume_state(&mut self, part: &Path, state: &Path) -> bool {
+        let Ok(metadata) = tokio::fs::metadata(part).await else {
+            return false;
+        };
+        if metadata.len() != self.file.total_size as u64 {
+            return false;
+        }
+        let Ok(info_hash) = self.torrent.info_hash() else {
+            return false;
+        };
+        let piece_count = self.data.piece_count;
+        let listed = tokio::fs::read(state).await.ok().and_then(|raw| {
+            let (hash, bitfield) = raw.split_at_checked(20)?;
+            if hash != info_hash {
+                return None;
+            }
+            from_bitfield(bitfield, piece_count).ok()
+        });
+        let verified: Vec<usize> = match listed {
+            Some(pieces) => pieces.into_iter().map(|idx| idx as usize).collect(),
+            None => match self.rehash_part(part).await {
+                Ok(verified) => verified,
+                Err(_) => return false,
+            },
+        };
+        for idx in verified {
+            if std::mem::replace(&mut self.data.have[idx], true) {
+                continue;
+            }
+            self.file
+                .downloaded
+                .fetch_add(self.piece_length(idx), Ordering::Relaxed);
+            // Peers got our bitfield before the state was loaded
+            self.broadcast_have(idx).await;
+        }
+        true
+    }
+    // Pieces of `part` that match their hash, read one at a time so a large file never has to
+    // fit in memory
+    async fn rehash_part(&self, part: &Path) -> anyhow::Result<Vec<usize>> {
+        let mut file = tokio::fs::File::open(part).await?;
+        let mut piece = vec![0u8; self.data.plength];
+        let mut verified = Vec::new();
+        for idx in 0..self.data.piece_count {
+            let piece = &mut piece[..self.piece_length(idx)];
+            file.seek(SeekFrom::Start((idx * self.data.plength) as u64))
+                .await?;
+            file.read_exact(piece).await?;
+            if sha1_hex(piece) == self.data.piece_hashes[idx] {
+                verified.push(idx);
+            }
+        }
+        Ok(verified)
+    }
+    // Peers are told we want nothing while paused, and kept from timing out the idle connection
+    async fn wait_while_paused(&mut self) {
+        let mut paused = self.paused.0.subscribe();
+        if !*paused.borrow_and_update() {
+            return;
+        }
+        for peer in &mut self.peers {
+            let _ = peer.set_interested(false).await;
+        }
+        while *paused.borrow_and_update() && !self.cancel.is_cancelled() {
+            tokio::select! {
+                _ = paused.changed() => {}
+                _ = self.cancel.cancelled() => {}
+                _ = tokio::time::sleep(KEEP_ALIVE_INTERVAL) => {
+                    for peer in &mut self.peers {
+                        let _ = peer.send_keep_alive().await;
+                    }
+                }
+            }
+        }
+    }
+    // Stops as soon as the download is cancelled, whatever the piece is waiting on: a peer,
+    // the backoff or an announce
+    async fn fetch_piece(&mut self, idx: usize) -> anyhow::Result<Vec<u8>> {
+        let cancel = self.cancel.clone();
+        tokio::select! {
+            _ = cancel.cancelled() => bail!(Cancelled(Vec::new())),
+            result = self.fetch_from_any_source(idx) => result,
+        }
+    }
+    async fn fetch_from_any_source(&mut self, idx: usize) -> anyhow::Result<Vec<u8>> {
+        let _slot = match self.config.piece_slots.clone() {
+            Some(slots) => Some(slots.acquire_owned().await?),
+            None => None,
+        };
+        // Regular announces without an event in between started and completed
+        if self.tracker.announce_due() {
+            let _ = self.refresh_peers().await;
+        }
+        let plength = self.piece_length(idx);
+        self.poll_peers().await;
+        if let Some((slice, addr)) = self.race_endgame(idx, plength).await {
+            self.pool.record_piece_success(addr);
+            return Ok(self.complete_piece(idx, slice, Some(addr)).await);
+        }
+        let mut backoff = self.config.backoff;
+        // Corrupt copies of this piece the current peer sent
+        let mut mismatches = 0;
+        // Peers that kept us choked during this attempt, still connected
+        let mut choked = Vec::new();
+        for attempt in 0..=self.config.max_retries {
+            // They may have unchoked us by now
+            choked.clear();
+            while let Some(pos) = self.peers.iter().position(|peer| {
+                peer.pieces.contains(&(idx as i32)) && !choked.contains(&peer.addr)
+            }) {
+                let peer = &mut self.peers[pos];
+                let addr = peer.addr;
+                let mut ban = false;
+                match peer.download_piece(idx, plength).await {
+                    Ok(slice) => {
+                        let (slice, valid) = self.verify_piece(idx, slice).await;
+                        if valid {
+                            self.pool.record_piece_success(addr);
+                            return Ok(self.complete_piece(idx, slice, Some(addr)).await);
+                        }
+                        match self.config.mismatch_policy {
+                            MismatchPolicy::RetrySamePeer(retries) if mismatches < retries => {
+                                mismatches += 1;
+                                continue;
+                            }
+                            MismatchPolicy::BanPeer => ban = true,
+                            _ => {}
+                        }
+                    }
+                    // Choking is no fault of the connection, the peer is asked again next attempt
+                    Err(err) if err.is::<PeerChoking>() => {
+                        mismatches = 0;
+                        choked.push(addr);
+                        continue;
+                    }
+                    Err(_) => {}
+                }
+                // The peer went away or sent corrupt data, try another one
+                mismatches = 0;
+                let peer = self.peers.remove(pos);
+                self.pool.mark_failed(peer.addr);
+                if ban {
+                    self.pool.ban(peer.addr);
+                } else {
+                    self.pool.record_piece_failure(peer.addr);
+                }
+                // Frees its share of the connection budget for a candidate waiting in the pool
+                drop(peer);
+                if self.pool.count(PeerState::Known) > 0 {
+                    let _ = self.fill_from_pool().await;
+                }
+            }
+            if let Some(slice) = self.fetch_from_webseeds(idx).await {
+                return Ok(self.complete_piece(idx, slice, None).await);
+            }
+            if attempt == self.config.max_retries {
+                break;
+            }
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+            // A failed announce is retried on the next attempt, `max_retries` bounds them all
+            let _ = self.refresh_peers().await;
+            self.poll_peers().await;
+        }
+        bail!(
+            "peers don't have this piece :{} (gave up after {} retries)",
+            idx,
+            self.config.max_retries
+        );
+    }
+    // Endgame: the last missing piece is requested from every peer that has it, the first
+    // valid copy wins and the others get their requests cancelled. Falls back to the regular
+    // one peer at a time path when fewer than two peers have it or no copy was valid
+    async fn race_endgame(&mut self, idx: usize, plength: usize) -> Option<(Vec<u8>, SocketAddr)> {
+        let last =
+            self.data.have.iter().filter(|have| !**have).count() == 1 && !self.data.have[idx];
+        let mut racers: Vec<&mut Peer> = self
+            .peers
+            .iter_mut()
+            .filter(|peer| peer.pieces.contains(&(idx as i32)))
+            .collect();
+        if !last || racers.len() < 2 {
+            return None;
+        }
+        let expected = &self.data.piece_hashes[idx];
+        let winner = {
+            let mut downloads: FuturesUnordered<_> = racers
+                .iter_mut()
+                .map(|peer| async {
+                    let addr = peer.addr;
+                    (addr, peer.download_piece(idx, plength).await)
+                })
+                .collect();
+            let mut winner = None;
+            while let Some((addr, result)) = downloads.next().await {
+                if let Ok(slice) = result {
+                    if slice.len() == plength && sha1_hex(&slice) == *expected {
+                        winner = Some((slice, addr));
+                        break;
+                    }
+                }
+            }
+            winner
+        };
+        // The losers' downloads were dropped halfway, whatever they still had requested is
+        // of no use anymore
+        for peer in racers {
+            peer.cancel_pending().await;
+        }
+        winner
+    }
+    async fn complete_piece(
+        &mut self,
+        idx: usize,
+        slice: Vec<u8>,
+        from: Option<SocketAddr>,
+    ) -> Vec<u8> {
+        self.data.have[idx] = true;
+        if let Some(pieces) = &self.config.peer.serve_pieces {
+            pieces.write().unwrap().insert(idx, Arc::new(slice.clone()));
+        }
+        self.broadcast_have(idx).await;
+        // Fails only when nobody is subscribed
+        let _ = self.events.send(PieceCompleted {
+            index: idx,
+            peer: from,
+        });
+        self.file
+            .downloaded
+            .fetch_add(slice.len(), Ordering::Relaxed);
+        if !self.data.have.contains(&false) {
+            // Nothing left to ask for from anyone
+            for peer in &mut self.peers {
+                let _ = peer.set_interested(false).await;
+            }
+            if self.tracker.take_completed() {
+                self.announce_completed().await;
+            }
+        }
+        slice
+    }
+    // Sent as soon as the last piece verifies, regardless of the announce interval
+    async fn announce_completed(&mut self) {
+        if !self.tracker.has_announced() {
+            return;
+        }
+        let mut request = self.tracker_request();
+        request.event = Some(Event::Completed);
+        request.left = 0;
+        match self.torrent.announce(&self.http, &request).await {
+            Ok(response) => self.tracker.record(&response),
+            Err(_) => {
+                self.tracker
+                    .record_failure(self.config.rotate_peer_id_after);
+            }
+        }
+    }
+    // Web seeds are checked against the same piece hashes as peers, a bad range is fetched again
+    async fn fetch_from_webseeds(&self, idx: usize) -> Option<Vec<u8>> {
+        let start = idx * self.data.plength;
+        let end = start + self.piece_length(idx);
+        for base in self.torrent.webseeds() {
+            let spans = webseed::spans(self.torrent, base, start, end);
+            for _ in 0..WEBSEED_ATTEMPTS {
+                let Ok(slice) = webseed::fetch(&self.http, &spans).await else {
+                    continue;
+                };
+                let (slice, valid) = self.verify_piece(idx, slice).await;
+                if valid {
+                    return Some(slice);
+                }
+            }
+        }
+        None
+    }
+    fn tracker_request(&self) -> TrackerRequest {
+        let mut request = self.torrent.tracker_request();
+        request.compact = self.config.compact as u8;
+        request.port = self.config.port;
+        if let Some(peer_id) = self.tracker.peer_id() {
+            request.peer_id = peer_id.to_string();
+        }
+        request.tracker_id = self.tracker.tracker_id().map(str::to_string);
+        request.downloaded = self.file.downloaded.load(Ordering::Relaxed);
+        request.left = self.bytes_left();
+        request.numwant = self.config.numwant;
+        request
+    }
+    // Lets every peer know we can serve the piece now, peers we can't reach are dropped
+    async fn broadcast_have(&mut self, idx: usize) {
+        let mut failed = Vec::new();
+        for (pos, peer) in self.peers.iter_mut().enumerate() {
+            if peer.send_have(idx as u32).await.is_err() {
+                failed.push(pos);
+            }
+        }
+        for pos in failed.into_iter().rev() {
+            let peer = self.peers.remove(pos);
+            self.pool.mark_failed(peer.addr);
+        }
+    }
+    // Catches up on what every peer sent since we last downloaded from it, peers that went
+    // away are dropped
+    async fn poll_peers(&mut self) {
+        let mut failed = Vec::new();
+        for (pos, peer) in self.peers.iter_mut().enumerate() {
+            if peer.poll_idle().await.is_err() {
+                failed.push(pos);
+            }
+        }
+        for pos in failed.into_iter().rev() {
+            let peer = self.peers.remove(pos);
+            self.pool.mark_failed(peer.addr);
+        }
+    }
+    /// Re-announces to the tracker and connects to any peer we aren't already talking to
+    async fn refresh_peers(&mut self) -> anyhow::Result<()> {
+        if self.config.no_tracker {
+            return self.connect_more().await;
+        }
+        self.tracker.wait_for_announce().await;
+        let response = match self
+            .torrent
+            .announce(&self.http, &self.tracker_request())
+            .await
+        {
+            Ok(response) => response,
+            Err(err) => {
+                self.tracker
+                    .record_failure(self.config.rotate_peer_id_after);
+                // The other sources may still have peers while the tracker is down
+                self.connect_more().await?;
+                return Err(err);
+            }
+        };
+        self.tracker.record(&response);
+        self.pool.extend(
+            response
+                .resolve_addrs_with(&*self.config.dns_resolver)
+                .await,
+        );
+        self.connect_more().await
+    }
+    // Connects to whatever the pool and the other peer sources have to offer
+    async fn connect_more(&mut self) -> anyhow::Result<()> {
+        let info_hash = self.torrent.info_hash()?;
+        let request = self.tracker_request();
+        if let Some(lsd_peers) = &mut self.lsd_peers {
+            while let Ok(addr) = lsd_peers.try_recv() {
+                self.pool.add(addr);
+            }
+        }
+        self.pool
+            .extend(discover_all(&self.config.sources, info_hash, &request).await);
+        self.fill_from_pool().await
+    }
+    // Tops the connections back up from candidates already in the pool, up to `max_peers` and
+    // whatever the connection budget allows
+    async fn fill_from_pool(&mut self) -> anyhow::Result<()> {
+        self.pool.requeue_cooled();
+        let info_hash = self.torrent.info_hash()?;
+        let peers = connect_peers(
+            &mut self.pool,
+            info_hash,
+            &self.data.have,
+            &self.config,
+            self.config.max_peers.saturating_sub(self.peers.len()),
+            &self.shutdown,
+        )
+        .await;
+        self.peers.extend(peers.into_iter().flatten());
+        Ok(())
+    }
+}
+
+// Overwrites `data` at `offset` of the existing file at `path`
+async fn write_at(path: &Path, offset: u64, data: &[u8]) -> anyhow::Result<()> {
+    let mut file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.write_all(data).await?;
+    file.sync_all().await?;
+    Ok(())
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    sidecar_path(path, "part")
+}
+
+// `path` with `.extension` appended, next to it in the same directory
+fn sidecar_path(path: &Path, extension: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    PathBuf::from(sidecar)
+}
+
+fn sha1_hex(data: &[u8]) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+// Connects to up to `slots` candidates of the pool, with at most `max_connecting` attempts in
+// flight, the remaining candidates stay in the pool
+async fn connect_peers(
+    pool: &mut PeerPool,
+    info_hash: [u8; 20],
+    have: &[bool],
+    config: &Config,
+    slots: usize,
+    shutdown: &CancellationToken,
+) -> Vec<anyhow::Result<Peer>> {
+    let permits = Arc::new(Semaphore::new(config.max_connecting));
+    let mut tasks = JoinSet::new();
+    while tasks.len() < slots {
+        let Some(addr) = pool.next_candidate() else {
+            break;
+        };
+        let budget = match &config.connection_budget {
+            Some(budget) => match budget.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                // The session is at its limit, leave the rest for a later refresh
+                Err(_) => {
+                    pool.requeue(addr);
+                    break;
+                }
+            },
+            None => None,
+        };
+        let permits = permits.clone();
+        let options = config.peer.clone();
+        let shutdown = shutdown.clone();
+        let have = have.to_vec();
+        tasks.spawn(async move {
+            let connect = async {
+                let _permit = permits.acquire_owned().await?;
+                let mut peer = Peer::new(addr, &info_hash, &have, &options).await?;
+                peer.budget = budget;
+                Ok(peer)
+            };
+            let peer = tokio::select! {
+                _ = shutdown.cancelled() => Err(anyhow!("Client was shut down")),
+                peer = connect => peer,
+            };
+            (addr, peer)
+        });
+    }
+    let mut peers = Vec::new();
+    while let Some(joined) = tasks.join_next().await {
+        let (addr, peer) = match joined {
+            Ok(joined) => joined,
+            Err(err) => {
+                peers.push(Err(err.into()));
+                continue;
+            }
+        };
+        match &peer {
+            Ok(_) => pool.mark_connected(addr),
+            Err(_) => pool.mark_failed(addr),
+        }
+        peers.push(peer);
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mock::{self, MockPeer},
+        peer::message::MessageTag,
+    };
+
+    const PLENGTH: usize = 1 << 14;
+
+    // A client connected to a single mock serving `pieces`
+    async fn client<'a>(torrent: &'a Torrent, mock: MockPeer) -> Client<'a> {
+        let addr = mock.spawn().await.unwrap();
+        ClientBuilder::new(torrent)
+            .peers([addr])
+            .max_retries(0)
+            .backoff(Duration::ZERO)
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn each_piece_verifies_against_its_own_index() {
+        // The last piece is short, so both the sizing and the hash lookup are exercised
+        let mut content = vec![1u8; PLENGTH];
+        content.extend([2u8; 100]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces.clone()),
+        )
+        .await;
+        assert_eq!(client.piece_length(1), 100);
+        assert!(client.verify_piece(0, pieces[0].clone()).await.1);
+        assert!(client.verify_piece(1, pieces[1].clone()).await.1);
+        assert!(!client.verify_piece(0, pieces[1].clone()).await.1);
+        assert!(!client.verify_piece(1, pieces[0].clone()).await.1);
+    }
+
+    #[tokio::test]
+    async fn endgame_cancels_the_slower_peer() {
+        // A single piece of four blocks is the last missing one from the start
+        let content: Vec<u8> = (0..4 * PLENGTH).map(|n| n as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, 4 * PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let slow = MockPeer::new(info_hash, pieces.clone()).delay(Duration::from_millis(200));
+        let cancels = slow.cancels();
+        let slow = slow.spawn().await.unwrap();
+        let fast = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([slow, fast])
+            .max_retries(0)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        // The slow mock only reads the Cancel once done sleeping over the requests before it
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while cancels.load(Ordering::SeqCst) == 0 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        })
+        .await
+        .expect("The slower peer never got a Cancel");
+    }
+
+    #[tokio::test]
+    async fn magnet_peers_are_queued_before_other_sources() {
+        let content = vec![4u8; 100];
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let from_magnet = MockPeer::new(info_hash, pieces.clone())
+            .spawn()
+            .await
+            .unwrap();
+        let from_source = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let magnet = Magnet::parse(&format!(
+            "magnet:?xt=urn:btih:{}&x.pe={}",
+            hex::encode(info_hash),
+            from_magnet
+        ))
+        .unwrap();
+        // With room for a single connection, only the first queued peer is connected
+        let client = ClientBuilder::from_magnet(&torrent, &magnet)
+            .unwrap()
+            .peer_source(vec![from_source])
+            .max_peers(1)
+            .build()
+            .await
+            .unwrap();
+        let connected: Vec<SocketAddr> = client.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(connected, vec![from_magnet]);
+        assert_eq!(client.pool.count(PeerState::Known), 1);
+
+        let other = Magnet {
+            info_hash: [0; 20],
+            ..magnet
+        };
+        assert!(ClientBuilder::from_magnet(&torrent, &other).is_err());
+    }
+
+    #[tokio::test]
+    async fn choked_peers_are_kept_and_asked_again_until_one_unchokes() {
+        let content = vec![2u8; PLENGTH + 300];
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let never = MockPeer::new(info_hash, pieces.clone())
+            .choke(true)
+            .spawn()
+            .await
+            .unwrap();
+        // Still choking when the first attempt gives up on it
+        let late = MockPeer::new(info_hash, pieces)
+            .unchoke_after(Duration::from_millis(300))
+            .spawn()
+            .await
+            .unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([never, late])
+            .unchoke_timeout(Duration::from_millis(100))
+            .max_retries(5)
+            .backoff(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        let connected: Vec<SocketAddr> = client.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(connected, vec![never, late]);
+        assert_eq!(client.pool.state(&never), Some(PeerState::Connected));
+    }
+
+    #[tokio::test]
+    async fn pieces_announced_with_have_instead_of_a_bitfield_are_fetched() {
+        let content: Vec<u8> = (0..3 * PLENGTH).map(|n| (n / 3) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces).have_messages(true);
+        let mut client = client(&torrent, mock).await;
+        // Only the first Have was read while connecting, the others wait on the idle peer
+        assert_eq!(client.peers[0].pieces, vec![2]);
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn a_failed_started_announce_only_matters_without_other_peers() {
+        let content = vec![8u8; 500];
+        let (mut torrent, pieces) = mock::torrent(&content, PLENGTH);
+        // Nothing listens on port 1
+        torrent.announce = Some("http://127.0.0.1:1/announce".to_string());
+        assert!(ClientBuilder::new(&torrent).build().await.is_err());
+
+        let addr = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+    }
+
+    #[tokio::test]
+    async fn dead_peers_and_a_failed_announce_are_retried_until_one_connects() {
+        let (mut torrent, pieces) = mock::torrent(&[9u8; 500], PLENGTH);
+        let live = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        // Nothing listens there anymore
+        let dead = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let (url, mut announces) = mock::tracker(move |n| match n {
+            0 => Some(mock::announce_response(1800, &[dead])),
+            1 => Some(b"not bencode".to_vec()),
+            _ => Some(mock::announce_response(1800, &[live])),
+        })
+        .await
+        .unwrap();
+        torrent.announce = Some(url);
+        let mut client = ClientBuilder::new(&torrent)
+            .connect_retries(3)
+            .build()
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            announces.recv().await.unwrap();
+        }
+        assert!(announces.try_recv().is_err());
+        let connected: Vec<SocketAddr> = client.peers.iter().map(|peer| peer.addr).collect();
+        assert_eq!(connected, vec![live]);
+        assert_eq!(client.download_to_vec().await.unwrap(), vec![9u8; 500]);
+    }
+
+    #[tokio::test]
+    async fn a_large_peer_list_fills_max_peers_and_keeps_the_rest() {
+        const MAX_PEERS: usize = 8;
+        let (mut torrent, pieces) = mock::torrent(&[6u8; 500], PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces);
+        let open = mock.open_connections();
+        let port = mock
+            .spawn_at("0.0.0.0:0".parse().unwrap())
+            .await
+            .unwrap()
+            .port();
+        // 500 distinct loopback addresses, all reaching the same mock
+        let peers: Vec<SocketAddr> = (0..500u16)
+            .map(|n| SocketAddr::from(([127, 0, (n / 250) as u8, (n % 250) as u8 + 1], port)))
+            .collect();
+        let (url, _announces) = mock::tracker(move |_| Some(mock::announce_response(1800, &peers)))
+            .await
+            .unwrap();
+        torrent.announce = Some(url);
+        let client = ClientBuilder::new(&torrent)
+            .max_peers(MAX_PEERS)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.peers.len(), MAX_PEERS);
+        assert_eq!(open.load(Ordering::SeqCst), MAX_PEERS);
+        assert_eq!(client.pool.len(), 500);
+        assert_eq!(client.pool.count(PeerState::Connected), MAX_PEERS);
+        assert_eq!(client.pool.count(PeerState::Known), 500 - MAX_PEERS);
+    }
+
+    #[tokio::test]
+    async fn identical_pieces_verify_at_their_own_positions() {
+        // Pieces 0 and 1 are the same, 2 and 3 differ from them and each other
+        let mut content = vec![7u8; 2 * PLENGTH];
+        content.extend([8u8; PLENGTH]);
+        content.extend([9u8; PLENGTH]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let addr = MockPeer::new(torrent.info_hash().unwrap(), pieces.clone())
+            .spawn()
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .max_retries(0)
+            .backoff(Duration::ZERO)
+            .build()
+            .await
+            .unwrap();
+        for (idx, piece) in pieces.iter().enumerate() {
+            assert!(
+                client.verify_piece(idx, piece.clone()).await.1,
+                "piece {}",
+                idx
+            );
+        }
+        assert!(!client.verify_piece(2, pieces[0].clone()).await.1);
+        assert_eq!(client.verify_complete(&content), Ok(()));
+
+        // Swapping the two identical pieces changes nothing, any other swap is caught at both ends
+        let order = |order: [usize; 4]| -> Vec<u8> {
+            order.iter().flat_map(|idx| pieces[*idx].clone()).collect()
+        };
+        assert_eq!(client.verify_complete(&order([1, 0, 2, 3])), Ok(()));
+        assert_eq!(
+            client.verify_complete(&order([0, 2, 1, 3])),
+            Err(vec![1, 2])
+        );
+    }
+
+    #[tokio::test]
+    async fn a_range_across_two_piece_boundaries_is_exact() {
+        let content: Vec<u8> = (0..4 * PLENGTH).map(|n| (n % 251) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces);
+        let received = mock.received();
+        let mut client = client(&torrent, mock).await;
+        // Starts in piece 0 and ends in piece 2, piece 3 isn't needed
+        let (start, end) = (PLENGTH - 10, 2 * PLENGTH + 10);
+        assert_eq!(
+            client.download_range(start, end).await.unwrap(),
+            content[start..end]
+        );
+        let requests = received
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|tag| **tag == MessageTag::Request)
+            .count();
+        assert_eq!(requests, 3);
+        assert!(client.download_range(end, start).await.is_err());
+        assert!(client.download_range(0, content.len() + 1).await.is_err());
+        assert!(client.download_range(7, 7).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn with_peers_downloads_from_the_given_peers_without_announcing() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n / 3) as u8).collect();
+        let (mut torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let first = MockPeer::new(info_hash, pieces.clone())
+            .missing(1)
+            .spawn()
+            .await
+            .unwrap();
+        let second = MockPeer::new(info_hash, pieces)
+            .missing(0)
+            .spawn()
+            .await
+            .unwrap();
+        // Would hand out nobody, it mustn't be asked at all
+        let (url, mut announces) = mock::tracker(|_| Some(mock::announce_response(1800, &[])))
+            .await
+            .unwrap();
+        torrent.announce = Some(url);
+        let mut client = Client::with_peers(&torrent, vec![first, second])
+            .await
+            .unwrap();
+        let mut connected: Vec<SocketAddr> = client.peers.iter().map(|peer| peer.addr).collect();
+        connected.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(connected, expected);
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        client.shutdown().await;
+        assert!(announces.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_one_event_per_piece() {
+        let content: Vec<u8> = (0..4 * PLENGTH).map(|n| (n / 5) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let addr = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .build()
+            .await
+            .unwrap();
+        let mut events = client.subscribe();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        let mut received = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            received.push(event);
+        }
+        received.sort_by_key(|event| event.index);
+        let expected: Vec<PieceCompleted> = (0..4)
+            .map(|index| PieceCompleted {
+                index,
+                peer: Some(addr),
+            })
+            .collect();
+        assert_eq!(received, expected);
+    }
+
+    #[tokio::test]
+    async fn peers_status_reflects_the_connected_mocks() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| n as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let full = MockPeer::new(info_hash, pieces.clone())
+            .spawn()
+            .await
+            .unwrap();
+        let partial = MockPeer::new(info_hash, pieces)
+            .missing(0)
+            .spawn()
+            .await
+            .unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([full, partial])
+            .build()
+            .await
+            .unwrap();
+        let status = |client: &Client, addr| {
+            client
+                .peers_status()
+                .into_iter()
+                .find(|status| status.addr == addr)
+                .unwrap()
+        };
+        assert_eq!(client.peers_status().len(), 2);
+        let before = status(&client, partial);
+        assert!(before.choked && !before.interested);
+        assert_eq!(before.pieces_owned, 1);
+        assert_eq!(status(&client, full).pieces_owned, 2);
+
+        // Only the full peer has piece 0
+        client.download_range(0, 1).await.unwrap();
+        let after = status(&client, full);
+        assert!(!after.choked && after.interested);
+        assert!(after.download_rate > 0.0);
+    }
+
+    #[tokio::test]
+    async fn a_stalling_peer_runs_into_the_deadline() {
+        let (torrent, pieces) = mock::torrent(&[6u8; PLENGTH], PLENGTH);
+        let stalling = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .delay(Duration::from_secs(10))
+            .spawn()
+            .await
+            .unwrap();
+        let deadline = Duration::from_millis(300);
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([stalling])
+            .deadline(deadline)
+            .build()
+            .await
+            .unwrap();
+        let started = std::time::Instant::now();
+        let err = client.download_to_vec().await.unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        let exceeded = err.downcast_ref::<DeadlineExceeded>().unwrap();
+        assert_eq!(exceeded.0, deadline);
+        // The outstanding peers went with it
+        assert!(client.peers_status().is_empty());
+    }
+
+    #[tokio::test]
+    async fn connection_attempts_stay_under_the_permit_count() {
+        const PERMITS: usize = 3;
+        let (torrent, _) = mock::torrent(&[6u8; PLENGTH], PLENGTH);
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let mut addrs = Vec::new();
+        // Each holds the connection without answering the handshake for a while, then drops it
+        for _ in 0..10 {
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            let (in_flight, most) = (in_flight.clone(), most.clone());
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                drop(stream);
+            });
+        }
+        let built = ClientBuilder::new(&torrent)
+            .peers(addrs)
+            .max_connecting(PERMITS)
+            .build()
+            .await;
+        assert!(built.is_err());
+        assert_eq!(most.load(Ordering::SeqCst), PERMITS);
+    }
+
+    #[tokio::test]
+    async fn announces_carry_the_configured_user_agent() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut torrent, pieces) = mock::torrent(&[8u8; 500], PLENGTH);
+        let seeder = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        let tracker = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        torrent.announce = Some(format!("http://{}/announce", tracker.local_addr().unwrap()));
+        let answer = tokio::spawn(async move {
+            let (mut stream, _) = tracker.accept().await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                stream.read_exact(&mut byte).await.unwrap();
+                head.push(byte[0]);
+            }
+            let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+            body.extend([127, 0, 0, 1]);
+            body.extend(seeder.port().to_be_bytes());
+            body.push(b'e');
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            stream.write_all(&response).await.unwrap();
+            String::from_utf8(head).unwrap().to_ascii_lowercase()
+        });
+        let mut client = ClientBuilder::new(&torrent)
+            .user_agent("test-agent/1.0")
+            .build()
+            .await
+            .unwrap();
+        let head = answer.await.unwrap();
+        assert!(
+            head.contains("\r\nuser-agent: test-agent/1.0\r\n"),
+            "{}",
+            head
+        );
+        assert_eq!(client.download_to_vec().await.unwrap(), vec![8u8; 500]);
+    }
+
+    #[tokio::test]
+    async fn announces_go_from_started_to_completed_to_regular_to_stopped() {
+        let (mut torrent, pieces) = mock::torrent(&[3u8; 500], PLENGTH);
+        let seeder = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        // A short interval, so the regular announce comes soon after completing
+        let (url, mut announces) =
+            mock::tracker(move |_| Some(mock::announce_response(1, &[seeder])))
+                .await
+                .unwrap();
+        torrent.announce = Some(url);
+        let mut client = ClientBuilder::new(&torrent).build().await.unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), vec![3u8; 500]);
+        let mut seen = Vec::new();
+        let mut next = async || {
+            let query = announces.recv().await.unwrap();
+            let param = |key| mock::query_param(&query, key).map(str::to_string);
+            seen.push((param("event"), param("left")));
+        };
+        tokio::select! {
+            result = client.seed() => panic!("Stopped seeding early: {:?}", result),
+            _ = async { for _ in 0..3 { next().await } } => {}
+        }
+        client.shutdown().await;
+        next().await;
+        let expected = [
+            (Some("started"), "500"),
+            (Some("completed"), "0"),
+            (None, "0"),
+            (Some("stopped"), "0"),
+        ]
+        .map(|(event, left)| (event.map(str::to_string), Some(left.to_string())));
+        assert_eq!(seen, expected);
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_stopped_without_waiting_on_a_hanging_tracker() {
+        let (mut torrent, pieces) = mock::torrent(&[4u8; 500], PLENGTH);
+        let seeder = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        // Answers the started announce, never the stopped one
+        let (url, mut announces) =
+            mock::tracker(move |n| (n == 0).then(|| mock::announce_response(1800, &[seeder])))
+                .await
+                .unwrap();
+        torrent.announce = Some(url);
+        let mut client = ClientBuilder::new(&torrent).build().await.unwrap();
+        announces.recv().await.unwrap();
+        tokio::time::timeout(
+            STOPPED_TIMEOUT + Duration::from_millis(500),
+            client.shutdown(),
+        )
+        .await
+        .expect("Shutdown waited on the tracker");
+        let stopped = announces.recv().await.unwrap();
+        assert_eq!(mock::query_param(&stopped, "event"), Some("stopped"));
+        assert_eq!(mock::query_param(&stopped, "numwant"), Some("0"));
+        assert!(client.peers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn a_bad_web_seed_range_is_rejected_and_fetched_again() {
+        use crate::{mock::WebseedFault, torrent::UrlList};
+
+        let content: Vec<u8> = (0..2 * PLENGTH + 100).map(|n| (n % 253) as u8).collect();
+        let (mut torrent, _) = mock::torrent(&content, PLENGTH);
+        // The first copy of piece 0 and of piece 1
+        let faults = vec![(0, WebseedFault::Corrupt), (2, WebseedFault::Truncated)];
+        let (url, requests) = mock::webseed(content.clone(), faults).await.unwrap();
+        torrent.url_list = Some(UrlList::One(url));
+        let mut client = ClientBuilder::new(&torrent)
+            .max_retries(0)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        // A piece each, plus the two bad answers fetched again
+        assert_eq!(requests.load(Ordering::SeqCst), 3 + 2);
+    }
+
+    #[tokio::test]
+    async fn announces_report_the_port_the_listener_was_given() {
+        let (mut torrent, pieces) = mock::torrent(&[5u8; 500], PLENGTH);
+        let seeder = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        let (url, mut announces) =
+            mock::tracker(move |_| Some(mock::announce_response(1800, &[seeder])))
+                .await
+                .unwrap();
+        torrent.announce = Some(url);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let _client = ClientBuilder::new(&torrent)
+            .announce_port(port)
+            .build()
+            .await
+            .unwrap();
+        let started = announces.recv().await.unwrap();
+        assert_eq!(
+            mock::query_param(&started, "port"),
+            Some(port.to_string().as_str())
+        );
+    }
+
+    #[tokio::test]
+    async fn have_bitfield_is_msb_first_and_zero_padded() {
+        let (torrent, pieces) = mock::torrent(&[1u8; 10 * 16], 16);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        assert_eq!(client.have_bitfield(), vec![0, 0]);
+        for idx in [0, 2, 9] {
+            client.data.have[idx] = true;
+        }
+        assert_eq!(client.have_bitfield(), vec![0b1010_0000, 0b0100_0000]);
+    }
+
+    #[tokio::test]
+    async fn verified_pieces_are_announced_to_every_peer() {
+        let content: Vec<u8> = (0..3 * PLENGTH).map(|n| n as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let seeder = MockPeer::new(info_hash, pieces.clone());
+        // Only has the last piece, the seeder serves the rest
+        let bystander = MockPeer::new(info_hash, pieces).missing(0).missing(1);
+        let (from_seeder, from_bystander) = (seeder.received(), bystander.received());
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([
+                seeder.spawn().await.unwrap(),
+                bystander.spawn().await.unwrap(),
+            ])
+            .build()
+            .await
+            .unwrap();
+        client.download_to_vec().await.unwrap();
+        for received in [from_seeder, from_bystander] {
+            let haves = || {
+                let received = received.lock().unwrap();
+                received
+                    .iter()
+                    .filter(|tag| **tag == MessageTag::Have)
+                    .count()
+            };
+            // The last one may still be on its way to the mock
+            let all_read = async {
+                while haves() < 3 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            };
+            tokio::time::timeout(Duration::from_secs(1), all_read)
+                .await
+                .unwrap();
+            assert_eq!(haves(), 3);
+        }
+    }
+
+    #[tokio::test]
+    async fn availability_counts_the_peers_with_each_piece() {
+        let (torrent, pieces) = mock::torrent(&[1u8; 4 * 16], 16);
+        let info_hash = torrent.info_hash().unwrap();
+        let mocks = [
+            MockPeer::new(info_hash, pieces.clone()),
+            MockPeer::new(info_hash, pieces.clone()).missing(0),
+            MockPeer::new(info_hash, pieces).missing(0).missing(3),
+        ];
+        let mut addrs = Vec::new();
+        for mock in mocks {
+            addrs.push(mock.spawn().await.unwrap());
+        }
+        let client = ClientBuilder::new(&torrent)
+            .peers(addrs)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.availability(), vec![1, 3, 3, 2]);
+    }
+
+    #[tokio::test]
+    async fn peer_id_rotates_only_when_enabled() {
+        let (mut torrent, pieces) = mock::torrent(&[8u8; 500], PLENGTH);
+        // Nothing listens on port 1, every announce fails
+        torrent.announce = Some("http://127.0.0.1:1/announce".to_string());
+        let addr = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        let original = torrent.tracker_request().peer_id;
+        for rotate in [false, true] {
+            let mut builder = ClientBuilder::new(&torrent).peers([addr]);
+            if rotate {
+                builder = builder.rotate_peer_id_after(2);
+            }
+            let mut client = builder.build().await.unwrap();
+            // The started announce in `build` was the first failure
+            assert!(client.refresh_peers().await.is_err());
+            let peer_id = client.tracker_request().peer_id;
+            if rotate {
+                assert_ne!(peer_id, original);
+                assert_eq!(client.rotated_peer_id(), Some(peer_id.as_str()));
             } else {
-                bail!("peers don't have this piece :{}", idx);
+                assert_eq!(peer_id, original);
+                assert_eq!(client.rotated_peer_id(), None);
             }
         }
-        Ok(buffer)
+    }
+
+    #[tokio::test]
+    async fn only_peers_of_the_selected_family_are_connected() {
+        let (torrent, pieces) = mock::torrent(&[8u8; 500], PLENGTH);
+        let v4 = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        // Never connected to, so it doesn't matter that nothing listens there
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        let client = ClientBuilder::new(&torrent)
+            .peers([v6, v4])
+            .peer_ip_family(IpFamily::V4)
+            .build()
+            .await
+            .unwrap();
+        let connected: Vec<SocketAddr> = client
+            .peers_status()
+            .iter()
+            .map(|status| status.addr)
+            .collect();
+        assert_eq!(connected, vec![v4]);
+        assert_eq!(client.pool.state(&v6), None);
+    }
+
+    #[tokio::test]
+    async fn file_progress_splits_pieces_across_file_boundaries() {
+        // 16 byte pieces over files of 20, 30 and 10 bytes
+        let files = [
+            ("a", &[1u8; 20][..]),
+            ("b", &[2u8; 30][..]),
+            ("c", &[3u8; 10][..]),
+        ];
+        let (torrent, pieces) = mock::multi_file_torrent(&files, 16);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let progress = |client: &Client| -> Vec<(usize, usize)> {
+            client
+                .file_progress()
+                .into_iter()
+                .map(|(_, done, total)| (done, total))
+                .collect()
+        };
+        assert_eq!(progress(&client), vec![(0, 20), (0, 30), (0, 10)]);
+        // Pieces 0 and 1, bytes 0..32
+        client.download_range(0, 32).await.unwrap();
+        assert_eq!(progress(&client), vec![(20, 20), (12, 30), (0, 10)]);
+        // Piece 3, bytes 48..60
+        client.download_range(50, 60).await.unwrap();
+        assert_eq!(progress(&client), vec![(20, 20), (14, 30), (10, 10)]);
+        let paths: Vec<PathBuf> = client
+            .file_progress()
+            .into_iter()
+            .map(|(path, ..)| path)
+            .collect();
+        assert_eq!(paths, ["mock/a", "mock/b", "mock/c"].map(PathBuf::from));
+    }
+
+    // A fresh path under the temp dir, nothing is created there
+    fn scratch_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("torrent-{}-{}", std::process::id(), name))
+    }
+
+    #[tokio::test]
+    async fn download_file_writes_every_piece_and_cleans_up() {
+        let mut content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n / 7) as u8).collect();
+        content.extend([5u8; 100]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let path = scratch_path("whole-file");
+        client.download_file(&path).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert!(!part_path(&path).exists());
+        assert!(!sidecar_path(&path, "state").exists());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn dump_pieces_writes_one_file_per_piece() {
+        let mut content: Vec<u8> = (0..3 * PLENGTH).map(|n| (n / 11) as u8).collect();
+        content.extend([2u8; 50]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces.clone()),
+        )
+        .await;
+        let dir = scratch_path("dump");
+        client.dump_pieces(&dir).await.unwrap();
+        let mut names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "piece-0000.bin",
+                "piece-0001.bin",
+                "piece-0002.bin",
+                "piece-0003.bin"
+            ]
+        );
+        for (name, piece) in names.iter().zip(&pieces) {
+            assert_eq!(std::fs::read(dir.join(name)).unwrap(), *piece);
+        }
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[tokio::test]
+    async fn a_miscomputed_piece_length_fails_the_length_check() {
+        let mut content = vec![6u8; PLENGTH];
+        content.extend([7u8; 100]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        // A sizing bug that drops the last byte, with a hash to match so every piece verifies
+        let short = |client: &mut Client| {
+            client.file.total_size -= 1;
+            client.data.piece_hashes[1] = sha1_hex(&pieces[1][..99]);
+        };
+        let mut in_memory = client(&torrent, MockPeer::new(info_hash, pieces.clone())).await;
+        short(&mut in_memory);
+        let err = in_memory.download_to_vec().await.unwrap_err();
+        assert!(err.to_string().contains("torrent declares"), "{}", err);
+
+        let mut on_disk = client(&torrent, MockPeer::new(info_hash, pieces.clone())).await;
+        short(&mut on_disk);
+        let path = scratch_path("miscomputed-length");
+        let err = on_disk.download_file(&path).await.unwrap_err();
+        assert!(err.to_string().contains("torrent declares"), "{}", err);
+        assert!(!path.exists());
+        let _ = std::fs::remove_file(part_path(&path));
+        let _ = std::fs::remove_file(sidecar_path(&path, "state"));
+    }
+
+    #[tokio::test]
+    async fn a_zero_length_torrent_downloads_to_an_empty_file() {
+        let (torrent, pieces) = mock::torrent(&[], PLENGTH);
+        assert!(pieces.is_empty());
+        let mut client = ClientBuilder::new(&torrent).build().await.unwrap();
+        let path = scratch_path("zero-length");
+        client.download_file(&path).await.unwrap();
+        assert!(std::fs::read(&path).unwrap().is_empty());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn content_without_piece_hashes_is_refused() {
+        let raw = b"d4:infod6:lengthi100e4:name4:mock12:piece lengthi16384e6:pieces0:ee";
+        let torrent = Torrent::from_bytes(raw).unwrap();
+        let err = ClientBuilder::new(&torrent).build().await.err().unwrap();
+        assert!(err.to_string().contains("no piece hashes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn multi_file_torrents_are_written_per_file() {
+        let first: Vec<u8> = (0..PLENGTH + 10).map(|n| n as u8).collect();
+        let second = vec![3u8; 500];
+        let files = [("a.bin", &first[..]), ("sub/b.bin", &second[..])];
+        let (torrent, pieces) = mock::multi_file_torrent(&files, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let path = scratch_path("multi-file");
+        assert_eq!(client.download_file(&path).await.unwrap(), path);
+        assert_eq!(std::fs::read(path.join("a.bin")).unwrap(), first);
+        assert_eq!(std::fs::read(path.join("sub/b.bin")).unwrap(), second);
+        assert!(!part_path(&path).exists());
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn a_file_in_the_way_is_refused() {
+        let (torrent, pieces) = mock::multi_file_torrent(&[("a.bin", &[1u8; 100])], PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let path = scratch_path("file-collision");
+        std::fs::write(&path, b"keep me").unwrap();
+        let err = client.download_file(&path).await.unwrap_err();
+        assert!(err.to_string().contains("already exists"), "{}", err);
+        assert_eq!(std::fs::read(&path).unwrap(), b"keep me");
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn an_existing_directory_is_only_merged_into_when_resuming() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n / 3) as u8).collect();
+        let files = [
+            ("a.bin", &content[..PLENGTH]),
+            ("b.bin", &content[PLENGTH..]),
+        ];
+        let (torrent, pieces) = mock::multi_file_torrent(&files, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+
+        // Without a resume state the directory is someone else's, the download goes next to it
+        let path = scratch_path("dir-unrelated");
+        std::fs::create_dir_all(&path).unwrap();
+        let mut fresh = client(&torrent, MockPeer::new(info_hash, pieces.clone())).await;
+        let written = fresh.download_file(&path).await.unwrap();
+        assert_eq!(written, sidecar_path(&path, "1"));
+        assert_eq!(std::fs::read(written.join("a.bin")).unwrap(), files[0].1);
+        assert_eq!(std::fs::read_dir(&path).unwrap().count(), 0);
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_dir_all(written);
+
+        // With a matching state, the first piece comes from the part file and the rest is
+        // merged into the directory next to what's already there
+        let path = scratch_path("dir-resumed");
+        std::fs::create_dir_all(&path).unwrap();
+        std::fs::write(path.join("notes.txt"), b"mine").unwrap();
+        let mut part = pieces[0].clone();
+        part.resize(content.len(), 0);
+        std::fs::write(part_path(&path), part).unwrap();
+        let mut state = info_hash.to_vec();
+        state.extend(to_bitfield(&[true, false]));
+        std::fs::write(sidecar_path(&path, "state"), state).unwrap();
+        let mut resumed = client(&torrent, MockPeer::new(info_hash, pieces).missing(0)).await;
+        assert_eq!(resumed.download_file(&path).await.unwrap(), path);
+        assert_eq!(std::fs::read(path.join("a.bin")).unwrap(), files[0].1);
+        assert_eq!(std::fs::read(path.join("b.bin")).unwrap(), files[1].1);
+        assert_eq!(std::fs::read(path.join("notes.txt")).unwrap(), b"mine");
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn redownloading_a_piece_repairs_it_on_disk_and_counts_it_once() {
+        let mut content: Vec<u8> = (0..PLENGTH).map(|n| n as u8).collect();
+        content.extend([6u8; 200]);
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let path = scratch_path("redownload");
+        client.download_file(&path).await.unwrap();
+        assert_eq!(
+            client.file.downloaded.load(Ordering::Relaxed),
+            content.len()
+        );
+        for idx in [1, 0] {
+            // A bad read or write on our side, the piece still counts as downloaded
+            let offset = idx * PLENGTH;
+            let mut file = std::fs::read(&path).unwrap();
+            file[offset..offset + 100].fill(0xee);
+            std::fs::write(&path, &file).unwrap();
+            assert_ne!(std::fs::read(&path).unwrap(), content);
+
+            client.redownload_piece(idx, &path).await.unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), content);
+            assert_eq!(
+                client.file.downloaded.load(Ordering::Relaxed),
+                content.len()
+            );
+        }
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn redownloading_a_piece_of_a_multi_file_torrent_repairs_every_file_it_spans() {
+        let first: Vec<u8> = (0..PLENGTH + 10).map(|n| n as u8).collect();
+        let second = vec![3u8; 500];
+        let files = [("a.bin", &first[..]), ("sub/b.bin", &second[..])];
+        let (torrent, pieces) = mock::multi_file_torrent(&files, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces),
+        )
+        .await;
+        let path = scratch_path("redownload-multi");
+        client.download_file(&path).await.unwrap();
+        // Piece 1 is the tail of a.bin and all of sub/b.bin
+        std::fs::write(path.join("a.bin"), vec![0xee; first.len()]).unwrap();
+        std::fs::write(path.join("sub/b.bin"), vec![0xee; second.len()]).unwrap();
+        client.redownload_piece(1, &path).await.unwrap();
+        let mut a = vec![0xee; PLENGTH];
+        a.extend(&first[PLENGTH..]);
+        assert_eq!(std::fs::read(path.join("a.bin")).unwrap(), a);
+        assert_eq!(std::fs::read(path.join("sub/b.bin")).unwrap(), second);
+        client.redownload_piece(0, &path).await.unwrap();
+        assert_eq!(std::fs::read(path.join("a.bin")).unwrap(), first);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[tokio::test]
+    async fn failed_pieces_are_zero_filled_or_leave_the_part_file() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| n as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces).corrupt(1);
+        let addr = mock.spawn().await.unwrap();
+        let builder = |best_effort| {
+            ClientBuilder::new(&torrent)
+                .peers([addr])
+                .max_retries(0)
+                .best_effort(best_effort)
+                .build()
+        };
+
+        // Best effort produces the output, with what's missing listed
+        let mut client = builder(true).await.unwrap();
+        let path = scratch_path("failed-pieces-best-effort");
+        client.download_file(&path).await.unwrap();
+        assert_eq!(client.failed_pieces(), [1]);
+        let written = std::fs::read(&path).unwrap();
+        assert_eq!(&written[..PLENGTH], &content[..PLENGTH]);
+        assert!(written[PLENGTH..].iter().all(|byte| *byte == 0));
+        assert!(!part_path(&path).exists());
+        let _ = std::fs::remove_file(path);
+
+        // Otherwise the download fails and keeps what it has for a later run
+        let mut client = builder(false).await.unwrap();
+        let path = scratch_path("failed-pieces");
+        assert!(client.download_file(&path).await.is_err());
+        assert!(!path.exists());
+        let part = part_path(&path);
+        let state = sidecar_path(&path, "state");
+        let written = std::fs::read(&part).unwrap();
+        assert_eq!(&written[..PLENGTH], &content[..PLENGTH]);
+        assert!(state.exists());
+        let _ = std::fs::remove_file(part);
+        let _ = std::fs::remove_file(state);
+    }
+
+    #[tokio::test]
+    async fn resume_fetches_only_the_missing_pieces() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n % 251) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        // Once from the state file, once by rehashing the part file without one
+        for keep_state in [true, false] {
+            let path = scratch_path(&format!("resume-{}", keep_state));
+            let part = part_path(&path);
+            let state = sidecar_path(&path, "state");
+            let mut first = ClientBuilder::new(&torrent)
+                .peers([MockPeer::new(info_hash, pieces.clone())
+                    .corrupt(1)
+                    .spawn()
+                    .await
+                    .unwrap()])
+                .max_retries(0)
+                .build()
+                .await
+                .unwrap();
+            assert!(first.download_file(&path).await.is_err());
+            if !keep_state {
+                std::fs::remove_file(&state).unwrap();
+            }
+
+            // Piece 0 is nowhere to be found anymore, only piece 1 may be asked for
+            let mut second = client(
+                &torrent,
+                MockPeer::new(info_hash, pieces.clone()).missing(0),
+            )
+            .await;
+            assert!(second.load_resume_state(&part, &state).await);
+            assert_eq!(second.bytes_left(), PLENGTH);
+            second.download_file(&path).await.unwrap();
+            assert_eq!(std::fs::read(&path).unwrap(), content);
+            assert_eq!(second.bytes_left(), 0);
+            let _ = std::fs::remove_file(path);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_fails_advertised_pieces_is_dropped_for_another() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n / 3) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let liar = MockPeer::new(info_hash, pieces.clone())
+            .corrupt(0)
+            .corrupt(1);
+        let liar = liar.spawn().await.unwrap();
+        let honest = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([liar, honest])
+            .max_retries(0)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        assert_eq!(client.pool.state(&liar), Some(PeerState::Failed));
+        assert_eq!(client.pool.state(&honest), Some(PeerState::Connected));
+    }
+
+    #[tokio::test]
+    async fn pausing_stops_requests_until_resumed() {
+        let content: Vec<u8> = (0..3 * PLENGTH).map(|n| (n / 5) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces);
+        let received = mock.received();
+        let mut client = client(&torrent, mock).await;
+        let pause = client.pause_handle();
+        let mut downloaded = Vec::new();
+        let download = client.download_streaming(|idx, slice| {
+            if idx == 0 {
+                pause.pause();
+            }
+            downloaded.extend(slice);
+            async { Ok(()) }
+        });
+        let resume = async {
+            let paused = || {
+                received
+                    .lock()
+                    .unwrap()
+                    .contains(&MessageTag::NotInterested)
+            };
+            while !paused() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            // Parked the whole time, the last thing the peer heard is that we want nothing
+            assert_eq!(
+                received.lock().unwrap().last(),
+                Some(&MessageTag::NotInterested)
+            );
+            pause.resume();
+        };
+        let (result, _) = tokio::join!(download, resume);
+        result.unwrap();
+        assert_eq!(downloaded, content);
+        let received = received.lock().unwrap();
+        let paused_at = received
+            .iter()
+            .position(|tag| *tag == MessageTag::NotInterested)
+            .unwrap();
+        assert_eq!(received[paused_at + 1], MessageTag::Interested);
+        assert!(received[paused_at + 2..].contains(&MessageTag::Request));
+    }
+
+    #[tokio::test]
+    async fn download_partial_keeps_everything_but_the_missing_piece() {
+        let content: Vec<u8> = (0..3 * PLENGTH).map(|n| (n / 9) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mut client = client(
+            &torrent,
+            MockPeer::new(torrent.info_hash().unwrap(), pieces).missing(1),
+        )
+        .await;
+        let outcome = client.download_partial().await.unwrap();
+        assert_eq!(outcome.missing, vec![1]);
+        assert_eq!(client.failed_pieces(), &[1]);
+        assert_eq!(outcome.data[..PLENGTH], content[..PLENGTH]);
+        assert!(outcome.data[PLENGTH..2 * PLENGTH].iter().all(|b| *b == 0));
+        assert_eq!(outcome.data[2 * PLENGTH..], content[2 * PLENGTH..]);
+    }
+
+    #[tokio::test]
+    async fn a_slow_consumer_bounds_the_pieces_fetched_ahead() {
+        const LIMIT: usize = 2;
+        let content: Vec<u8> = (0..6 * PLENGTH).map(|n| (n / 11) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces);
+        let received = mock.received();
+        let addr = mock.spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .max_buffered_pieces(LIMIT)
+            .build()
+            .await
+            .unwrap();
+        let most_ahead = AtomicUsize::new(0);
+        client
+            .download_streaming(|idx, _| {
+                let (received, most_ahead) = (received.clone(), &most_ahead);
+                async move {
+                    // Gives the fetch time to run as far ahead as it's allowed to
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    // Every piece is a single block, so one request each
+                    let requested = received
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter(|tag| **tag == MessageTag::Request)
+                        .count();
+                    most_ahead.fetch_max(requested - (idx + 1), Ordering::SeqCst);
+                    Ok(())
+                }
+            })
+            .await
+            .unwrap();
+        // Besides the buffered pieces, the fetch may hold one it can't hand over yet
+        let most_ahead = most_ahead.into_inner();
+        assert!(most_ahead <= LIMIT + 1, "{} pieces ahead", most_ahead);
+        assert!(most_ahead >= LIMIT, "{} pieces ahead", most_ahead);
+    }
+
+    #[tokio::test]
+    async fn a_failed_download_ends_every_task_and_connection() {
+        let (torrent, pieces) = mock::torrent(&[4u8; 2 * PLENGTH], PLENGTH);
+        let mock = MockPeer::new(torrent.info_hash().unwrap(), pieces).missing(1);
+        let open = mock.open_connections();
+        let addr = mock.spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .max_retries(0)
+            .lsd(true)
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(open.load(Ordering::SeqCst), 1);
+        assert!(client.download_to_vec().await.is_err());
+        // The announcer drops its end of the channel once it stops
+        let mut lsd_peers = client.lsd_peers.take().unwrap();
+        let announcer_done = async { while lsd_peers.recv().await.is_some() {} };
+        tokio::time::timeout(Duration::from_secs(1), announcer_done)
+            .await
+            .unwrap();
+        let closed = async {
+            while open.load(Ordering::SeqCst) > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(1), closed)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn cancelling_stops_a_download_waiting_on_a_slow_peer() {
+        let (torrent, pieces) = mock::torrent(&[5u8; 2 * PLENGTH], PLENGTH);
+        let mock =
+            MockPeer::new(torrent.info_hash().unwrap(), pieces).delay(Duration::from_secs(10));
+        let mut client = client(&torrent, mock).await;
+        let cancel = client.cancel_handle();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            cancel.cancel();
+        });
+        let err = tokio::time::timeout(Duration::from_secs(1), client.download_to_vec())
+            .await
+            .expect("Cancelling waited on the piece in flight")
+            .unwrap_err();
+        let cancelled = err.downcast::<Cancelled>().unwrap();
+        assert!(cancelled.0.is_empty());
+        // The mock only notices once done sleeping over the request
+        assert!(client.peers.is_empty());
+    }
+
+    // A one piece torrent whose mock is set up by `corrupt`, downloaded under `policy`
+    async fn download_with_policy(
+        policy: MismatchPolicy,
+        corrupt: impl FnOnce(MockPeer) -> MockPeer,
+    ) -> (anyhow::Result<Vec<u8>>, PeerPool) {
+        let content = vec![3u8; PLENGTH];
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let mock = corrupt(MockPeer::new(torrent.info_hash().unwrap(), pieces));
+        let addr = mock.spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .max_retries(0)
+            .mismatch_policy(policy)
+            .build()
+            .await
+            .unwrap();
+        let result = client.download_to_vec().await;
+        (result, std::mem::take(&mut client.pool))
+    }
+
+    #[tokio::test]
+    async fn retry_same_peer_recovers_from_transient_corruption() {
+        let policy = MismatchPolicy::RetrySamePeer(2);
+        let (result, _) = download_with_policy(policy, |mock| mock.corrupt_times(0, 2)).await;
+        assert_eq!(result.unwrap(), vec![3u8; PLENGTH]);
+        let (result, pool) = download_with_policy(policy, |mock| mock.corrupt_times(0, 3)).await;
+        assert!(result.is_err());
+        assert_eq!(pool.count(PeerState::Failed), 1);
+    }
+
+    #[tokio::test]
+    async fn switch_peer_drops_the_peer_without_banning_it() {
+        let policy = MismatchPolicy::SwitchPeer;
+        let (result, pool) = download_with_policy(policy, |mock| mock.corrupt_times(0, 1)).await;
+        assert!(result.is_err());
+        assert_eq!(pool.count(PeerState::Failed), 1);
+        assert_eq!(pool.count(PeerState::Unreliable), 0);
+    }
+
+    #[tokio::test]
+    async fn ban_peer_bans_the_peer_that_served_corrupt_data() {
+        let policy = MismatchPolicy::BanPeer;
+        let (result, pool) = download_with_policy(policy, |mock| mock.corrupt(0)).await;
+        assert!(result.is_err());
+        assert_eq!(pool.count(PeerState::Unreliable), 1);
+    }
+
+    #[tokio::test]
+    async fn a_peer_announced_on_the_local_network_joins_the_pool() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n % 251) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let partial = MockPeer::new(info_hash, pieces.clone())
+            .missing(1)
+            .spawn()
+            .await
+            .unwrap();
+        let on_lan = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([partial])
+            .no_tracker(true)
+            .max_retries(1)
+            .backoff(Duration::ZERO)
+            .build()
+            .await
+            .unwrap();
+        // What the announcer hands over for another client's announce, it has the last piece
+        let (tx, rx) = mpsc::channel(1);
+        client.lsd_peers = Some(rx);
+        let (ours, theirs) = (Lsd::new(), Lsd::new());
+        let announce = theirs.announce_message(&info_hash, on_lan.port());
+        let from = SocketAddr::from(([127, 0, 0, 1], 6771));
+        let peer = ours.parse_announce(announce.as_bytes(), from, &info_hash);
+        assert_eq!(peer, Some(on_lan));
+        tx.send(peer.unwrap()).await.unwrap();
+        assert_eq!(client.pool.state(&on_lan), None);
+
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+        assert_eq!(client.pool.state(&on_lan), Some(PeerState::Connected));
+    }
+
+    #[tokio::test]
+    async fn peers_silent_after_the_handshake_are_dropped_and_the_rest_used() {
+        let content: Vec<u8> = (0..2 * PLENGTH).map(|n| (n % 13) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PLENGTH);
+        let info_hash = torrent.info_hash().unwrap();
+        let silent = MockPeer::new(info_hash, pieces.clone())
+            .missing(0)
+            .missing(1)
+            .spawn()
+            .await
+            .unwrap();
+        let seeder = MockPeer::new(info_hash, pieces).spawn().await.unwrap();
+        let started = std::time::Instant::now();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([silent, seeder])
+            .bitfield_timeout(Duration::from_millis(100))
+            .max_retries(0)
+            .build()
+            .await
+            .unwrap();
+        // Both connect at once, the silent one only costs its window
+        assert!(
+            started.elapsed() < Duration::from_millis(250),
+            "{:?}",
+            started.elapsed()
+        );
+        assert_eq!(client.pool.state(&silent), Some(PeerState::Failed));
+        assert_eq!(client.pool.state(&seeder), Some(PeerState::Connected));
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
     }
 }
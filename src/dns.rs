@@ -0,0 +1,159 @@
+use std::{future::Future, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+
+// Head start each connection attempt gets before the next address is tried alongside it
+pub const CONNECT_STAGGER: Duration = Duration::from_millis(250);
+
+// Resolves on the blocking pool instead of stalling the runtime, with both families interleaved
+// so whoever connects tries IPv6 and IPv4 in turn instead of exhausting one family first
+#[derive(Debug, Default, Clone)]
+pub struct Resolver;
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs = resolve(&host, 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// Lets a resolver handed to the client builder be shared as a trait object
+pub(crate) struct SharedResolver(pub Arc<dyn Resolve>);
+
+impl Resolve for SharedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        self.0.resolve(name)
+    }
+}
+
+/// Every address of `host`, alternating IPv6 and IPv4
+pub async fn resolve(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    let addrs = interleave(tokio::net::lookup_host((host, port)).await?);
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses", host),
+        ));
+    }
+    Ok(addrs)
+}
+
+// IPv6 first, then one of each family in turn, each family keeping its own order
+fn interleave(addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) =
+        addrs.into_iter().partition(SocketAddr::is_ipv6);
+    let mut addrs = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => break,
+            (a, b) => addrs.extend(a.into_iter().chain(b)),
+        }
+    }
+    addrs
+}
+
+/// Happy eyeballs (RFC 8305): connects to `addrs` in order, starting the next attempt once
+/// the previous one failed or had `stagger` to itself, and returns the first to succeed
+pub async fn connect_staggered<F, Fut, T>(
+    addrs: &[SocketAddr],
+    stagger: Duration,
+    connect: F,
+) -> io::Result<(SocketAddr, T)>
+where
+    F: Fn(SocketAddr) -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let connect = &connect;
+    let attempt = |addr: SocketAddr| async move { (addr, connect(addr).await) };
+    let mut remaining = addrs.iter().copied();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "No address to connect to");
+    loop {
+        if attempts.is_empty() {
+            match remaining.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => return Err(last_err),
+            }
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(connected) => return Ok((addr, connected)),
+                // The next address doesn't wait out the stagger of a failed one
+                Err(err) => {
+                    last_err = err;
+                    attempts.extend(remaining.next().map(attempt));
+                }
+            },
+            _ = tokio::time::sleep(stagger), if remaining.len() > 0 => {
+                attempts.extend(remaining.next().map(attempt));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn families_alternate_starting_with_ipv6() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+        let resolved = [
+            addr("10.0.0.1:80"),
+            addr("10.0.0.2:80"),
+            addr("10.0.0.3:80"),
+            addr("[::1]:80"),
+            addr("[::2]:80"),
+        ];
+        assert_eq!(
+            interleave(resolved),
+            vec![
+                addr("[::1]:80"),
+                addr("10.0.0.1:80"),
+                addr("[::2]:80"),
+                addr("10.0.0.2:80"),
+                addr("10.0.0.3:80"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_stalled_address_is_overtaken_after_the_stagger() {
+        let stalled: SocketAddr = "[::1]:1".parse().unwrap();
+        let failing: SocketAddr = "10.0.0.1:1".parse().unwrap();
+        let working: SocketAddr = "10.0.0.2:1".parse().unwrap();
+        let start = tokio::time::Instant::now();
+        let (addr, _) = connect_staggered(
+            &[stalled, failing, working],
+            Duration::from_millis(50),
+            |addr| async move {
+                if addr == stalled {
+                    std::future::pending::<()>().await;
+                }
+                if addr == failing {
+                    return Err(io::Error::from(io::ErrorKind::ConnectionRefused));
+                }
+                Ok(())
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(addr, working);
+        // One stagger for the stalled address, none for the one that failed right away
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        let refused = connect_staggered(&[failing], Duration::from_millis(50), |_| async {
+            Err::<(), _>(io::Error::from(io::ErrorKind::ConnectionRefused))
+        })
+        .await;
+        assert_eq!(
+            refused.unwrap_err().kind(),
+            io::ErrorKind::ConnectionRefused
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+// Extended messages (BEP 10) use id 0 for the handshake, the rest are assigned in it
+pub const HANDSHAKE_ID: u8 = 0;
+// The id peers have to use for ut_metadata messages sent to us
+pub const UT_METADATA_ID: u8 = 1;
+// Metadata is exchanged in pieces of this size, the last one may be shorter (BEP 9)
+pub const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    // Extension names mapped to the message id the sender wants them on, 0 disables one
+    #[serde(default)]
+    pub m: BTreeMap<String, u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    /// Our handshake, advertising ut_metadata when we have the info dict to serve
+    pub fn ours(metadata_size: Option<usize>) -> Self {
+        let mut m = BTreeMap::new();
+        if metadata_size.is_some() {
+            m.insert("ut_metadata".to_string(), UT_METADATA_ID);
+        }
+        Self { m, metadata_size }
+    }
+    pub fn id(&self, extension: &str) -> Option<u8> {
+        self.m.get(extension).copied().filter(|id| *id != 0)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MetadataMessage {
+    // 0 request, 1 data, 2 reject
+    msg_type: u8,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+/// Answers a ut_metadata request with the piece of `info` it asks for, or a reject. Anything
+/// else, like the data and rejects of a peer fetching metadata from us, gets no answer
+pub fn serve_metadata(info: &[u8], request: &[u8]) -> Option<Vec<u8>> {
+    // A data message carries its raw piece after the dict, so it doesn't parse on its own
+    let request: MetadataMessage = serde_bencode::from_bytes(request).ok()?;
+    if request.msg_type != 0 {
+        return None;
+    }
+    // The piece number comes from the peer, it may be anything
+    let start = request
+        .piece
+        .checked_mul(METADATA_PIECE_SIZE)
+        .filter(|start| *start < info.len());
+    let Some(start) = start else {
+        let reject = MetadataMessage {
+            msg_type: 2,
+            piece: request.piece,
+            total_size: None,
+        };
+        return serde_bencode::to_bytes(&reject).ok();
+    };
+    let data = MetadataMessage {
+        msg_type: 1,
+        piece: request.piece,
+        total_size: Some(info.len()),
+    };
+    // The raw piece follows right after the bencoded dict
+    let mut response = serde_bencode::to_bytes(&data).ok()?;
+    let length = (info.len() - start).min(METADATA_PIECE_SIZE);
+    response.extend(&info[start..start + length]);
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(piece: usize) -> Vec<u8> {
+        format!("d8:msg_typei0e5:piecei{}ee", piece).into_bytes()
+    }
+
+    #[test]
+    fn serves_the_requested_piece() {
+        let info: Vec<u8> = (0..METADATA_PIECE_SIZE + 10).map(|n| n as u8).collect();
+        let response = serve_metadata(&info, &request(0)).unwrap();
+        let header = b"d8:msg_typei1e5:piecei0e10:total_sizei16394ee";
+        assert_eq!(&response[..header.len()], header);
+        assert_eq!(&response[header.len()..], &info[..METADATA_PIECE_SIZE]);
+
+        let response = serve_metadata(&info, &request(1)).unwrap();
+        assert!(response.ends_with(&info[METADATA_PIECE_SIZE..]));
+    }
+
+    #[test]
+    fn rejects_pieces_past_the_end_without_overflowing() {
+        let info = vec![0u8; 100];
+        for piece in [1, 1 << 62, i64::MAX as usize] {
+            let response = serve_metadata(&info, &request(piece)).unwrap();
+            let reject = format!("d8:msg_typei2e5:piecei{}ee", piece);
+            assert_eq!(response, reject.into_bytes());
+        }
+    }
+
+    #[test]
+    fn ignores_data_and_rejects_sent_to_us() {
+        let info = vec![0u8; 100];
+        let mut data = b"d8:msg_typei1e5:piecei0e10:total_sizei3ee".to_vec();
+        data.extend(b"abc");
+        assert_eq!(serve_metadata(&info, &data), None);
+        assert_eq!(serve_metadata(&info, b"d8:msg_typei2e5:piecei0ee"), None);
+    }
+}
@@ -0,0 +1,18 @@
+pub mod client;
+pub mod dns;
+pub mod extension;
+pub mod lsd;
+pub mod magnet;
+pub mod merkle;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod mse;
+pub mod peer;
+pub mod picker;
+pub mod pool;
+pub mod rate;
+pub mod session;
+pub mod source;
+pub mod torrent;
+pub mod tracker;
+pub mod webseed;
@@ -0,0 +1,229 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{net::UdpSocket, sync::mpsc, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    source::{Discovery, PeerSource},
+    tracker::TrackerRequest,
+};
+
+// Local Service Discovery multicast group (BEP 14)
+const GROUP: Ipv4Addr = Ipv4Addr::new(239, 192, 152, 143);
+const PORT: u16 = 6771;
+// How long a discovery listens for other announces after sending ours
+const LISTEN: Duration = Duration::from_secs(2);
+// BEP 14 allows one announce per torrent every 5 minutes
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Finds peers of the same torrent on the local network, never use it for private torrents
+pub struct Lsd {
+    // Sent along with our announces so we can recognise and skip them
+    cookie: String,
+    listen: Duration,
+}
+
+impl Default for Lsd {
+    fn default() -> Self {
+        Self {
+            cookie: hex::encode(rand::random::<[u8; 8]>()),
+            listen: LISTEN,
+        }
+    }
+}
+
+impl Lsd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn listen(mut self, listen: Duration) -> Self {
+        self.listen = listen;
+        self
+    }
+    pub fn announce_message(&self, info_hash: &[u8; 20], port: u16) -> String {
+        format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}:{}\r\nPort: {}\r\nInfohash: {}\r\ncookie: {}\r\n\r\n\r\n",
+            GROUP,
+            PORT,
+            port,
+            hex::encode(info_hash),
+            self.cookie
+        )
+    }
+    /// The peer announced in `data` if it's for `info_hash` and not one of our own announces
+    pub fn parse_announce(
+        &self,
+        data: &[u8],
+        from: SocketAddr,
+        info_hash: &[u8; 20],
+    ) -> Option<SocketAddr> {
+        let text = std::str::from_utf8(data).ok()?;
+        let mut lines = text.split("\r\n");
+        if !lines.next()?.starts_with("BT-SEARCH * HTTP/1.1") {
+            return None;
+        }
+        let mut port = None;
+        let mut matches = false;
+        for line in lines {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "port" => port = value.parse::<u16>().ok(),
+                // An announce may carry several hashes, one header each
+                "infohash" => matches |= value.eq_ignore_ascii_case(&hex::encode(info_hash)),
+                "cookie" if value == self.cookie => return None,
+                _ => {}
+            }
+        }
+        matches.then_some(SocketAddr::new(from.ip(), port?))
+    }
+    /// Keeps announcing `info_hash` every `interval`, starting one interval from now, until
+    /// `shutdown`. Peers announced by others meanwhile come out of the returned channel
+    pub fn spawn_announcer(
+        self,
+        info_hash: [u8; 20],
+        port: u16,
+        interval: Duration,
+        shutdown: CancellationToken,
+    ) -> mpsc::Receiver<SocketAddr> {
+        let (tx, rx) = mpsc::channel(64);
+        tokio::spawn(async move {
+            let Ok(socket) = Self::bind() else {
+                return;
+            };
+            let message = self.announce_message(&info_hash, port);
+            let mut ticks = tokio::time::interval_at(Instant::now() + interval, interval);
+            let mut buffer = [0u8; 1500];
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = ticks.tick() => {
+                        let group = SocketAddrV4::new(GROUP, PORT);
+                        let _ = socket.send_to(message.as_bytes(), group).await;
+                    }
+                    received = socket.recv_from(&mut buffer) => {
+                        let Ok((len, from)) = received else {
+                            continue;
+                        };
+                        let Some(peer) = self.parse_announce(&buffer[..len], from, &info_hash)
+                        else {
+                            continue;
+                        };
+                        // A full channel only drops a peer the next announce brings back
+                        if let Err(mpsc::error::TrySendError::Closed(_)) = tx.try_send(peer) {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        rx
+    }
+    fn bind() -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        // Every client on the host listens on the same port
+        socket.set_reuse_address(true)?;
+        socket.set_nonblocking(true)?;
+        socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT).into())?;
+        socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        UdpSocket::from_std(socket.into())
+    }
+}
+
+impl PeerSource for Lsd {
+    fn discover<'a>(&'a self, info_hash: [u8; 20], request: &'a TrackerRequest) -> Discovery<'a> {
+        Box::pin(async move {
+            let socket = Self::bind()?;
+            let message = self.announce_message(&info_hash, request.port);
+            socket
+                .send_to(message.as_bytes(), SocketAddrV4::new(GROUP, PORT))
+                .await?;
+            let mut peers = Vec::new();
+            let mut buffer = [0u8; 1500];
+            let deadline = Instant::now() + self.listen;
+            while let Ok(received) =
+                tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await
+            {
+                let (len, from) = received?;
+                if let Some(peer) = self.parse_announce(&buffer[..len], from, &info_hash) {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            Ok(peers)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INFO_HASH: [u8; 20] = [0xab; 20];
+
+    fn from() -> SocketAddr {
+        "192.168.1.7:6771".parse().unwrap()
+    }
+
+    fn announce(headers: &str) -> Vec<u8> {
+        format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\n{}\r\n\r\n",
+            headers
+        )
+        .into_bytes()
+    }
+
+    #[test]
+    fn parses_an_announce_for_our_torrent() {
+        let lsd = Lsd::new();
+        let data = announce(&format!(
+            "Port: 51413\r\nInfohash: {}",
+            hex::encode_upper(INFO_HASH)
+        ));
+        assert_eq!(
+            lsd.parse_announce(&data, from(), &INFO_HASH),
+            Some("192.168.1.7:51413".parse().unwrap())
+        );
+        // Our own announces come back to us too
+        let own = lsd.announce_message(&INFO_HASH, 51413);
+        assert_eq!(lsd.parse_announce(own.as_bytes(), from(), &INFO_HASH), None);
+    }
+
+    #[test]
+    fn an_announce_without_our_infohash_is_ignored() {
+        let lsd = Lsd::new();
+        assert_eq!(
+            lsd.parse_announce(&announce("Port: 51413"), from(), &INFO_HASH),
+            None
+        );
+        let other = announce(&format!(
+            "Port: 51413\r\nInfohash: {}",
+            hex::encode([1u8; 20])
+        ));
+        assert_eq!(lsd.parse_announce(&other, from(), &INFO_HASH), None);
+    }
+
+    #[test]
+    fn an_announce_with_a_bad_port_is_ignored() {
+        let lsd = Lsd::new();
+        let infohash = hex::encode(INFO_HASH);
+        for port in ["70000", "-1", "http", ""] {
+            let data = announce(&format!("Port: {}\r\nInfohash: {}", port, infohash));
+            assert_eq!(
+                lsd.parse_announce(&data, from(), &INFO_HASH),
+                None,
+                "{}",
+                port
+            );
+        }
+        let data = announce(&format!("Infohash: {}", infohash));
+        assert_eq!(lsd.parse_announce(&data, from(), &INFO_HASH), None);
+    }
+}
@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, Context};
+
+// What a magnet URI carries, fetching the info dict it points to isn't supported yet
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    // `dn`, the suggested display name
+    pub name: Option<String>,
+    // `tr`, in the order they appear
+    pub trackers: Vec<String>,
+    // `x.pe`, peers to connect to before any tracker is asked
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> anyhow::Result<Self> {
+        let query = uri.strip_prefix("magnet:?").context("Not a magnet URI")?;
+        let mut info_hash = None;
+        let mut magnet = Magnet::default();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_btih(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                // Only literal addresses, a peer given by hostname is skipped
+                "x.pe" => magnet.peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("Magnet URI has no BitTorrent info hash")?;
+        Ok(magnet)
+    }
+}
+
+// 40 hex digits, or the older 32 character base32 form
+fn parse_btih(hash: &str) -> anyhow::Result<[u8; 20]> {
+    let mut info_hash = [0u8; 20];
+    match hash.len() {
+        40 => hex::decode_to_slice(hash, &mut info_hash).context("Invalid hex info hash")?,
+        32 => {
+            let mut bits: u64 = 0;
+            let mut count = 0;
+            let mut pos = 0;
+            for c in hash.bytes() {
+                let value = match c.to_ascii_uppercase() {
+                    c @ b'A'..=b'Z' => c - b'A',
+                    c @ b'2'..=b'7' => c - b'2' + 26,
+                    _ => bail!("Invalid base32 info hash"),
+                };
+                bits = (bits << 5) | value as u64;
+                count += 5;
+                if count >= 8 {
+                    count -= 8;
+                    info_hash[pos] = (bits >> count) as u8;
+                    pos += 1;
+                }
+            }
+        }
+        _ => bail!("Info hash has {} characters", hash.len()),
+    }
+    Ok(info_hash)
+}
+
+fn percent_decode(text: &str) -> anyhow::Result<String> {
+    let mut decoded = Vec::with_capacity(text.len());
+    let mut bytes = text.bytes();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            b'%' => {
+                let hex = [
+                    bytes.next().context("Truncated escape")?,
+                    bytes.next().context("Truncated escape")?,
+                ];
+                let mut byte = [0u8];
+                hex::decode_to_slice(hex, &mut byte).context("Invalid escape")?;
+                decoded.push(byte[0]);
+            }
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+    }
+    Ok(String::from_utf8(decoded)?)
+}
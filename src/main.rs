@@ -1,19 +1,32 @@
-use std::{fs::File, io::Write};
-
-use client::Client;
-use torrent::Torrent;
-mod client;
-mod peer;
-mod torrent;
-mod tracker;
+use anyhow::Context;
+use torrent::{
+    client::ClientBuilder,
+    torrent::{dump_bencode, Torrent},
+};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let buff = std::fs::read("sample.torrent")?;
-    let torrent: Torrent = serde_bencode::from_bytes(&buff)?;
-    let mut client = Client::new(&torrent).await?;
-    let buffer = client.download_file().await?;
-    let mut file = File::create(torrent.info.name)?;
-    file.write_all(&buffer)?;
+    let torrent = Torrent::from_bytes(&buff)?;
+    let mut builder = ClientBuilder::new(&torrent);
+    // `--peer <addr>` connects to a known peer, `--no-tracker` skips the announce, `--dump`
+    // prints the decoded torrent file instead of downloading
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--peer" => {
+                let addr = args.next().context("--peer needs an address")?;
+                builder = builder.peer(addr.parse().context("Invalid peer address")?);
+            }
+            "--no-tracker" => builder = builder.no_tracker(true),
+            "--dump" => {
+                print!("{}", dump_bencode(&buff)?);
+                return Ok(());
+            }
+            other => anyhow::bail!("Unknown argument : {}", other),
+        }
+    }
+    let mut client = builder.build().await?;
+    client.download_file(torrent.display_name()).await?;
     Ok(())
 }
@@ -0,0 +1,46 @@
+use sha2::{Digest, Sha256};
+
+// v2 torrents hash content in blocks of this size, whatever the piece length (BEP 52)
+pub const BLOCK_SIZE: usize = 16 * 1024;
+
+pub type Hash = [u8; 32];
+
+pub fn sha256(data: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hasher.finalize().into()
+}
+
+/// Root of a tree whose bottom layer is `leaves` padded with `pad` up to `width` leaves
+pub fn root(mut layer: Vec<Hash>, width: usize, pad: Hash) -> Hash {
+    layer.resize(width.max(1).next_power_of_two(), pad);
+    while layer.len() > 1 {
+        layer = layer
+            .chunks_exact(2)
+            .map(|pair| sha256(&[pair[0], pair[1]].concat()))
+            .collect();
+    }
+    layer[0]
+}
+
+// Leaf hashes of some content, the last block may be shorter
+pub fn leaves(data: &[u8]) -> Vec<Hash> {
+    data.chunks(BLOCK_SIZE).map(sha256).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_matches_known_answers() {
+        assert_eq!(
+            hex::encode(sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            hex::encode(sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,461 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream},
+    net::TcpListener,
+    sync::mpsc,
+};
+
+use sha1::{Digest, Sha1};
+
+use crate::{
+    peer::{
+        message::{Message, MessageTag},
+        to_bitfield, HandShake,
+    },
+    torrent::Torrent,
+};
+
+// A scripted seeder, either on a localhost socket for `Peer::new` or on an in-memory stream for
+// `Peer::new_from_stream`
+#[derive(Debug, Clone)]
+pub struct MockPeer {
+    info_hash: [u8; 20],
+    // Content of every piece, by index
+    pieces: Vec<Vec<u8>>,
+    // Pieces left out of the bitfield
+    missing: Vec<usize>,
+    // Pieces served with their first byte flipped
+    corrupt: Vec<usize>,
+    // Pieces served corrupt until their count runs out, shared by every connection
+    corrupt_times: Vec<(usize, Arc<AtomicUsize>)>,
+    // Wait before answering each request
+    delay: Duration,
+    // Never unchoke
+    choke: bool,
+    // Wait after an Interested before unchoking
+    unchoke_after: Duration,
+    // Announce the pieces one Have at a time, last piece first, instead of with a bitfield
+    have_messages: bool,
+    // Reserved bytes sent in the handshake, our own by default
+    reserved: Option<[u8; 8]>,
+    // Answer every request with only the first half of the block
+    short_blocks: bool,
+    // Cancel messages received, shared by every connection
+    cancels: Arc<AtomicUsize>,
+    // Tag of every message received, in order, shared by every connection
+    received: Arc<Mutex<Vec<MessageTag>>>,
+    // Connections being served right now
+    open: Arc<AtomicUsize>,
+}
+
+impl MockPeer {
+    pub fn new(info_hash: [u8; 20], pieces: Vec<Vec<u8>>) -> Self {
+        Self {
+            info_hash,
+            pieces,
+            missing: Vec::new(),
+            corrupt: Vec::new(),
+            corrupt_times: Vec::new(),
+            delay: Duration::ZERO,
+            choke: false,
+            unchoke_after: Duration::ZERO,
+            have_messages: false,
+            reserved: None,
+            short_blocks: false,
+            cancels: Arc::new(AtomicUsize::new(0)),
+            received: Arc::default(),
+            open: Arc::default(),
+        }
+    }
+    pub fn missing(mut self, idx: usize) -> Self {
+        self.missing.push(idx);
+        self
+    }
+    pub fn corrupt(mut self, idx: usize) -> Self {
+        self.corrupt.push(idx);
+        self
+    }
+    /// Serves piece `idx` corrupt the first `times` times it's asked for, then intact
+    pub fn corrupt_times(mut self, idx: usize, times: usize) -> Self {
+        self.corrupt_times
+            .push((idx, Arc::new(AtomicUsize::new(times))));
+        self
+    }
+    pub fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+    pub fn choke(mut self, choke: bool) -> Self {
+        self.choke = choke;
+        self
+    }
+    pub fn unchoke_after(mut self, delay: Duration) -> Self {
+        self.unchoke_after = delay;
+        self
+    }
+    pub fn have_messages(mut self, have_messages: bool) -> Self {
+        self.have_messages = have_messages;
+        self
+    }
+    pub fn reserved(mut self, reserved: [u8; 8]) -> Self {
+        self.reserved = Some(reserved);
+        self
+    }
+    pub fn short_blocks(mut self, short_blocks: bool) -> Self {
+        self.short_blocks = short_blocks;
+        self
+    }
+    /// Counts the Cancel messages this mock receives, over every connection
+    pub fn cancels(&self) -> Arc<AtomicUsize> {
+        self.cancels.clone()
+    }
+    /// Tags of the messages this mock receives, over every connection
+    pub fn received(&self) -> Arc<Mutex<Vec<MessageTag>>> {
+        self.received.clone()
+    }
+    /// How many connections to this mock are open, drops as soon as one is closed
+    pub fn open_connections(&self) -> Arc<AtomicUsize> {
+        self.open.clone()
+    }
+    /// Starts serving every incoming connection and returns the address to connect to
+    pub async fn spawn(self) -> io::Result<SocketAddr> {
+        self.spawn_at("127.0.0.1:0".parse().expect("Can't panic"))
+            .await
+    }
+    /// Like `spawn`, listening on `addr`, e.g. every loopback address on `0.0.0.0:0`
+    pub async fn spawn_at(self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr).await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let mock = self.clone();
+                tokio::spawn(async move {
+                    mock.open.fetch_add(1, Ordering::SeqCst);
+                    let _ = mock.serve(stream).await;
+                    mock.open.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+        Ok(addr)
+    }
+    // Counts down the piece's remaining corrupt serves, if it has any
+    fn is_corrupt(&self, idx: usize) -> bool {
+        self.corrupt.contains(&idx)
+            || self.corrupt_times.iter().any(|(piece, left)| {
+                *piece == idx
+                    && left
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok()
+            })
+    }
+    /// Serves a single connection over an in-memory pipe and returns our end of it
+    pub fn duplex(self) -> DuplexStream {
+        let (ours, theirs) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            let _ = self.serve(theirs).await;
+        });
+        ours
+    }
+    async fn serve<S>(&self, mut stream: S) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut handshake = [0u8; 68];
+        stream.read_exact(&mut handshake).await?;
+        if handshake[28..48] != self.info_hash {
+            anyhow::bail!("Handshake for another torrent");
+        }
+        let peer_id = [b'm'; 20];
+        let mut reply = HandShake::new(&self.info_hash, &peer_id);
+        if let Some(reserved) = self.reserved {
+            reply.reserved = reserved;
+        }
+        stream.write_all(&reply.to_bytes()).await?;
+        let have: Vec<bool> = (0..self.pieces.len())
+            .map(|idx| !self.missing.contains(&idx))
+            .collect();
+        if self.have_messages {
+            for idx in (0..have.len()).rev().filter(|idx| have[*idx]) {
+                Message::encode(&mut stream, MessageTag::Have, &(idx as u32).to_be_bytes()).await?;
+            }
+        } else if have.contains(&true) {
+            // Like real peers, one with nothing to offer leaves the bitfield out and stays silent
+            Message::encode(&mut stream, MessageTag::Bitfield, &to_bitfield(&have)).await?;
+        }
+        loop {
+            let length = stream.read_u32().await? as usize;
+            // Keep-alive
+            if length == 0 {
+                continue;
+            }
+            let mut buffer = vec![0u8; length];
+            stream.read_exact(&mut buffer).await?;
+            let tag = MessageTag::from(buffer[0].into());
+            if let Ok(tag) = tag {
+                self.received.lock().unwrap().push(tag);
+            }
+            match tag {
+                Ok(MessageTag::Interested) if !self.choke => {
+                    tokio::time::sleep(self.unchoke_after).await;
+                    Message::encode(&mut stream, MessageTag::Unchoke, &[]).await?;
+                }
+                Ok(MessageTag::Request) if buffer.len() == 13 => {
+                    let field = |at: usize| {
+                        u32::from_be_bytes(buffer[at..at + 4].try_into().expect("Can't panic"))
+                            as usize
+                    };
+                    let (idx, offset, mut len) = (field(1), field(5), field(9));
+                    if self.short_blocks {
+                        len = len.div_ceil(2);
+                    }
+                    let Some(block) = self
+                        .pieces
+                        .get(idx)
+                        .and_then(|piece| piece.get(offset..offset + len))
+                    else {
+                        continue;
+                    };
+                    let mut payload = Vec::with_capacity(8 + len);
+                    payload.extend((idx as u32).to_be_bytes());
+                    payload.extend((offset as u32).to_be_bytes());
+                    payload.extend(block);
+                    if offset == 0 && self.is_corrupt(idx) {
+                        payload[8] ^= 0xff;
+                    }
+                    tokio::time::sleep(self.delay).await;
+                    Message::encode(&mut stream, MessageTag::Piece, &payload).await?;
+                }
+                Ok(MessageTag::Cancel) => {
+                    self.cancels.fetch_add(1, Ordering::SeqCst);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// An HTTP tracker on localhost answering the `n`th announce, from 0, with the body
+/// `answer(n)` gives, or never when it gives `None`. Returns the announce URL and the query
+/// string of every announce as it arrives
+pub async fn tracker<F>(answer: F) -> io::Result<(String, mpsc::UnboundedReceiver<String>)>
+where
+    F: Fn(usize) -> Option<Vec<u8>> + Send + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/announce", listener.local_addr()?);
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        for n in 0.. {
+            let Ok((mut stream, _)) = listener.accept().await else {
+                break;
+            };
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let Ok(byte) = stream.read_u8().await else {
+                    break;
+                };
+                head.push(byte);
+            }
+            let head = String::from_utf8_lossy(&head);
+            let query = head
+                .split_whitespace()
+                .nth(1)
+                .and_then(|target| target.split_once('?'))
+                .map_or("", |(_, query)| query);
+            let _ = tx.send(query.to_string());
+            let Some(body) = answer(n) else {
+                // Holds the connection open without a word
+                tokio::spawn(async move {
+                    let _stream = stream;
+                    std::future::pending::<()>().await;
+                });
+                continue;
+            };
+            let mut response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    Ok((url, rx))
+}
+
+/// How a mock web seed spoils one of its answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebseedFault {
+    // The first byte of the range flipped
+    Corrupt,
+    // Only the first half of the range
+    Truncated,
+}
+
+/// A web seed on localhost serving `content` as a single file, `faults` spoil the answers to
+/// the requests they're numbered with, from 0. Returns the file's URL and how many requests
+/// it got
+pub async fn webseed(
+    content: Vec<u8>,
+    faults: Vec<(usize, WebseedFault)>,
+) -> io::Result<(String, Arc<AtomicUsize>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/mock", listener.local_addr()?);
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let n = counter.fetch_add(1, Ordering::SeqCst);
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let Ok(byte) = stream.read_u8().await else {
+                    break;
+                };
+                head.push(byte);
+            }
+            let head = String::from_utf8_lossy(&head).to_ascii_lowercase();
+            let (start, end) = head
+                .lines()
+                .find_map(|line| line.strip_prefix("range: bytes="))
+                .and_then(|range| range.trim().split_once('-'))
+                .and_then(|(start, end)| Some((start.parse().ok()?, end.parse::<usize>().ok()?)))
+                .unwrap_or((0, content.len().saturating_sub(1)));
+            let mut body = content.get(start..=end).unwrap_or_default().to_vec();
+            match faults.iter().find(|(at, _)| *at == n) {
+                Some((_, WebseedFault::Corrupt)) => body[0] ^= 0xff,
+                Some((_, WebseedFault::Truncated)) => body.truncate(body.len() / 2),
+                None => {}
+            }
+            let mut response = format!(
+                "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes {}-{}/{}\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n",
+                start,
+                end,
+                content.len(),
+                body.len()
+            )
+            .into_bytes();
+            response.extend(body);
+            let _ = stream.write_all(&response).await;
+        }
+    });
+    Ok((url, requests))
+}
+
+/// A compact announce response listing `peers`, which must all be IPv4
+pub fn announce_response(interval: u64, peers: &[SocketAddr]) -> Vec<u8> {
+    let mut body = format!("d8:intervali{}e5:peers{}:", interval, peers.len() * 6).into_bytes();
+    for peer in peers {
+        let SocketAddr::V4(peer) = peer else {
+            panic!("Compact peers are IPv4 only, not {}", peer);
+        };
+        body.extend(peer.ip().octets());
+        body.extend(peer.port().to_be_bytes());
+    }
+    body.push(b'e');
+    body
+}
+
+/// The value of `key` in an announce's query string, still percent-encoded
+pub fn query_param<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(name, _)| *name == key)
+        .map(|(_, value)| value)
+}
+
+/// A trackerless single file torrent for `content`, with the pieces a `MockPeer` serves it as
+pub fn torrent(content: &[u8], plength: usize) -> (Torrent, Vec<Vec<u8>>) {
+    let pieces: Vec<Vec<u8>> = content.chunks(plength).map(<[u8]>::to_vec).collect();
+    let mut raw = format!(
+        "d4:infod6:lengthi{}e4:name4:mock12:piece lengthi{}e6:pieces{}:",
+        content.len(),
+        plength,
+        pieces.len() * 20
+    )
+    .into_bytes();
+    for piece in &pieces {
+        raw.extend(Sha1::digest(piece));
+    }
+    raw.extend(b"ee");
+    let torrent = Torrent::from_bytes(&raw).expect("Mock torrent should parse");
+    (torrent, pieces)
+}
+
+/// A trackerless multi file torrent named `mock`, `files` are each path and content in order
+pub fn multi_file_torrent(files: &[(&str, &[u8])], plength: usize) -> (Torrent, Vec<Vec<u8>>) {
+    let content: Vec<u8> = files.iter().flat_map(|(_, data)| data.to_vec()).collect();
+    let pieces: Vec<Vec<u8>> = content.chunks(plength).map(<[u8]>::to_vec).collect();
+    let mut raw = b"d4:infod5:filesl".to_vec();
+    for (path, data) in files {
+        raw.extend(format!("d6:lengthi{}e4:pathl", data.len()).into_bytes());
+        for part in path.split('/') {
+            raw.extend(format!("{}:{}", part.len(), part).into_bytes());
+        }
+        raw.extend(b"ee");
+    }
+    raw.extend(
+        format!(
+            "e4:name4:mock12:piece lengthi{}e6:pieces{}:",
+            plength,
+            pieces.len() * 20
+        )
+        .into_bytes(),
+    );
+    for piece in &pieces {
+        raw.extend(Sha1::digest(piece));
+    }
+    raw.extend(b"ee");
+    let torrent = Torrent::from_bytes(&raw).expect("Mock torrent should parse");
+    (torrent, pieces)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::ClientBuilder,
+        peer::{Peer, PeerOptions},
+    };
+
+    #[tokio::test]
+    async fn downloads_one_piece_over_a_duplex_stream() {
+        let (torrent, pieces) = torrent(&[42u8; 1 << 15], 1 << 15);
+        let info_hash = torrent.info_hash().unwrap();
+        let stream = MockPeer::new(info_hash, pieces.clone()).duplex();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut peer =
+            Peer::new_from_stream(addr, stream, &info_hash, &[false], &PeerOptions::default())
+                .await
+                .unwrap();
+        assert_eq!(peer.pieces, vec![0]);
+        let piece = peer.download_piece(0, 1 << 15).await.unwrap();
+        assert_eq!(piece, pieces[0]);
+    }
+
+    #[tokio::test]
+    async fn client_downloads_one_piece_end_to_end() {
+        let content: Vec<u8> = (0..20_000u32).map(|n| n as u8).collect();
+        let (torrent, pieces) = torrent(&content, 1 << 15);
+        let addr = MockPeer::new(torrent.info_hash().unwrap(), pieces)
+            .spawn()
+            .await
+            .unwrap();
+        let mut client = ClientBuilder::new(&torrent)
+            .peers([addr])
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.download_to_vec().await.unwrap(), content);
+    }
+}
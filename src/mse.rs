@@ -0,0 +1,483 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{bail, Context as _};
+use bignum::BigUint;
+use rand::Rng;
+use rc4::Rc4;
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// 768 bit safe prime shared by every MSE implementation, the generator is 2
+const PRIME: [u8; 96] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 0x01;
+const CRYPTO_RC4: u32 = 0x02;
+const MAX_PAD: usize = 512;
+
+/// What the receiving side picked out of the methods we offered
+#[derive(Debug)]
+pub enum Negotiated<S> {
+    Plaintext(S),
+    Rc4(Box<MseStream<S>>),
+}
+
+/// Runs the initiating side of the Message Stream Encryption handshake,
+/// the BitTorrent handshake is then sent over whatever stream comes out of it
+pub async fn handshake<S>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    allow_plaintext: bool,
+) -> anyhow::Result<Negotiated<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let prime = BigUint::from_be_bytes(&PRIME);
+    let private = {
+        let mut rng = rand::thread_rng();
+        let bytes: [u8; 20] = rng.gen();
+        BigUint::from_be_bytes(&bytes)
+    };
+    let public = BigUint::from_be_bytes(&[2]).modpow(&private, &prime);
+
+    let mut out = public.to_be_bytes(96);
+    let pad_len = rand::thread_rng().gen_range(0..=MAX_PAD);
+    out.extend((0..pad_len).map(|_| rand::random::<u8>()));
+    stream.write_all(&out).await?;
+
+    let mut remote_public = [0u8; 96];
+    stream
+        .read_exact(&mut remote_public)
+        .await
+        .context("Read remote public key")?;
+    let secret = BigUint::from_be_bytes(&remote_public)
+        .modpow(&private, &prime)
+        .to_be_bytes(96);
+
+    let mut encryptor = Rc4::new(&hash(&[b"keyA", &secret, info_hash]));
+    let mut decryptor = Rc4::new(&hash(&[b"keyB", &secret, info_hash]));
+    encryptor.discard(1024);
+    decryptor.discard(1024);
+
+    let provide = if allow_plaintext {
+        CRYPTO_RC4 | CRYPTO_PLAINTEXT
+    } else {
+        CRYPTO_RC4
+    };
+    let mut out = Vec::with_capacity(40 + 16);
+    out.extend(hash(&[b"req1", &secret]));
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    out.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    let mut payload = Vec::with_capacity(16);
+    payload.extend(VC);
+    payload.extend(provide.to_be_bytes());
+    // No padding and no initial payload, the BitTorrent handshake follows separately
+    payload.extend(0u16.to_be_bytes());
+    payload.extend(0u16.to_be_bytes());
+    encryptor.apply(&mut payload);
+    out.extend(payload);
+    stream.write_all(&out).await?;
+
+    // The remote pads its public key with up to 512 random bytes, so scan for the encrypted VC
+    let mut expected_vc = VC;
+    decryptor.clone().apply(&mut expected_vc);
+    let mut window = [0u8; 8];
+    stream.read_exact(&mut window).await?;
+    let mut skipped = 0;
+    while window != expected_vc {
+        if skipped == MAX_PAD {
+            bail!("MSE verification constant not found");
+        }
+        window.rotate_left(1);
+        window[7] = stream.read_u8().await?;
+        skipped += 1;
+    }
+    decryptor.discard(VC.len());
+
+    let mut select = [0u8; 6];
+    stream.read_exact(&mut select).await?;
+    decryptor.apply(&mut select);
+    let selected = u32::from_be_bytes(select[..4].try_into()?);
+    let pad_len = u16::from_be_bytes(select[4..].try_into()?) as usize;
+    if pad_len > MAX_PAD {
+        bail!("MSE padding too long : {}", pad_len);
+    }
+    let mut pad = vec![0u8; pad_len];
+    stream.read_exact(&mut pad).await?;
+    decryptor.apply(&mut pad);
+
+    match selected {
+        CRYPTO_RC4 => Ok(Negotiated::Rc4(Box::new(MseStream {
+            inner: stream,
+            encryptor,
+            decryptor,
+            pending: Vec::new(),
+        }))),
+        CRYPTO_PLAINTEXT if allow_plaintext => Ok(Negotiated::Plaintext(stream)),
+        _ => bail!("Peer selected an unsupported crypto method : {}", selected),
+    }
+}
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// A stream whose traffic is RC4 encrypted in both directions
+#[derive(Debug)]
+pub struct MseStream<S> {
+    inner: S,
+    encryptor: Rc4,
+    decryptor: Rc4,
+    // Encrypted bytes accepted from the caller but not yet written to `inner`
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncWrite + Unpin> MseStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written =
+                std::task::ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        std::task::ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.decryptor.apply(&mut buf.filled_mut()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_drain(cx))?;
+        let mut encrypted = buf.to_vec();
+        this.encryptor.apply(&mut encrypted);
+        this.pending = encrypted;
+        // The keystream already advanced, so the bytes count as written even if still pending
+        let _ = this.poll_drain(cx)?;
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        std::task::ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+mod rc4 {
+    #[derive(Debug, Clone)]
+    pub struct Rc4 {
+        state: [u8; 256],
+        i: u8,
+        j: u8,
+    }
+
+    impl Rc4 {
+        pub fn new(key: &[u8]) -> Self {
+            let mut state = [0u8; 256];
+            for (idx, byte) in state.iter_mut().enumerate() {
+                *byte = idx as u8;
+            }
+            let mut j: u8 = 0;
+            for i in 0..256 {
+                j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+                state.swap(i, j as usize);
+            }
+            Self { state, i: 0, j: 0 }
+        }
+        pub fn apply(&mut self, data: &mut [u8]) {
+            for byte in data {
+                self.i = self.i.wrapping_add(1);
+                self.j = self.j.wrapping_add(self.state[self.i as usize]);
+                self.state.swap(self.i as usize, self.j as usize);
+                let idx = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+                *byte ^= self.state[idx as usize];
+            }
+        }
+        pub fn discard(&mut self, count: usize) {
+            let mut sink = vec![0u8; count];
+            self.apply(&mut sink);
+        }
+    }
+}
+
+mod bignum {
+    use std::cmp::Ordering;
+
+    // Just enough unsigned arithmetic for the Diffie-Hellman exchange, limbs are little endian
+    #[derive(Debug, Clone)]
+    pub struct BigUint(Vec<u32>);
+
+    impl BigUint {
+        pub fn from_be_bytes(bytes: &[u8]) -> Self {
+            let mut limbs: Vec<u32> = bytes
+                .rchunks(4)
+                .map(|chunk| {
+                    let mut limb = [0u8; 4];
+                    limb[4 - chunk.len()..].copy_from_slice(chunk);
+                    u32::from_be_bytes(limb)
+                })
+                .collect();
+            trim(&mut limbs);
+            Self(limbs)
+        }
+        pub fn to_be_bytes(&self, len: usize) -> Vec<u8> {
+            let mut bytes: Vec<u8> = self.0.iter().rev().flat_map(|l| l.to_be_bytes()).collect();
+            let leading = bytes.iter().take_while(|b| **b == 0).count();
+            bytes.drain(..leading);
+            let mut out = vec![0u8; len.saturating_sub(bytes.len())];
+            out.extend(bytes);
+            out
+        }
+        fn bits(&self) -> usize {
+            match self.0.last() {
+                Some(last) => self.0.len() * 32 - last.leading_zeros() as usize,
+                None => 0,
+            }
+        }
+        fn bit(&self, idx: usize) -> bool {
+            self.0
+                .get(idx / 32)
+                .is_some_and(|limb| (limb >> (idx % 32)) & 1 == 1)
+        }
+        fn mul(&self, other: &Self) -> Self {
+            let mut out = vec![0u32; self.0.len() + other.0.len()];
+            for (i, &a) in self.0.iter().enumerate() {
+                let mut carry = 0u64;
+                for (j, &b) in other.0.iter().enumerate() {
+                    let cur = out[i + j] as u64 + a as u64 * b as u64 + carry;
+                    out[i + j] = cur as u32;
+                    carry = cur >> 32;
+                }
+                out[i + other.0.len()] = carry as u32;
+            }
+            trim(&mut out);
+            Self(out)
+        }
+        // Bitwise long division, slow but only ever run a few hundred times per handshake
+        fn rem(&self, modulus: &Self) -> Self {
+            let mut rem = Self(Vec::new());
+            for idx in (0..self.bits()).rev() {
+                rem.shl1(self.bit(idx));
+                if rem.cmp(modulus) != Ordering::Less {
+                    rem.sub(modulus);
+                }
+            }
+            rem
+        }
+        pub fn modpow(&self, exp: &Self, modulus: &Self) -> Self {
+            let mut result = Self(vec![1]).rem(modulus);
+            let base = self.rem(modulus);
+            for idx in (0..exp.bits()).rev() {
+                result = result.mul(&result).rem(modulus);
+                if exp.bit(idx) {
+                    result = result.mul(&base).rem(modulus);
+                }
+            }
+            result
+        }
+        fn shl1(&mut self, low_bit: bool) {
+            let mut carry = low_bit as u32;
+            for limb in self.0.iter_mut() {
+                let next = *limb >> 31;
+                *limb = (*limb << 1) | carry;
+                carry = next;
+            }
+            if carry != 0 {
+                self.0.push(carry);
+            }
+        }
+        fn sub(&mut self, other: &Self) {
+            let mut borrow = 0i64;
+            for (idx, limb) in self.0.iter_mut().enumerate() {
+                let rhs = other.0.get(idx).copied().unwrap_or(0) as i64;
+                let mut cur = *limb as i64 - rhs - borrow;
+                borrow = 0;
+                if cur < 0 {
+                    cur += 1 << 32;
+                    borrow = 1;
+                }
+                *limb = cur as u32;
+            }
+            trim(&mut self.0);
+        }
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0
+                .len()
+                .cmp(&other.0.len())
+                .then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+        }
+    }
+
+    fn trim(limbs: &mut Vec<u32>) {
+        while limbs.last() == Some(&0) {
+            limbs.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rc4_known_answers() {
+        let cases: [(&[u8], &[u8], &str); 3] = [
+            (b"Key", b"Plaintext", "bbf316e8d940af0ad3"),
+            (b"Wiki", b"pedia", "1021bf0420"),
+            (b"Secret", b"Attack at dawn", "45a01f645fc35b383552544b9bf5"),
+        ];
+        for (key, plaintext, expected) in cases {
+            let mut data = plaintext.to_vec();
+            Rc4::new(key).apply(&mut data);
+            assert_eq!(hex::encode(&data), expected);
+        }
+    }
+
+    #[test]
+    fn modpow_known_answers() {
+        let small = |n: u64| BigUint::from_be_bytes(&n.to_be_bytes());
+        let result = small(4).modpow(&small(13), &small(497));
+        assert_eq!(result.to_be_bytes(8), 445u64.to_be_bytes());
+        let result = small(3).modpow(&small(12345), &small(1_000_000_007));
+        assert_eq!(result.to_be_bytes(8), 964_676_307u64.to_be_bytes());
+
+        let private = hex::decode("0123456789abcdef0123456789abcdef01234567").unwrap();
+        let public = BigUint::from_be_bytes(&[2]).modpow(
+            &BigUint::from_be_bytes(&private),
+            &BigUint::from_be_bytes(&PRIME),
+        );
+        assert_eq!(
+            hex::encode(public.to_be_bytes(96)),
+            "6fd4bc7aa649593205ec30348a3ccc737b61fa01e9e1762c2c53eb69033afecbdf7c13b8ac3643af78d07\
+             60b0f42db009f2b96c970f009d060faf617f117d0f1c221cea0561b9a86e852fc70a6f09ad0f82378603\
+             aa5e56b811deb3f534bf276"
+        );
+    }
+
+    // The receiving side of the handshake, written from the spec rather than from `handshake`
+    async fn receive(
+        mut stream: tokio::io::DuplexStream,
+        info_hash: [u8; 20],
+        select: u32,
+    ) -> anyhow::Result<(tokio::io::DuplexStream, Rc4, Rc4)> {
+        let prime = BigUint::from_be_bytes(&PRIME);
+        let private = BigUint::from_be_bytes(&[7; 20]);
+        let public = BigUint::from_be_bytes(&[2]).modpow(&private, &prime);
+        stream.write_all(&public.to_be_bytes(96)).await?;
+
+        let mut remote_public = [0u8; 96];
+        stream.read_exact(&mut remote_public).await?;
+        let secret = BigUint::from_be_bytes(&remote_public)
+            .modpow(&private, &prime)
+            .to_be_bytes(96);
+        // The initiator's padding runs until HASH('req1', S)
+        let req1 = hash(&[b"req1", &secret]);
+        let mut window = [0u8; 20];
+        stream.read_exact(&mut window).await?;
+        while window != req1 {
+            window.rotate_left(1);
+            window[19] = stream.read_u8().await?;
+        }
+        let mut skey = [0u8; 20];
+        stream.read_exact(&mut skey).await?;
+        let req3 = hash(&[b"req3", &secret]);
+        let req2: Vec<u8> = skey.iter().zip(req3).map(|(a, b)| a ^ b).collect();
+        assert_eq!(req2, hash(&[b"req2", &info_hash]));
+
+        let mut decryptor = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+        let mut encryptor = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+        decryptor.discard(1024);
+        encryptor.discard(1024);
+        let mut header = [0u8; 14];
+        stream.read_exact(&mut header).await?;
+        decryptor.apply(&mut header);
+        assert_eq!(header[..8], VC);
+        let provide = u32::from_be_bytes(header[8..12].try_into()?);
+        assert_ne!(provide & select, 0);
+        let pad_len = u16::from_be_bytes(header[12..].try_into()?) as usize;
+        let mut rest = vec![0u8; pad_len + 2];
+        stream.read_exact(&mut rest).await?;
+        decryptor.apply(&mut rest);
+
+        let mut reply = VC.to_vec();
+        reply.extend(select.to_be_bytes());
+        reply.extend(3u16.to_be_bytes());
+        reply.extend([0xaa; 3]);
+        encryptor.apply(&mut reply);
+        stream.write_all(&reply).await?;
+        Ok((stream, encryptor, decryptor))
+    }
+
+    #[tokio::test]
+    async fn handshake_negotiates_rc4_with_a_receiver() {
+        let info_hash = [9u8; 20];
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let receiver = tokio::spawn(receive(theirs, info_hash, CRYPTO_RC4));
+        let Negotiated::Rc4(mut stream) = handshake(ours, &info_hash, true).await.unwrap() else {
+            panic!("Expected RC4 to be selected");
+        };
+        let (mut theirs, mut encryptor, mut decryptor) = receiver.await.unwrap().unwrap();
+
+        stream.write_all(b"ping").await.unwrap();
+        stream.flush().await.unwrap();
+        let mut received = [0u8; 4];
+        theirs.read_exact(&mut received).await.unwrap();
+        decryptor.apply(&mut received);
+        assert_eq!(&received, b"ping");
+
+        let mut pong = b"pong".to_vec();
+        encryptor.apply(&mut pong);
+        theirs.write_all(&pong).await.unwrap();
+        let mut received = [0u8; 4];
+        stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(&received, b"pong");
+    }
+
+    #[tokio::test]
+    async fn handshake_falls_back_to_plaintext_when_selected() {
+        let info_hash = [9u8; 20];
+        let (ours, theirs) = tokio::io::duplex(4096);
+        let receiver = tokio::spawn(receive(theirs, info_hash, CRYPTO_PLAINTEXT));
+        let negotiated = handshake(ours, &info_hash, true).await.unwrap();
+        receiver.await.unwrap().unwrap();
+        assert!(matches!(negotiated, Negotiated::Plaintext(_)));
+    }
+}
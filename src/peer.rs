@@ -1,9 +1,45 @@
-use std::net::SocketAddrV4;
+use std::{
+    collections::HashMap,
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
 
-use message::{Message, MessageTag};
-use rand::Rng;
+use anyhow::{anyhow, bail};
+use futures_util::{FutureExt, SinkExt, StreamExt};
+use message::{Message, MessageCodec, MessageTag};
 use response::{Request, Response};
-use tokio::{io::AsyncWriteExt, net::TcpStream};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    net::{TcpSocket, TcpStream},
+    sync::OwnedSemaphorePermit,
+    time::Instant,
+};
+use tokio_util::codec::Framed;
+
+use crate::{
+    extension::{self, ExtendedHandshake},
+    mse::{self, MseStream, Negotiated},
+    rate::RateLimiter,
+};
+
+// How long a new peer gets to send its first message before it's kept without a bitfield
+const FIRST_MESSAGE_WAIT: Duration = Duration::from_secs(5);
+// Bounds of the per peer request pipeline, every connection starts at the initial depth
+const MIN_PIPELINE_DEPTH: usize = 1;
+const INITIAL_PIPELINE_DEPTH: usize = 5;
+const MAX_PIPELINE_DEPTH: usize = 64;
+// How long a choking peer gets to unchoke us before the piece is asked of another one
+const UNCHOKE_TIMEOUT: Duration = Duration::from_secs(5);
+// How long a peer may sit on our requests before the window shrinks
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+// Stalls within one piece before the peer is given up on for it
+const MAX_STALLS: usize = 3;
+// Larger requests are ignored rather than served, as most clients do
+const MAX_SERVED_BLOCK: usize = 1 << 17;
 
 pub struct HandShake<'a> {
     pub length: u8,
@@ -18,7 +54,8 @@ impl<'a> HandShake<'a> {
         Self {
             length: 19,
             bittorrent: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            // Extension protocol (BEP 10) and fast extension (BEP 6)
+            reserved: [0, 0, 0, 0, 0, 0x10, 0, 0x04],
             info_hash,
             peer_id,
         }
@@ -34,41 +71,542 @@ impl<'a> HandShake<'a> {
     }
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct PeerOptions {
+    // Local address outbound connections are bound to, OS picks one when unset
+    pub bind_addr: Option<SocketAddr>,
+    // Try the MSE obfuscated handshake first, falling back to plaintext
+    pub prefer_encryption: bool,
+    // SOCKS5 proxy every peer connection is tunnelled through
+    pub socks5_proxy: Option<SocketAddr>,
+    // Initial capacity of the buffer incoming messages are framed from
+    pub read_buffer: Option<usize>,
+    // SO_RCVBUF and SO_SNDBUF of the socket, the OS picks them when unset
+    pub socket_recv_buffer: Option<u32>,
+    pub socket_send_buffer: Option<u32>,
+    // Raw info dict, served to peers that ask for it over ut_metadata
+    pub metadata: Option<Arc<Vec<u8>>>,
+    // Caps what we send to peers, shared by every connection
+    pub upload_limit: Option<Arc<RateLimiter>>,
+    // Verified pieces we answer peers' requests from, nothing is uploaded when unset
+    pub serve_pieces: Option<SharedPieces>,
+    // Peers that send nothing within this after the handshake are dropped, instead of kept
+    // after the default wait. It also bounds the handshake itself, MSE included
+    pub bitfield_timeout: Option<Duration>,
+    // How long to wait on requested blocks before shrinking the pipeline and asking again
+    pub block_timeout: Option<Duration>,
+    // How long to wait for a choking peer to unchoke us before giving up on it for now
+    pub unchoke_timeout: Option<Duration>,
+}
+
+// Pieces by index, filled in by the client as they verify and read by every connection
+pub type SharedPieces = Arc<std::sync::RwLock<HashMap<usize, Arc<Vec<u8>>>>>;
+
+// Any connected byte stream a peer can talk over, an accepted socket or an in-memory pipe
+pub trait PeerIo: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> PeerIo for T {}
+
+pub enum PeerStream {
+    Plain(TcpStream),
+    Encrypted(Box<MseStream<TcpStream>>),
+    // Handed over already connected, see `Peer::new_from_stream`
+    Other(Box<dyn PeerIo>),
+}
+
+impl fmt::Debug for PeerStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerStream::Plain(stream) => f.debug_tuple("Plain").field(stream).finish(),
+            PeerStream::Encrypted(stream) => f.debug_tuple("Encrypted").field(stream).finish(),
+            PeerStream::Other(_) => f.write_str("Other"),
+        }
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Encrypted(stream) => Pin::new(stream).poll_read(cx, buf),
+            PeerStream::Other(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Encrypted(stream) => Pin::new(stream).poll_write(cx, buf),
+            PeerStream::Other(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Encrypted(stream) => Pin::new(stream).poll_flush(cx),
+            PeerStream::Other(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            PeerStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Encrypted(stream) => Pin::new(stream).poll_shutdown(cx),
+            PeerStream::Other(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Peer {
-    pub addr: SocketAddrV4,
-    pub stream: TcpStream,
-    pub sent_interested: bool,
+    pub addr: SocketAddr,
+    stream: Framed<PeerStream, MessageCodec>,
+    // The four flags of the BitTorrent connection state, every connection starts out choked
+    // and not interested on both ends
+    am_interested: bool,
+    am_choking: bool,
+    peer_choking: bool,
+    peer_interested: bool,
     pub pieces: Vec<i32>,
+    // Pieces in the torrent, no index in `pieces` is ever past it
+    piece_count: usize,
+    // Pieces we may request even while choked
+    pub allowed_fast: Vec<i32>,
+    // Pieces the peer would prefer us to download first
+    pub suggested: Vec<i32>,
+    // Reserved bytes of the peer's handshake, flagging which extensions it speaks
+    reserved: [u8; 8],
+    // What the peer advertised in its extended handshake, once it arrived
+    extensions: Option<ExtendedHandshake>,
+    sent_extended: bool,
+    metadata: Option<Arc<Vec<u8>>>,
+    upload_limit: Option<Arc<RateLimiter>>,
+    serve_pieces: Option<SharedPieces>,
+    block_timeout: Duration,
+    unchoke_timeout: Duration,
+    downloaded: usize,
+    // Requests kept in flight, grows while the peer keeps up and halves when it stalls
+    pipeline_depth: usize,
+    // Blocks answered since the window last grew
+    answered: usize,
+    // Piece, offset and length of every block requested and not answered yet
+    pending: Vec<(usize, usize, usize)>,
+    // Piece index and offset of blocks we cancelled, they may still be on the wire. Each is
+    // forgotten once it arrives or once a later piece completes
+    cancelled: Vec<(usize, usize)>,
+    connected_at: Instant,
+    // Share of a session wide connection budget, given back when the peer is dropped
+    pub(crate) budget: Option<OwnedSemaphorePermit>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStatus {
+    pub addr: SocketAddr,
+    pub choked: bool,
+    pub interested: bool,
+    pub pieces_owned: usize,
+    // Average bytes per second since the connection was established
+    pub download_rate: f64,
 }
 
 impl Peer {
-    pub async fn new(addr: SocketAddrV4, info_hash: &[u8; 20]) -> anyhow::Result<Peer> {
-        let mut stream = TcpStream::connect(addr).await?;
-        let mut rng = rand::thread_rng();
-        let peer_id: [u8; 20] = rng.gen();
+    pub async fn new(
+        addr: SocketAddr,
+        info_hash: &[u8; 20],
+        have: &[bool],
+        options: &PeerOptions,
+    ) -> anyhow::Result<Peer> {
+        let stream = Self::connect(addr, options).await?;
+        let stream = if options.prefer_encryption {
+            let wait = options.bitfield_timeout.unwrap_or(FIRST_MESSAGE_WAIT);
+            match tokio::time::timeout(wait, mse::handshake(stream, info_hash, true)).await {
+                Ok(Ok(Negotiated::Rc4(stream))) => PeerStream::Encrypted(stream),
+                Ok(Ok(Negotiated::Plaintext(stream))) => PeerStream::Plain(stream),
+                // Peers that don't speak MSE drop the connection, so start over in plaintext
+                Ok(Err(_)) => PeerStream::Plain(Self::connect(addr, options).await?),
+                Err(_) => bail!(
+                    "peer {} didn't answer the encryption handshake within {:?}",
+                    addr,
+                    wait
+                ),
+            }
+        } else {
+            PeerStream::Plain(stream)
+        };
+        Self::start(addr, stream, info_hash, have, options).await
+    }
+    /// Runs the handshake and bitfield exchange over a connection that's already established,
+    /// `addr` only identifies the peer. Encryption isn't negotiated on it
+    pub async fn new_from_stream<S>(
+        addr: SocketAddr,
+        stream: S,
+        info_hash: &[u8; 20],
+        have: &[bool],
+        options: &PeerOptions,
+    ) -> anyhow::Result<Peer>
+    where
+        S: PeerIo + 'static,
+    {
+        let stream = PeerStream::Other(Box::new(stream));
+        Self::start(addr, stream, info_hash, have, options).await
+    }
+    // Everything after the connection is up, the same whichever way it was made
+    async fn start(
+        addr: SocketAddr,
+        mut stream: PeerStream,
+        info_hash: &[u8; 20],
+        have: &[bool],
+        options: &PeerOptions,
+    ) -> anyhow::Result<Peer> {
+        let piece_count = have.len();
+        let wait = options.bitfield_timeout.unwrap_or(FIRST_MESSAGE_WAIT);
+        let peer_id: [u8; 20] = rand::random();
         let handshake = HandShake::new(info_hash, &peer_id);
-        stream.write_all(&handshake.to_bytes()).await?;
-
-        // Decode only the Bitfield message
-        let message = Message::decode(&mut stream, MessageTag::Bitfield).await?;
-        let mut pieces = Vec::new();
-        let mut piece_count = 0;
-        for chunk in message.payload {
-            let bin = format!("{:b}", chunk);
-            for c in bin.chars() {
-                if c == '1' {
-                    pieces.push(piece_count);
-                }
-                piece_count += 1;
+        // A peer that accepts the connection and stays silent would otherwise hold us forever
+        let exchange = async {
+            stream.write_all(&handshake.to_bytes()).await?;
+            let mut reply = [0u8; 68];
+            stream.read_exact(&mut reply).await?;
+            anyhow::Ok(reply)
+        };
+        let reply = tokio::time::timeout(wait, exchange).await.map_err(|_| {
+            anyhow!(
+                "peer {} didn't answer the handshake within {:?}",
+                addr,
+                wait
+            )
+        })??;
+        if &reply[28..48] != info_hash {
+            bail!("Peer answered the handshake for another torrent");
+        }
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&reply[20..28]);
+        // Room for a full block message, so a piece arrives without regrowing the buffer
+        let capacity = options.read_buffer.unwrap_or(32 * 1024);
+        let mut stream = Framed::with_capacity(stream, MessageCodec, capacity);
+        // A bitfield may only follow the handshake, and is left out when we have nothing unless
+        // both sides speak the fast extension, which requires HaveNone in its place
+        if have.contains(&true) {
+            stream
+                .send(Message::new(MessageTag::Bitfield, to_bitfield(have)))
+                .await?;
+        } else if reserved[7] & 0x04 != 0 {
+            stream
+                .send(Message::new(MessageTag::HaveNone, Vec::new()))
+                .await?;
+        }
+
+        // Only worth it with something to offer, we don't use any other extension yet
+        let mut sent_extended = false;
+        if reserved[5] & 0x10 != 0 {
+            if let Some(metadata) = &options.metadata {
+                send_extended_handshake(&mut stream, Some(metadata.len())).await?;
+                sent_extended = true;
             }
         }
-        Ok(Self {
+
+        let mut peer = Self {
             addr,
             stream,
-            sent_interested: false,
-            pieces,
-        })
+            am_interested: false,
+            am_choking: true,
+            peer_choking: true,
+            peer_interested: false,
+            pieces: Vec::new(),
+            piece_count,
+            allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            reserved,
+            extensions: None,
+            sent_extended,
+            metadata: options.metadata.clone(),
+            upload_limit: options.upload_limit.clone(),
+            serve_pieces: options.serve_pieces.clone(),
+            block_timeout: options.block_timeout.unwrap_or(BLOCK_TIMEOUT),
+            unchoke_timeout: options.unchoke_timeout.unwrap_or(UNCHOKE_TIMEOUT),
+            downloaded: 0,
+            pipeline_depth: INITIAL_PIPELINE_DEPTH,
+            answered: 0,
+            pending: Vec::new(),
+            cancelled: Vec::new(),
+            connected_at: Instant::now(),
+            budget: None,
+        };
+        // The bitfield is optional, a peer with nothing may skip it or go straight to other
+        // messages, or stay silent until it has something
+        let first = tokio::time::timeout(wait, next_message(&mut peer.stream));
+        let Ok(message) = first.await else {
+            if options.bitfield_timeout.is_some() {
+                bail!(
+                    "peer {} sent nothing within {:?} of the handshake",
+                    addr,
+                    wait
+                );
+            }
+            return Ok(peer);
+        };
+        let message = message?;
+        match message.tag {
+            MessageTag::Bitfield => peer.pieces = from_bitfield(&message.payload, piece_count)?,
+            MessageTag::HaveAll => peer.pieces.extend(0..piece_count as i32),
+            MessageTag::HaveNone => {}
+            _ => peer.handle(&message).await?,
+        }
+        Ok(peer)
+    }
+
+    pub async fn send_have(&mut self, index: u32) -> anyhow::Result<()> {
+        self.stream
+            .send(Message::new(MessageTag::Have, index.to_be_bytes().to_vec()))
+            .await
+    }
+    /// Size of the info dict the peer advertised in its extended handshake, sending ours first
+    /// and waiting for the peer's if needed. `None` when it left the size out, the metadata
+    /// then has to be requested piece by piece until the peer rejects one
+    pub async fn request_metadata_size(&mut self) -> anyhow::Result<Option<usize>> {
+        if !self.supports_extensions() {
+            bail!("peer {} doesn't speak the extension protocol", self.addr);
+        }
+        if !self.sent_extended {
+            send_extended_handshake(&mut self.stream, None).await?;
+            self.sent_extended = true;
+        }
+        let handshake = async {
+            while self.extensions.is_none() {
+                let message = next_message(&mut self.stream).await?;
+                self.handle(&message).await?;
+            }
+            anyhow::Ok(())
+        };
+        tokio::time::timeout(FIRST_MESSAGE_WAIT, handshake)
+            .await
+            .map_err(|_| anyhow!("peer {} never sent its extended handshake", self.addr))??;
+        Ok(self
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.metadata_size))
+    }
+    /// Keeps an idle connection from timing out, the codec only frames messages with a tag
+    pub async fn send_keep_alive(&mut self) -> anyhow::Result<()> {
+        self.stream.get_mut().write_all(&[0; 4]).await?;
+        Ok(())
+    }
+    /// Handles whatever the peer sent while we weren't downloading from it, without waiting for
+    /// more. A peer that skipped its bitfield tells us what it has this way
+    pub async fn poll_idle(&mut self) -> anyhow::Result<()> {
+        while let Some(message) = next_message(&mut self.stream).now_or_never() {
+            self.handle(&message?).await?;
+        }
+        Ok(())
+    }
+    /// Waits for the peer's next message and handles it, a request is answered from the served
+    /// pieces
+    pub async fn serve_next(&mut self) -> anyhow::Result<()> {
+        let message = next_message(&mut self.stream).await?;
+        self.handle(&message).await
+    }
+    pub async fn shutdown(&mut self) -> anyhow::Result<()> {
+        self.stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+    pub fn is_choked(&self) -> bool {
+        self.peer_choking
+    }
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+    /// How many block requests are currently kept in flight to this peer
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+    /// The raw reserved bytes the peer sent in its handshake
+    pub fn reserved(&self) -> [u8; 8] {
+        self.reserved
+    }
+    /// BEP 5, the peer runs a DHT node
+    pub fn supports_dht(&self) -> bool {
+        self.reserved[7] & 0x01 != 0
+    }
+    /// BEP 6, the peer understands the fast extension messages
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+    /// BEP 10, the peer speaks the extension protocol
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+    /// Tells the peer whether we want anything from it, only when that changes
+    pub async fn set_interested(&mut self, interested: bool) -> anyhow::Result<()> {
+        if self.am_interested == interested {
+            return Ok(());
+        }
+        let tag = if interested {
+            MessageTag::Interested
+        } else {
+            MessageTag::NotInterested
+        };
+        self.stream.send(Message::new(tag, Vec::new())).await?;
+        self.am_interested = interested;
+        Ok(())
+    }
+    async fn handle_extended(&mut self, payload: &[u8]) -> anyhow::Result<()> {
+        let Some((&id, body)) = payload.split_first() else {
+            bail!("Empty extended message");
+        };
+        match id {
+            extension::HANDSHAKE_ID => self.extensions = Some(serde_bencode::from_bytes(body)?),
+            extension::UT_METADATA_ID => {
+                // Replies go out on the id the peer picked, not ours
+                let (Some(metadata), Some(reply_id)) = (
+                    &self.metadata,
+                    self.extensions
+                        .as_ref()
+                        .and_then(|ext| ext.id("ut_metadata")),
+                ) else {
+                    return Ok(());
+                };
+                let Some(answer) = extension::serve_metadata(metadata, body) else {
+                    return Ok(());
+                };
+                let mut reply = vec![reply_id];
+                reply.extend(answer);
+                if let Some(limit) = &self.upload_limit {
+                    limit.acquire(reply.len()).await;
+                }
+                self.stream
+                    .send(Message::new(MessageTag::Extended, reply))
+                    .await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    // Blocks of a piece may be requested while we're interested and either unchoked or the
+    // piece is allowed fast
+    fn can_request(&self, piece_idx: usize) -> bool {
+        self.am_interested
+            && (!self.peer_choking || self.allowed_fast.contains(&(piece_idx as i32)))
+    }
+    // Keeps the connection state and what the peer has in sync with every message it sends
+    async fn handle(&mut self, message: &Message) -> anyhow::Result<()> {
+        match message.tag {
+            MessageTag::Extended => self.handle_extended(&message.payload).await?,
+            MessageTag::Choke => self.peer_choking = true,
+            MessageTag::Unchoke => self.peer_choking = false,
+            MessageTag::Interested => {
+                self.peer_interested = true;
+                // With something to serve, every interested peer is unchoked
+                if self.serve_pieces.is_some() && self.am_choking {
+                    self.stream
+                        .send(Message::new(MessageTag::Unchoke, Vec::new()))
+                        .await?;
+                    self.am_choking = false;
+                }
+            }
+            MessageTag::NotInterested => self.peer_interested = false,
+            MessageTag::Request if !self.am_choking => self.serve_block(message).await?,
+            MessageTag::Have => {
+                let idx = Message::piece_index(message)?;
+                // An index past the last piece can only be a bug on the peer's side
+                if (idx as usize) < self.piece_count {
+                    add_piece(&mut self.pieces, idx)
+                }
+            }
+            MessageTag::AllowedFast => {
+                add_piece(&mut self.allowed_fast, Message::piece_index(message)?)
+            }
+            MessageTag::SuggestPiece => {
+                add_piece(&mut self.suggested, Message::piece_index(message)?)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    // Answers a request with the block, once the upload limit lets it through. Requests for
+    // pieces we don't have or past their end are ignored
+    async fn serve_block(&mut self, message: &Message) -> anyhow::Result<()> {
+        let request = Request::decode(message)?;
+        let (idx, offset, length) = (
+            request.piece_idx() as usize,
+            request.block_offset() as usize,
+            request.block_length() as usize,
+        );
+        if length > MAX_SERVED_BLOCK {
+            return Ok(());
+        }
+        let block = self.serve_pieces.as_ref().and_then(|pieces| {
+            let pieces = pieces.read().unwrap();
+            let block = pieces.get(&idx)?.get(offset..offset.checked_add(length)?)?;
+            Some(block.to_vec())
+        });
+        let Some(block) = block else {
+            return Ok(());
+        };
+        let mut payload = Vec::with_capacity(8 + block.len());
+        payload.extend((idx as u32).to_be_bytes());
+        payload.extend((offset as u32).to_be_bytes());
+        payload.extend(block);
+        if let Some(limit) = &self.upload_limit {
+            limit.acquire(payload.len()).await;
+        }
+        self.stream
+            .send(Message::new(MessageTag::Piece, payload))
+            .await
+    }
+    pub fn download_rate(&self) -> f64 {
+        let elapsed = self.connected_at.elapsed().as_secs_f64();
+        if elapsed == 0.0 {
+            return 0.0;
+        }
+        self.downloaded as f64 / elapsed
+    }
+    pub fn status(&self) -> PeerStatus {
+        PeerStatus {
+            addr: self.addr,
+            choked: self.peer_choking,
+            interested: self.am_interested,
+            pieces_owned: self.pieces.len(),
+            download_rate: self.download_rate(),
+        }
+    }
+
+    async fn connect(addr: SocketAddr, options: &PeerOptions) -> anyhow::Result<TcpStream> {
+        let target = options.socks5_proxy.unwrap_or(addr);
+        let socket = if target.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        if let Some(bind_addr) = options.bind_addr {
+            socket.bind(bind_addr)?;
+        }
+        // Before connecting, the receive window scale is negotiated from it in the handshake
+        if let Some(size) = options.socket_recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = options.socket_send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        let mut stream = socket.connect(target).await?;
+        if options.socks5_proxy.is_some() {
+            socks5_connect(&mut stream, addr).await?;
+        }
+        Ok(stream)
     }
 
     pub async fn download_piece(
@@ -76,50 +614,335 @@ impl Peer {
         piece_idx: usize,
         plength: usize,
     ) -> anyhow::Result<Vec<u8>> {
+        self.set_interested(true).await?;
+        if !self.can_request(piece_idx) {
+            let unchoke_timeout = self.unchoke_timeout;
+            let unchoke = async {
+                while !self.can_request(piece_idx) {
+                    let message = next_message(&mut self.stream).await?;
+                    self.handle(&message).await?;
+                }
+                anyhow::Ok(())
+            };
+            tokio::time::timeout(unchoke_timeout, unchoke)
+                .await
+                .map_err(|_| PeerChoking(self.addr))??;
+        }
+
+        // Left over from a download that was dropped halfway, e.g. one that lost an endgame race
+        self.cancel_pending().await;
+        let result = self.fetch_blocks(piece_idx, plength).await;
+        // Whatever is still requested when we give up would only waste the peer's upload
+        if result.is_err() {
+            self.cancel_pending().await;
+        }
+        result
+    }
+    /// Sends a Cancel for every block still requested, e.g. once another peer delivered the
+    /// same piece first
+    pub async fn cancel_pending(&mut self) {
+        for (piece_idx, offset, length) in std::mem::take(&mut self.pending) {
+            self.cancelled.push((piece_idx, offset));
+            let cancel = Request::new(piece_idx as u32, offset as u32, length as u32);
+            let message = Message::new(MessageTag::Cancel, cancel.encode());
+            if self.stream.send(message).await.is_err() {
+                break;
+            }
+        }
+    }
+    // Requests every block of the piece, keeping a few requests in flight so the peer never
+    // waits on our round trip
+    async fn fetch_blocks(&mut self, piece_idx: usize, plength: usize) -> anyhow::Result<Vec<u8>> {
         const BLOCK_SIZE: usize = 1 << 14;
-        let mut stream = &mut self.stream;
+        const MAX_MISMATCHES: usize = 3;
         let mut downloaded_piece = vec![0u8; plength];
         let mut bytes_downloaded = 0;
-
-        if !self.sent_interested {
-            Message::encode(&mut stream, MessageTag::Interested, &[]).await?;
-            Message::decode(&mut stream, MessageTag::Unchoke).await?;
-            self.sent_interested = true;
-        }
-
+        let mut out_of_order = HashMap::new();
+        let mut mismatches = 0;
+        let mut next_offset = 0;
+        // Blocks whose requests stalled, asked for again before any new one
+        let mut retry: Vec<(usize, usize)> = Vec::new();
+        let mut stalls = 0;
+        let block_timeout = self.block_timeout;
         while bytes_downloaded < plength {
-            let block_offset = bytes_downloaded;
-            let block_length = (plength - bytes_downloaded).min(BLOCK_SIZE);
-
-            // Create a request for the next block
-            let request = Request::new(piece_idx as u32, block_offset as u32, block_length as u32);
-            let payload = request.encode();
-
-            Message::encode(&mut stream, MessageTag::Request, &payload).await?;
+            while self.pending.len() < self.pipeline_depth {
+                let (offset, block_length) = match retry.pop() {
+                    Some(block) => block,
+                    None if next_offset < plength => {
+                        let block_length = (plength - next_offset).min(BLOCK_SIZE);
+                        next_offset += block_length;
+                        (next_offset - block_length, block_length)
+                    }
+                    None => break,
+                };
+                self.request_block(piece_idx, offset, block_length).await?;
+            }
 
-            let message = Message::decode(&mut stream, MessageTag::Piece).await?;
-            let response = Response::decode(&message)?;
+            let response = async {
+                loop {
+                    let message = next_message(&mut self.stream).await?;
+                    self.handle(&message).await?;
+                    match message.tag {
+                        MessageTag::Piece => break anyhow::Ok(Response::decode(&message)?),
+                        // We must not wait on a rejected block, let another peer serve the piece
+                        MessageTag::RejectRequest
+                            if Message::piece_index(&message)? as usize == piece_idx =>
+                        {
+                            bail!(
+                                "peer {} rejected a request for piece {}",
+                                self.addr,
+                                piece_idx
+                            );
+                        }
+                        // Choking drops our pending requests unless the piece is allowed fast
+                        MessageTag::Choke if !self.can_request(piece_idx) => {
+                            bail!("peer {} choked us", self.addr);
+                        }
+                        _ => {}
+                    }
+                }
+            };
+            // A peer that sits on our requests gets fewer of them at a time, one that keeps
+            // doing it is as bad as a refusal
+            let Ok(response) = tokio::time::timeout(block_timeout, response).await else {
+                self.pipeline_depth = (self.pipeline_depth / 2).max(MIN_PIPELINE_DEPTH);
+                self.answered = 0;
+                stalls += 1;
+                if stalls > MAX_STALLS {
+                    bail!(
+                        "peer {} ignored requests for piece {}",
+                        self.addr,
+                        piece_idx
+                    );
+                }
+                // Lowest offset popped first
+                let mut stalled: Vec<(usize, usize)> = self
+                    .pending
+                    .iter()
+                    .map(|(_, offset, length)| (*offset, *length))
+                    .collect();
+                stalled.sort_unstable_by(|a, b| b.cmp(a));
+                self.cancel_pending().await;
+                retry.extend(stalled);
+                continue;
+            };
+            let response = response?;
             let data = response.data;
-            if response.idx as usize == piece_idx && response.offset as usize == block_offset {
-                downloaded_piece[block_offset..block_offset + data.len()].copy_from_slice(&data);
-                bytes_downloaded += data.len();
+            let offset = response.offset as usize;
+            if response.idx as usize != piece_idx {
+                // Sent before our Cancel reached the peer
+                if self.take_cancelled(response.idx as usize, offset) {
+                    continue;
+                }
+                mismatches += 1;
+                if mismatches >= MAX_MISMATCHES {
+                    bail!(
+                        "peer {} kept sending blocks for the wrong piece (wanted {}, got {})",
+                        self.addr,
+                        piece_idx,
+                        response.idx
+                    );
+                }
+                continue;
+            }
+            // Only blocks we asked for are taken, which also keeps the peer's offset in bounds
+            let Some(pos) = self
+                .pending
+                .iter()
+                .position(|(_, start, _)| *start == offset)
+            else {
+                self.take_cancelled(piece_idx, offset);
+                continue;
+            };
+            let requested = self.pending[pos].2;
+            if data.is_empty() || data.len() > requested {
+                continue;
+            }
+            self.pending.swap_remove(pos);
+            // Like a congestion window, one more request in flight per full window answered
+            self.answered += 1;
+            if self.answered >= self.pipeline_depth {
+                self.answered = 0;
+                self.pipeline_depth = (self.pipeline_depth + 1).min(MAX_PIPELINE_DEPTH);
+            }
+            downloaded_piece[offset..offset + data.len()].copy_from_slice(&data);
+            self.downloaded += data.len();
+            // A short block leaves a tail nobody asked for anymore, request it on its own
+            if data.len() < requested {
+                self.request_block(piece_idx, offset + data.len(), requested - data.len())
+                    .await?;
+            }
+            // Blocks answered out of order are kept until the gap before them is filled
+            out_of_order.insert(offset, data.len());
+            while let Some(len) = out_of_order.remove(&bytes_downloaded) {
+                bytes_downloaded += len;
             }
         }
+        // The other pieces were cancelled before this one was requested, the peer answered it so
+        // it saw those Cancels and nothing of theirs is still coming
+        self.cancelled.retain(|(idx, _)| *idx == piece_idx);
 
         Ok(downloaded_piece)
     }
+    // Forgets a cancelled block that arrived anyway, returns whether it was one
+    fn take_cancelled(&mut self, piece_idx: usize, offset: usize) -> bool {
+        let Some(pos) = self
+            .cancelled
+            .iter()
+            .position(|block| *block == (piece_idx, offset))
+        else {
+            return false;
+        };
+        self.cancelled.swap_remove(pos);
+        true
+    }
+    async fn request_block(
+        &mut self,
+        piece_idx: usize,
+        offset: usize,
+        length: usize,
+    ) -> anyhow::Result<()> {
+        let request = Request::new(piece_idx as u32, offset as u32, length as u32);
+        self.stream
+            .send(Message::new(MessageTag::Request, request.encode()))
+            .await?;
+        self.pending.push((piece_idx, offset, length));
+        Ok(())
+    }
 }
 
-pub mod message {
-    use std::time::Duration;
+// Asks a SOCKS5 proxy without authentication to open a tunnel to `addr` (RFC 1928)
+async fn socks5_connect(stream: &mut TcpStream, addr: SocketAddr) -> anyhow::Result<()> {
+    stream.write_all(&[0x05, 0x01, 0x00]).await?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply).await?;
+    if reply != [0x05, 0x00] {
+        bail!("SOCKS5 proxy refused unauthenticated access");
+    }
 
-    use anyhow::bail;
-    use tokio::{
-        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-        time::Instant,
+    let mut request = vec![0x05, 0x01, 0x00];
+    match addr {
+        SocketAddr::V4(addr) => {
+            request.push(0x01);
+            request.extend(addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(0x04);
+            request.extend(addr.ip().octets());
+        }
+    }
+    request.extend(addr.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0x00 {
+        bail!("SOCKS5 proxy couldn't reach {} (code {})", addr, reply[1]);
+    }
+    // Skip the address the proxy bound for us
+    let bound_len = match reply[3] {
+        0x01 => 4,
+        0x04 => 16,
+        0x03 => stream.read_u8().await? as usize,
+        other => bail!("Unknown SOCKS5 address type : {}", other),
     };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(())
+}
+
+// The peer closed the connection cleanly between two messages
+#[derive(Debug)]
+pub struct PeerDisconnected;
+
+impl fmt::Display for PeerDisconnected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Peer closed the connection")
+    }
+}
+
+impl std::error::Error for PeerDisconnected {}
+
+// The peer kept us choked, the connection is still fine and it may unchoke us later
+#[derive(Debug)]
+pub struct PeerChoking(pub SocketAddr);
+
+impl fmt::Display for PeerChoking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "peer {} never unchoked us", self.0)
+    }
+}
+
+impl std::error::Error for PeerChoking {}
+
+async fn next_message(stream: &mut Framed<PeerStream, MessageCodec>) -> anyhow::Result<Message> {
+    match stream.next().await {
+        Some(message) => message,
+        None => Err(PeerDisconnected.into()),
+    }
+}
+
+// Our BEP 10 handshake, advertising the metadata size only when we have the info dict to serve
+async fn send_extended_handshake(
+    stream: &mut Framed<PeerStream, MessageCodec>,
+    metadata_size: Option<usize>,
+) -> anyhow::Result<()> {
+    let mut payload = vec![extension::HANDSHAKE_ID];
+    payload.extend(serde_bencode::to_bytes(&ExtendedHandshake::ours(
+        metadata_size,
+    ))?);
+    stream
+        .send(Message::new(MessageTag::Extended, payload))
+        .await
+}
 
-    #[derive(Debug, PartialEq, Eq)]
+// Pieces are listed once however often they're announced, so availability counts a peer once
+fn add_piece(pieces: &mut Vec<i32>, idx: i32) {
+    if !pieces.contains(&idx) {
+        pieces.push(idx);
+    }
+}
+
+/// Indices of the pieces set in a peer's bitfield, which must be exactly long enough for
+/// `piece_count` pieces with the spare bits at the end cleared
+pub fn from_bitfield(bitfield: &[u8], piece_count: usize) -> anyhow::Result<Vec<i32>> {
+    if bitfield.len() != piece_count.div_ceil(8) {
+        bail!(
+            "Bitfield has {} bytes, expected {} for {} pieces",
+            bitfield.len(),
+            piece_count.div_ceil(8),
+            piece_count
+        );
+    }
+    let pieces: Vec<i32> = (0..bitfield.len() * 8)
+        .filter(|idx| bitfield[idx / 8] & (0x80 >> (idx % 8)) != 0)
+        .map(|idx| idx as i32)
+        .collect();
+    if pieces
+        .last()
+        .is_some_and(|idx| *idx as usize >= piece_count)
+    {
+        bail!("Bitfield has spare bits set past piece {}", piece_count);
+    }
+    Ok(pieces)
+}
+
+/// Packs piece flags into a bitfield, piece 0 is the high bit of the first byte
+pub fn to_bitfield(have: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; have.len().div_ceil(8)];
+    for (idx, _) in have.iter().enumerate().filter(|(_, have)| **have) {
+        bitfield[idx / 8] |= 0x80 >> (idx % 8);
+    }
+    bitfield
+}
+
+pub mod message {
+    use anyhow::bail;
+    use bytes::{Buf, BufMut, BytesMut};
+    use tokio::io::{AsyncWrite, AsyncWriteExt};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum MessageTag {
         Choke = 0,
         Unchoke = 1,
@@ -130,6 +953,14 @@ pub mod message {
         Request = 6,
         Piece = 7,
         Cancel = 8,
+        // Fast extension
+        SuggestPiece = 13,
+        HaveAll = 14,
+        HaveNone = 15,
+        RejectRequest = 16,
+        AllowedFast = 17,
+        // Extension protocol
+        Extended = 20,
     }
     impl MessageTag {
         pub fn from(idx: usize) -> anyhow::Result<Self> {
@@ -143,6 +974,12 @@ pub mod message {
                 6 => Ok(Self::Request),
                 7 => Ok(Self::Piece),
                 8 => Ok(Self::Cancel),
+                13 => Ok(Self::SuggestPiece),
+                14 => Ok(Self::HaveAll),
+                15 => Ok(Self::HaveNone),
+                16 => Ok(Self::RejectRequest),
+                17 => Ok(Self::AllowedFast),
+                20 => Ok(Self::Extended),
                 _ => anyhow::bail!("Not available"),
             }
         }
@@ -153,6 +990,9 @@ pub mod message {
         pub payload: Vec<u8>,
     }
     impl Message {
+        pub fn new(tag: MessageTag, payload: Vec<u8>) -> Self {
+            Self { tag, payload }
+        }
         pub async fn encode<W>(w: &mut W, tag: MessageTag, payload: &[u8]) -> anyhow::Result<()>
         where
             W: AsyncWrite + Unpin,
@@ -165,30 +1005,71 @@ pub mod message {
 
             w.write_all(payload).await?;
 
+            w.flush().await?;
+
             Ok(())
         }
-        pub async fn decode<R>(stream: &mut R, tag: MessageTag) -> anyhow::Result<Self>
-        where
-            R: AsyncRead + Unpin,
-        {
-            let tick = Instant::now();
+        // Have, Suggest, Reject and AllowedFast all start with the piece index
+        pub fn piece_index(message: &Message) -> anyhow::Result<i32> {
+            let Some(idx) = message.payload.get(0..4) else {
+                bail!("{:?} message is too short", message.tag);
+            };
+            Ok(u32::from_be_bytes(idx.try_into()?) as i32)
+        }
+    }
+
+    // Frames are a 4 byte length followed by the tag and payload, zero length frames are
+    // keep-alives and are skipped like unknown tags
+    #[derive(Debug, Default)]
+    pub struct MessageCodec;
+
+    // Well above a 16 KiB block and the bitfield of any realistic torrent
+    const MAX_LENGTH: usize = 1 << 20;
+
+    impl Decoder for MessageCodec {
+        type Item = Message;
+        type Error = anyhow::Error;
+        fn decode(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Message>> {
             loop {
-                if tick.elapsed() > Duration::from_secs(5) {
-                    break;
+                let Some(length) = src.get(..4) else {
+                    return Ok(None);
+                };
+                let length = u32::from_be_bytes(length.try_into()?) as usize;
+                if length > MAX_LENGTH {
+                    bail!("Message of {} bytes is too long", length);
+                }
+                if src.len() < 4 + length {
+                    src.reserve(4 + length - src.len());
+                    return Ok(None);
                 }
-                let length = stream.read_u32().await?;
-                if length == 0 || length > 18000 {
+                src.advance(4);
+                let frame = src.split_to(length);
+                if length == 0 {
                     continue;
                 }
-
-                let mut buffer = vec![0u8; length as usize];
-                stream.read_exact(&mut buffer).await?;
-                if let Ok(tag) = MessageTag::from(buffer[0].into()) {
-                    let payload = buffer[1..].to_vec();
-                    return Ok(Self { tag, payload });
+                if let Ok(tag) = MessageTag::from(frame[0].into()) {
+                    return Ok(Some(Message::new(tag, frame[1..].to_vec())));
                 }
             }
-            bail!("Failed to receive message of tag : {:?}", tag)
+        }
+        // A clean close ends the stream, leftover bytes mean a message was cut short
+        fn decode_eof(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Message>> {
+            match self.decode(src)? {
+                Some(message) => Ok(Some(message)),
+                None if src.is_empty() => Ok(None),
+                None => bail!("Connection closed {} bytes into a message", src.len()),
+            }
+        }
+    }
+
+    impl Encoder<Message> for MessageCodec {
+        type Error = anyhow::Error;
+        fn encode(&mut self, message: Message, dst: &mut BytesMut) -> anyhow::Result<()> {
+            dst.reserve(5 + message.payload.len());
+            dst.put_u32(message.payload.len() as u32 + 1);
+            dst.put_u8(message.tag as u8);
+            dst.put_slice(&message.payload);
+            Ok(())
         }
     }
 }
@@ -208,6 +1089,26 @@ pub mod response {
                 block_length,
             }
         }
+        pub fn decode(message: &Message) -> anyhow::Result<Self> {
+            let Some(fields) = message
+                .payload
+                .get(..12)
+                .filter(|_| message.payload.len() == 12)
+            else {
+                anyhow::bail!("Request message is {} bytes, not 12", message.payload.len());
+            };
+            let field = |at: usize| u32::from_be_bytes(fields[at..at + 4].try_into().unwrap());
+            Ok(Self::new(field(0), field(4), field(8)))
+        }
+        pub fn piece_idx(&self) -> u32 {
+            self.piece_idx
+        }
+        pub fn block_offset(&self) -> u32 {
+            self.block_offset
+        }
+        pub fn block_length(&self) -> u32 {
+            self.block_length
+        }
         pub fn encode(&self) -> Vec<u8> {
             let mut buffer = Vec::with_capacity(12);
             buffer.extend_from_slice(&(self.piece_idx).to_be_bytes());
@@ -223,6 +1124,12 @@ pub mod response {
     }
     impl Response {
         pub fn decode(message: &Message) -> anyhow::Result<Self> {
+            if message.payload.len() < 8 {
+                anyhow::bail!(
+                    "Piece message is {} bytes, shorter than its 8 byte header",
+                    message.payload.len()
+                );
+            }
             let idx = u32::from_be_bytes(message.payload[0..4].try_into()?);
             let offset = u32::from_be_bytes(message.payload[4..8].try_into()?);
             let data = message.payload[8..].to_vec();
@@ -230,3 +1137,744 @@ pub mod response {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::MockPeer;
+    use bytes::BytesMut;
+    use tokio::{io::DuplexStream, net::TcpListener};
+    use tokio_util::codec::{Decoder, Encoder};
+
+    const INFO_HASH: [u8; 20] = [5; 20];
+
+    type Remote = Framed<DuplexStream, MessageCodec>;
+
+    // Connects a peer to a scripted remote that echoes our handshake, so it speaks the same
+    // extensions we do, and then sends `first`
+    async fn connect(have: &[bool], first: Message) -> (Peer, Remote) {
+        connect_with(have, first, PeerOptions::default()).await
+    }
+
+    async fn connect_with(have: &[bool], first: Message, options: PeerOptions) -> (Peer, Remote) {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 20);
+        let remote = async move {
+            let mut handshake = [0u8; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            theirs.write_all(&handshake).await.unwrap();
+            let mut remote = Framed::new(theirs, MessageCodec);
+            remote.send(first).await.unwrap();
+            remote
+        };
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let peer = Peer::new_from_stream(addr, ours, &INFO_HASH, have, &options);
+        let (peer, remote) = tokio::join!(peer, remote);
+        (peer.unwrap(), remote)
+    }
+
+    fn have_all(piece_count: usize) -> Message {
+        Message::new(MessageTag::Bitfield, to_bitfield(&vec![true; piece_count]))
+    }
+
+    // Skips whatever else the peer sends until a message with `tag`
+    async fn expect(remote: &mut Remote, tag: MessageTag) -> Message {
+        loop {
+            let message = remote.next().await.unwrap().unwrap();
+            if message.tag == tag {
+                return message;
+            }
+        }
+    }
+
+    fn piece_message(idx: u32, offset: u32, data: &[u8]) -> Message {
+        let mut payload = idx.to_be_bytes().to_vec();
+        payload.extend(offset.to_be_bytes());
+        payload.extend(data);
+        Message::new(MessageTag::Piece, payload)
+    }
+
+    // Piece index, offset and length of a Request
+    fn decode_request(message: &Message) -> (u32, u32, u32) {
+        let field = |at: usize| u32::from_be_bytes(message.payload[at..at + 4].try_into().unwrap());
+        (field(0), field(4), field(8))
+    }
+
+    #[tokio::test]
+    async fn sends_have_none_to_fast_peers_when_empty() {
+        let (peer, mut remote) =
+            connect(&[false; 4], Message::new(MessageTag::HaveAll, vec![])).await;
+        let first = remote.next().await.unwrap().unwrap();
+        assert_eq!(first.tag, MessageTag::HaveNone);
+        assert_eq!(peer.pieces, vec![0, 1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn sends_our_bitfield_right_after_the_handshake() {
+        let have = [
+            true, false, true, false, false, false, false, false, false, true,
+        ];
+        let (_peer, mut remote) = connect(&have, have_all(10)).await;
+        let first = remote.next().await.unwrap().unwrap();
+        assert_eq!(first.tag, MessageTag::Bitfield);
+        assert_eq!(first.payload, vec![0b1010_0000, 0b0100_0000]);
+    }
+
+    #[tokio::test]
+    async fn send_have_writes_the_big_endian_index() {
+        let (mut peer, mut remote) = connect(&[false; 2], have_all(2)).await;
+        peer.send_have(0x0102_0304).await.unwrap();
+        let have = expect(&mut remote, MessageTag::Have).await;
+        assert_eq!(have.payload, vec![1, 2, 3, 4]);
+        let mut frame = BytesMut::new();
+        MessageCodec.encode(have, &mut frame).unwrap();
+        assert_eq!(&frame[..], &[0, 0, 0, 5, 4, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn serves_requested_blocks_once_the_peer_is_unchoked() {
+        const BLOCK: usize = 1 << 14;
+        let piece: Vec<u8> = (0..2 * BLOCK).map(|n| n as u8).collect();
+        let pieces = SharedPieces::default();
+        pieces.write().unwrap().insert(0, Arc::new(piece.clone()));
+        let options = PeerOptions {
+            serve_pieces: Some(pieces),
+            upload_limit: Some(Arc::new(RateLimiter::new(1 << 20))),
+            ..PeerOptions::default()
+        };
+        let (mut peer, mut remote) = connect_with(&[true], have_all(1), options).await;
+        let request = |offset: usize| {
+            let request = Request::new(0, offset as u32, BLOCK as u32);
+            Message::new(MessageTag::Request, request.encode())
+        };
+        // Still choked, the request goes unanswered
+        assert!(peer.am_choking());
+        peer.handle(&request(0)).await.unwrap();
+
+        peer.handle(&Message::new(MessageTag::Interested, vec![]))
+            .await
+            .unwrap();
+        assert!(!peer.am_choking());
+        assert!(peer.peer_interested());
+        expect(&mut remote, MessageTag::Unchoke).await;
+
+        peer.handle(&request(BLOCK)).await.unwrap();
+        let block = remote.next().await.unwrap().unwrap();
+        assert_eq!(block.tag, MessageTag::Piece);
+        let block = Response::decode(&block).unwrap();
+        assert_eq!((block.idx, block.offset), (0, BLOCK as u32));
+        assert_eq!(block.data, piece[BLOCK..]);
+    }
+
+    #[tokio::test]
+    async fn served_blocks_are_held_to_the_upload_limit() {
+        const BLOCK: usize = 1 << 14;
+        let pieces = SharedPieces::default();
+        pieces
+            .write()
+            .unwrap()
+            .insert(0, Arc::new(vec![7; 2 * BLOCK]));
+        // A block a second, the first is covered by the full bucket
+        let options = PeerOptions {
+            serve_pieces: Some(pieces),
+            upload_limit: Some(Arc::new(RateLimiter::new(BLOCK as u64))),
+            ..PeerOptions::default()
+        };
+        let (mut peer, mut remote) = connect_with(&[true], have_all(1), options).await;
+        peer.handle(&Message::new(MessageTag::Interested, vec![]))
+            .await
+            .unwrap();
+        expect(&mut remote, MessageTag::Unchoke).await;
+        let started = Instant::now();
+        for offset in [0, BLOCK] {
+            let request = Request::new(0, offset as u32, BLOCK as u32);
+            peer.handle(&Message::new(MessageTag::Request, request.encode()))
+                .await
+                .unwrap();
+            expect(&mut remote, MessageTag::Piece).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn a_stalling_peer_gets_a_smaller_window_and_is_kept() {
+        let piece: Vec<u8> = (0..2 << 14).map(|n| (n % 251) as u8).collect();
+        // Each block takes longer than we wait, so both of them stall once
+        let stream = MockPeer::new(INFO_HASH, vec![piece.clone()])
+            .delay(Duration::from_millis(200))
+            .duplex();
+        let options = PeerOptions {
+            block_timeout: Some(Duration::from_millis(120)),
+            ..PeerOptions::default()
+        };
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut peer = Peer::new_from_stream(addr, stream, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap();
+        assert_eq!(peer.pipeline_depth(), INITIAL_PIPELINE_DEPTH);
+        assert_eq!(peer.download_piece(0, piece.len()).await.unwrap(), piece);
+        // Halved on each stall, the block answered last may grow it by one again
+        assert!(peer.pipeline_depth() <= 2, "{}", peer.pipeline_depth());
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_keeps_up_gets_a_deeper_window() {
+        const PLENGTH: usize = 8 << 14;
+        let pieces: Vec<Vec<u8>> = (0..4u8).map(|n| vec![n; PLENGTH]).collect();
+        // Slow enough for a round trip to matter, never slow enough to stall
+        let stream = MockPeer::new(INFO_HASH, pieces.clone())
+            .delay(Duration::from_millis(1))
+            .duplex();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut peer = Peer::new_from_stream(
+            addr,
+            stream,
+            &INFO_HASH,
+            &[false; 4],
+            &PeerOptions::default(),
+        )
+        .await
+        .unwrap();
+        let mut depth = peer.pipeline_depth();
+        assert_eq!(depth, INITIAL_PIPELINE_DEPTH);
+        for (idx, piece) in pieces.iter().enumerate() {
+            assert_eq!(peer.download_piece(idx, PLENGTH).await.unwrap(), *piece);
+            assert!(peer.pipeline_depth() >= depth, "{}", peer.pipeline_depth());
+            depth = peer.pipeline_depth();
+        }
+        // 32 blocks answered in full windows of 5, 6, 7 and 8
+        assert!(depth > INITIAL_PIPELINE_DEPTH + 3, "{}", depth);
+    }
+
+    #[tokio::test]
+    async fn reserved_bits_of_the_handshake_are_decoded() {
+        let cases = [
+            ([0, 0, 0, 0, 0, 0, 0, 0x01], (true, false, false)),
+            ([0, 0, 0, 0, 0, 0x10, 0, 0x04], (false, true, true)),
+            ([0xff, 0, 0, 0, 0, 0xef, 0xff, 0xfa], (false, false, false)),
+            ([0, 0, 0, 0, 0, 0x10, 0, 0x05], (true, true, true)),
+        ];
+        for (reserved, expected) in cases {
+            let stream = MockPeer::new(INFO_HASH, vec![vec![1u8; 100]])
+                .reserved(reserved)
+                .duplex();
+            let addr = "127.0.0.1:1".parse().unwrap();
+            let peer =
+                Peer::new_from_stream(addr, stream, &INFO_HASH, &[false], &PeerOptions::default())
+                    .await
+                    .unwrap();
+            assert_eq!(peer.reserved(), reserved);
+            let decoded = (
+                peer.supports_dht(),
+                peer.supports_fast(),
+                peer.supports_extensions(),
+            );
+            assert_eq!(decoded, expected, "{:02x?}", reserved);
+        }
+    }
+
+    #[tokio::test]
+    async fn choke_and_interest_follow_the_messages() {
+        let (mut peer, mut remote) = connect(&[false], have_all(1)).await;
+        assert!(peer.is_choked() && peer.am_choking());
+        assert!(!peer.am_interested() && !peer.peer_interested());
+
+        peer.set_interested(true).await.unwrap();
+        expect(&mut remote, MessageTag::Interested).await;
+        assert!(peer.am_interested());
+        peer.set_interested(false).await.unwrap();
+        expect(&mut remote, MessageTag::NotInterested).await;
+        assert!(!peer.am_interested());
+
+        let message = |tag| Message::new(tag, vec![]);
+        peer.handle(&message(MessageTag::Unchoke)).await.unwrap();
+        assert!(!peer.is_choked());
+        peer.handle(&message(MessageTag::Choke)).await.unwrap();
+        assert!(peer.is_choked());
+        peer.handle(&message(MessageTag::Interested)).await.unwrap();
+        assert!(peer.peer_interested());
+        // Nothing to serve, so the interested peer stays choked
+        assert!(peer.am_choking());
+        peer.handle(&message(MessageTag::NotInterested))
+            .await
+            .unwrap();
+        assert!(!peer.peer_interested());
+    }
+
+    #[tokio::test]
+    async fn a_peer_that_never_unchokes_gets_no_requests() {
+        let piece = vec![3u8; 1 << 14];
+        let stream = MockPeer::new(INFO_HASH, vec![piece.clone()])
+            .choke(true)
+            .duplex();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let mut peer =
+            Peer::new_from_stream(addr, stream, &INFO_HASH, &[false], &PeerOptions::default())
+                .await
+                .unwrap();
+        let download = peer.download_piece(0, piece.len());
+        assert!(tokio::time::timeout(Duration::from_millis(300), download)
+            .await
+            .is_err());
+        assert!(peer.am_interested());
+        assert!(peer.is_choked());
+        assert!(peer.pending.is_empty());
+    }
+
+    #[test]
+    fn a_piece_message_shorter_than_its_header_is_an_error() {
+        let short = Message::new(MessageTag::Piece, vec![0, 0, 1]);
+        assert!(response::Response::decode(&short).is_err());
+        let empty = response::Response::decode(&piece_message(1, 2, &[])).unwrap();
+        assert_eq!((empty.idx, empty.offset, empty.data.len()), (1, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn metadata_size_is_read_from_the_extended_handshake() {
+        let extended = |body: &[u8]| {
+            let mut payload = vec![extension::HANDSHAKE_ID];
+            payload.extend(body);
+            Message::new(MessageTag::Extended, payload)
+        };
+        let (mut peer, mut remote) = connect(&[false], have_all(1)).await;
+        let answer = tokio::spawn(async move {
+            // Ours goes out first, theirs only comes after it
+            expect(&mut remote, MessageTag::Extended).await;
+            let body = b"d1:md11:ut_metadatai3ee13:metadata_sizei31235ee";
+            remote.send(extended(body)).await.unwrap();
+            remote
+        });
+        assert_eq!(peer.request_metadata_size().await.unwrap(), Some(31235));
+        answer.await.unwrap();
+
+        // Without the size the metadata has to be fetched until the peer rejects a piece
+        let (mut peer, _remote) = connect(&[false], extended(b"d1:md11:ut_metadatai3eee")).await;
+        assert_eq!(peer.request_metadata_size().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn outgoing_connections_come_from_the_bind_address() {
+        // A port nothing else holds, so the connection can only have come from it
+        let bind_addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mock = MockPeer::new(INFO_HASH, vec![vec![1; 16]]);
+        let accept = tokio::spawn(async move {
+            let (mut stream, from) = listener.accept().await.unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut stream, &mut mock.duplex()).await;
+            from
+        });
+        let options = PeerOptions {
+            bind_addr: Some(bind_addr),
+            ..PeerOptions::default()
+        };
+        let peer = Peer::new(addr, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap();
+        drop(peer);
+        assert_eq!(accept.await.unwrap(), bind_addr);
+    }
+
+    #[tokio::test]
+    async fn connects_through_a_socks5_proxy_to_the_peer() {
+        let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy_addr = proxy.local_addr().unwrap();
+        let target: SocketAddr = "10.1.2.3:6881".parse().unwrap();
+        let mock = MockPeer::new(INFO_HASH, vec![vec![1; 16]]);
+        let stub = tokio::spawn(async move {
+            let (mut client, _) = proxy.accept().await.unwrap();
+            let mut greeting = [0u8; 3];
+            client.read_exact(&mut greeting).await.unwrap();
+            assert_eq!(greeting, [0x05, 0x01, 0x00]);
+            client.write_all(&[0x05, 0x00]).await.unwrap();
+            let mut connect = [0u8; 10];
+            client.read_exact(&mut connect).await.unwrap();
+            // CONNECT to an IPv4 address, then the address and port
+            assert_eq!(connect[..4], [0x05, 0x01, 0x00, 0x01]);
+            assert_eq!(connect[4..8], [10, 1, 2, 3]);
+            assert_eq!(u16::from_be_bytes([connect[8], connect[9]]), 6881);
+            client
+                .write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+            let _ = tokio::io::copy_bidirectional(&mut client, &mut mock.duplex()).await;
+        });
+        let options = PeerOptions {
+            socks5_proxy: Some(proxy_addr),
+            ..PeerOptions::default()
+        };
+        let peer = Peer::new(target, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap();
+        assert_eq!(peer.addr, target);
+        assert_eq!(peer.pieces, vec![0]);
+        drop(peer);
+        stub.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn configured_socket_buffers_are_applied() {
+        // Away from the usual defaults in opposite directions, 128K to receive and 16K to send
+        const RECV: u32 = 8 * 1024;
+        const SEND: u32 = 64 * 1024;
+        let addr = MockPeer::new(INFO_HASH, vec![vec![1; 16]])
+            .spawn()
+            .await
+            .unwrap();
+        let options = PeerOptions {
+            socket_recv_buffer: Some(RECV),
+            socket_send_buffer: Some(SEND),
+            ..PeerOptions::default()
+        };
+        let peer = Peer::new(addr, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap();
+        let PeerStream::Plain(stream) = peer.stream.get_ref() else {
+            panic!("Expected a plain connection");
+        };
+        let socket = socket2::SockRef::from(stream);
+        // The OS may round them, Linux doubles them
+        let sizes = [
+            (socket.recv_buffer_size().unwrap(), RECV),
+            (socket.send_buffer_size().unwrap(), SEND),
+        ];
+        for (size, asked) in sizes {
+            let size = size as u32;
+            assert!(
+                (asked..=2 * asked).contains(&size),
+                "{} for {}",
+                size,
+                asked
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn decodes_and_handles_fast_messages() {
+        let (mut peer, _remote) = connect(&[false; 4], have_all(4)).await;
+        // Reject carries a whole request, the others just the piece index
+        let frames = [
+            (MessageTag::SuggestPiece, vec![0x0d, 0, 0, 0, 2]),
+            (MessageTag::AllowedFast, vec![0x11, 0, 0, 0, 2]),
+            (
+                MessageTag::RejectRequest,
+                vec![0x10, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0x40, 0],
+            ),
+        ];
+        for (tag, frame) in frames {
+            let mut raw = BytesMut::from(&(frame.len() as u32).to_be_bytes()[..]);
+            raw.extend_from_slice(&frame);
+            let message = MessageCodec.decode(&mut raw).unwrap().unwrap();
+            assert_eq!(message.tag, tag);
+            assert_eq!(Message::piece_index(&message).unwrap(), 2);
+            peer.handle(&message).await.unwrap();
+        }
+        assert_eq!(peer.suggested, vec![2]);
+        assert_eq!(peer.allowed_fast, vec![2]);
+    }
+
+    #[tokio::test]
+    async fn reject_mid_download_fails_the_piece() {
+        let (mut peer, mut remote) = connect(&[false; 1], have_all(1)).await;
+        tokio::spawn(async move {
+            expect(&mut remote, MessageTag::Interested).await;
+            remote
+                .send(Message::new(MessageTag::Unchoke, vec![]))
+                .await
+                .unwrap();
+            let request = expect(&mut remote, MessageTag::Request).await;
+            remote
+                .send(Message::new(MessageTag::RejectRequest, request.payload))
+                .await
+                .unwrap();
+            // Keep the connection open so only the reject can end the download
+            while remote.next().await.is_some() {}
+        });
+        let err = peer.download_piece(0, 1 << 15).await.unwrap_err();
+        assert!(err.to_string().contains("rejected"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn persistent_wrong_index_blocks_fail_the_piece() {
+        let (mut peer, mut remote) = connect(&[false; 2], have_all(2)).await;
+        tokio::spawn(async move {
+            expect(&mut remote, MessageTag::Interested).await;
+            remote
+                .send(Message::new(MessageTag::Unchoke, vec![]))
+                .await
+                .unwrap();
+            loop {
+                let request = expect(&mut remote, MessageTag::Request).await;
+                let (_, offset, length) = decode_request(&request);
+                let block = vec![0u8; length as usize];
+                remote.send(piece_message(1, offset, &block)).await.unwrap();
+            }
+        });
+        // Four blocks, so more wrong answers arrive than the mismatch limit
+        let err = peer.download_piece(0, 1 << 16).await.unwrap_err();
+        assert!(err.to_string().contains("wrong piece"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn cancelled_blocks_are_forgotten_once_drained_or_superseded() {
+        const BLOCK: usize = 1 << 14;
+        let (mut peer, mut remote) = connect(&[false; 3], have_all(3)).await;
+        // Cancelled halfway, the peer had already sent two of them
+        peer.request_block(0, 0, BLOCK).await.unwrap();
+        peer.request_block(1, 0, BLOCK).await.unwrap();
+        peer.request_block(1, BLOCK, BLOCK).await.unwrap();
+        peer.cancel_pending().await;
+        assert_eq!(peer.cancelled, vec![(0, 0), (1, 0), (1, BLOCK)]);
+        tokio::spawn(async move {
+            expect(&mut remote, MessageTag::Interested).await;
+            remote
+                .send(Message::new(MessageTag::Unchoke, vec![]))
+                .await
+                .unwrap();
+            for (idx, offset) in [(0, 0), (1, BLOCK as u32)] {
+                let block = [idx as u8; BLOCK];
+                remote
+                    .send(piece_message(idx, offset, &block))
+                    .await
+                    .unwrap();
+            }
+            loop {
+                let (idx, offset, length) =
+                    decode_request(&expect(&mut remote, MessageTag::Request).await);
+                let block = vec![idx as u8; length as usize];
+                remote
+                    .send(piece_message(idx, offset, &block))
+                    .await
+                    .unwrap();
+            }
+        });
+        assert_eq!(peer.download_piece(1, BLOCK).await.unwrap(), vec![1; BLOCK]);
+        // Both stray blocks were drained, the cancelled block of the piece may still come
+        assert_eq!(peer.cancelled, vec![(1, 0)]);
+        // Not once a later piece completed
+        assert_eq!(peer.download_piece(2, BLOCK).await.unwrap(), vec![2; BLOCK]);
+        assert!(peer.cancelled.is_empty(), "{:?}", peer.cancelled);
+    }
+
+    #[tokio::test]
+    async fn out_of_order_blocks_land_at_their_offset() {
+        let content: Vec<u8> = (0..1 << 15).map(|n: u32| (n / 7) as u8).collect();
+        let (mut peer, mut remote) = connect(&[false; 1], have_all(1)).await;
+        let served = content.clone();
+        tokio::spawn(async move {
+            expect(&mut remote, MessageTag::Interested).await;
+            remote
+                .send(Message::new(MessageTag::Unchoke, vec![]))
+                .await
+                .unwrap();
+            let first = decode_request(&expect(&mut remote, MessageTag::Request).await);
+            let second = decode_request(&expect(&mut remote, MessageTag::Request).await);
+            // Answer the later block first
+            for (_, offset, length) in [second, first] {
+                let block = &served[offset as usize..(offset + length) as usize];
+                remote.send(piece_message(0, offset, block)).await.unwrap();
+            }
+            while remote.next().await.is_some() {}
+        });
+        let piece = peer.download_piece(0, 1 << 15).await.unwrap();
+        assert_eq!(piece, content);
+    }
+
+    #[test]
+    fn codec_round_trips_messages_and_skips_keep_alives() {
+        let mut raw = BytesMut::new();
+        MessageCodec
+            .encode(Message::new(MessageTag::Unchoke, vec![]), &mut raw)
+            .unwrap();
+        raw.extend_from_slice(&[0, 0, 0, 0]);
+        MessageCodec
+            .encode(Message::new(MessageTag::Have, vec![0, 0, 0, 9]), &mut raw)
+            .unwrap();
+        raw.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(&raw[..5], &[0, 0, 0, 1, 1]);
+
+        let first = MessageCodec.decode(&mut raw).unwrap().unwrap();
+        assert_eq!(first.tag, MessageTag::Unchoke);
+        assert!(first.payload.is_empty());
+        let second = MessageCodec.decode(&mut raw).unwrap().unwrap();
+        assert_eq!(second.tag, MessageTag::Have);
+        assert_eq!(second.payload, vec![0, 0, 0, 9]);
+        // The trailing keep-alive is consumed without yielding anything
+        assert!(MessageCodec.decode(&mut raw).unwrap().is_none());
+        assert!(raw.is_empty());
+    }
+
+    #[test]
+    fn codec_waits_for_a_whole_frame() {
+        let mut raw = BytesMut::new();
+        MessageCodec
+            .encode(Message::new(MessageTag::Piece, vec![7; 100]), &mut raw)
+            .unwrap();
+        let mut partial = raw.split_to(50);
+        assert!(MessageCodec.decode(&mut partial).unwrap().is_none());
+        partial.unsplit(raw);
+        let message = MessageCodec.decode(&mut partial).unwrap().unwrap();
+        assert_eq!(message.payload, vec![7; 100]);
+    }
+
+    // Answers the handshake, writes `tail` and closes the connection
+    async fn connect_and_close(tail: &'static [u8]) -> anyhow::Error {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            theirs.write_all(&handshake).await.unwrap();
+            // Our HaveNone, closing before it's read would fail its write instead
+            let mut have_none = [0u8; 5];
+            theirs.read_exact(&mut have_none).await.unwrap();
+            theirs.write_all(tail).await.unwrap();
+        });
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let options = PeerOptions::default();
+        Peer::new_from_stream(addr, ours, &INFO_HASH, &[false; 4], &options)
+            .await
+            .unwrap_err()
+    }
+
+    #[tokio::test]
+    async fn clean_close_after_the_handshake_is_a_disconnect() {
+        let err = connect_and_close(&[]).await;
+        assert!(err.is::<PeerDisconnected>(), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn close_mid_message_is_not_a_clean_disconnect() {
+        let err = connect_and_close(&[0, 0, 0, 5, 4, 0]).await;
+        assert!(!err.is::<PeerDisconnected>(), "{}", err);
+        assert!(err.to_string().contains("into a message"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn half_size_blocks_are_re_requested_until_the_piece_completes() {
+        let content: Vec<u8> = (0..3 * (1 << 14) + 100)
+            .map(|n: u32| (n % 251) as u8)
+            .collect();
+        let stream = MockPeer::new(INFO_HASH, vec![content.clone()])
+            .short_blocks(true)
+            .duplex();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let options = PeerOptions::default();
+        let mut peer = Peer::new_from_stream(addr, stream, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap();
+        let piece = peer.download_piece(0, content.len()).await.unwrap();
+        assert_eq!(piece, content);
+    }
+
+    #[test]
+    fn bitfield_of_the_wrong_length_is_rejected() {
+        // 10 pieces need exactly 2 bytes
+        assert!(from_bitfield(&[0xff, 0xc0, 0xff], 10).is_err());
+        assert!(from_bitfield(&[0xff], 10).is_err());
+        assert_eq!(from_bitfield(&[0x80, 0x40], 10).unwrap(), vec![0, 9]);
+    }
+
+    #[test]
+    fn bitfield_spare_bits_past_the_last_piece_are_rejected() {
+        assert!(from_bitfield(&[0xff, 0xe0], 10).is_err());
+    }
+
+    #[tokio::test]
+    async fn over_long_bitfield_drops_the_peer() {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            theirs.write_all(&handshake).await.unwrap();
+            let mut remote = Framed::new(theirs, MessageCodec);
+            let bitfield = Message::new(MessageTag::Bitfield, vec![0xff; 4]);
+            remote.send(bitfield).await.unwrap();
+            while remote.next().await.is_some() {}
+        });
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let options = PeerOptions::default();
+        let err = Peer::new_from_stream(addr, ours, &INFO_HASH, &[false; 10], &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Bitfield has 4 bytes"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_dropped_within_the_handshake_timeout() {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 16);
+        // Takes our handshake and never answers
+        tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            theirs.read_exact(&mut handshake).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let options = PeerOptions {
+            bitfield_timeout: Some(Duration::from_millis(100)),
+            ..PeerOptions::default()
+        };
+        let started = Instant::now();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let err = Peer::new_from_stream(addr, ours, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("handshake"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn peer_silent_after_the_handshake_is_dropped_once_the_bitfield_timeout_runs_out() {
+        // Has nothing, so it sends no bitfield and then waits on us
+        let addr = MockPeer::new(INFO_HASH, vec![vec![0; 10]])
+            .missing(0)
+            .spawn()
+            .await
+            .unwrap();
+        let timeout = Duration::from_millis(100);
+        let options = PeerOptions {
+            bitfield_timeout: Some(timeout),
+            ..PeerOptions::default()
+        };
+        let started = Instant::now();
+        let err = Peer::new(addr, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap_err();
+        let elapsed = started.elapsed();
+        assert!(
+            err.to_string().contains("sent nothing within 100ms"),
+            "{}",
+            err
+        );
+        assert!(elapsed >= timeout, "{:?}", elapsed);
+        assert!(
+            elapsed < timeout + Duration::from_millis(150),
+            "{:?}",
+            elapsed
+        );
+    }
+
+    #[tokio::test]
+    async fn silent_peer_is_dropped_during_the_encryption_handshake() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        // Accepts the connection and never sends a byte
+        tokio::spawn(async move {
+            let _held = listener.accept().await;
+            std::future::pending::<()>().await;
+        });
+        let options = PeerOptions {
+            prefer_encryption: true,
+            bitfield_timeout: Some(Duration::from_millis(100)),
+            ..PeerOptions::default()
+        };
+        let started = Instant::now();
+        let err = Peer::new(addr, &INFO_HASH, &[false], &options)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("encryption handshake"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
@@ -0,0 +1,91 @@
+use rand::seq::SliceRandom;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PieceStrategy {
+    // In index order, what streaming needs
+    #[default]
+    Sequential,
+    // Pieces fewest peers have first, keeps them from disappearing from the swarm
+    RarestFirst,
+    // Doesn't reveal which part of the content we're after
+    Random,
+}
+
+impl PieceStrategy {
+    pub fn picker(self) -> Box<dyn PiecePicker> {
+        match self {
+            PieceStrategy::Sequential => Box::new(Sequential),
+            PieceStrategy::RarestFirst => Box::new(RarestFirst),
+            PieceStrategy::Random => Box::new(Random),
+        }
+    }
+}
+
+pub trait PiecePicker: Send + Sync {
+    /// The order to fetch `pieces` in, given how many peers have each piece
+    fn order(&self, pieces: &[usize], availability: &[u16]) -> Vec<usize>;
+}
+
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn order(&self, pieces: &[usize], _: &[u16]) -> Vec<usize> {
+        let mut order = pieces.to_vec();
+        order.sort_unstable();
+        order
+    }
+}
+
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    // Ties keep index order, pieces nobody has go last
+    fn order(&self, pieces: &[usize], availability: &[u16]) -> Vec<usize> {
+        let mut order = Sequential.order(pieces, availability);
+        order.sort_by_key(|idx| match availability.get(*idx) {
+            Some(0) | None => u16::MAX,
+            Some(count) => *count,
+        });
+        order
+    }
+}
+
+pub struct Random;
+
+impl PiecePicker for Random {
+    fn order(&self, pieces: &[usize], _: &[u16]) -> Vec<usize> {
+        let mut order = pieces.to_vec();
+        order.shuffle(&mut rand::thread_rng());
+        order
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECES: [usize; 5] = [4, 0, 3, 1, 2];
+    // Piece 2 is the rarest, nobody has piece 4
+    const AVAILABILITY: [u16; 5] = [3, 2, 1, 2, 0];
+
+    fn order(strategy: PieceStrategy) -> Vec<usize> {
+        strategy.picker().order(&PIECES, &AVAILABILITY)
+    }
+
+    #[test]
+    fn sequential_goes_in_index_order() {
+        assert_eq!(order(PieceStrategy::Sequential), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn rarest_first_goes_by_availability() {
+        assert_eq!(order(PieceStrategy::RarestFirst), vec![2, 1, 3, 0, 4]);
+    }
+
+    #[test]
+    fn random_is_a_permutation_of_the_pieces() {
+        let mut order = order(PieceStrategy::Random);
+        order.sort_unstable();
+        assert_eq!(order, vec![0, 1, 2, 3, 4]);
+    }
+}
@@ -0,0 +1,258 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+// Doubles with every failure in a row, so a flaky peer isn't hammered with reconnects
+const BASE_COOLDOWN: Duration = Duration::from_secs(5);
+const MAX_COOLDOWN: Duration = Duration::from_secs(10 * 60);
+// Pieces a peer may fail to serve in a row, despite advertising them, before it's given up on
+const MAX_PIECE_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerState {
+    Known,
+    Connecting,
+    Connected,
+    Failed,
+    // Kept failing to serve pieces it advertised, never connected to again
+    Unreliable,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IpFamily {
+    #[default]
+    Both,
+    V4,
+    V6,
+}
+
+impl IpFamily {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        match self {
+            IpFamily::Both => true,
+            IpFamily::V4 => addr.is_ipv4(),
+            IpFamily::V6 => addr.is_ipv6(),
+        }
+    }
+}
+
+// Every discovered peer address goes through here, whatever source it came from
+#[derive(Debug, Default)]
+pub struct PeerPool {
+    states: HashMap<SocketAddr, PeerState>,
+    // Known addresses not handed out yet, in discovery order
+    candidates: VecDeque<SocketAddr>,
+    // Addresses of any other family are never added
+    family: IpFamily,
+    // Failures in a row and when the address may be retried, cleared once it connects
+    failures: HashMap<SocketAddr, (u32, Instant)>,
+    // Advertised pieces each peer failed to serve in a row, across reconnects
+    piece_failures: HashMap<SocketAddr, u32>,
+}
+
+impl PeerPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn with_family(family: IpFamily) -> Self {
+        Self {
+            family,
+            ..Self::default()
+        }
+    }
+    /// Returns false if the address was already in the pool or is of the wrong family
+    pub fn add(&mut self, addr: SocketAddr) -> bool {
+        if !self.family.matches(&addr) || self.states.contains_key(&addr) {
+            return false;
+        }
+        self.states.insert(addr, PeerState::Known);
+        self.candidates.push_back(addr);
+        true
+    }
+    /// Returns how many of the addresses were new
+    pub fn extend<I>(&mut self, addrs: I) -> usize
+    where
+        I: IntoIterator<Item = SocketAddr>,
+    {
+        addrs.into_iter().filter(|addr| self.add(*addr)).count()
+    }
+    /// Hands out the next address to connect to and marks it as connecting
+    pub fn next_candidate(&mut self) -> Option<SocketAddr> {
+        let addr = self.candidates.pop_front()?;
+        self.states.insert(addr, PeerState::Connecting);
+        Some(addr)
+    }
+    /// Puts back a candidate that couldn't be connected to yet, it's handed out first next time
+    pub fn requeue(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, PeerState::Known);
+        self.candidates.push_front(addr);
+    }
+    pub fn mark_connected(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, PeerState::Connected);
+        self.failures.remove(&addr);
+    }
+    pub fn mark_failed(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, PeerState::Failed);
+        let count = self.failures.get(&addr).map_or(0, |(count, _)| *count) + 1;
+        let cooldown = BASE_COOLDOWN
+            .saturating_mul(1 << (count - 1).min(16))
+            .min(MAX_COOLDOWN);
+        self.failures
+            .insert(addr, (count, Instant::now() + cooldown));
+    }
+    /// Counts a piece the peer advertised but didn't serve, returns true once that made it
+    /// unreliable
+    pub fn record_piece_failure(&mut self, addr: SocketAddr) -> bool {
+        let count = self.piece_failures.entry(addr).or_insert(0);
+        *count += 1;
+        if *count < MAX_PIECE_FAILURES {
+            return false;
+        }
+        self.ban(addr);
+        true
+    }
+    /// Marks the peer unreliable right away, it's never handed out again
+    pub fn ban(&mut self, addr: SocketAddr) {
+        self.states.insert(addr, PeerState::Unreliable);
+        self.candidates.retain(|candidate| *candidate != addr);
+    }
+    pub fn record_piece_success(&mut self, addr: SocketAddr) {
+        self.piece_failures.remove(&addr);
+    }
+    /// How long a failed address is still skipped for
+    pub fn cooldown(&self, addr: &SocketAddr) -> Option<Duration> {
+        let (_, retry_at) = self.failures.get(addr)?;
+        let remaining = retry_at.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+    /// Makes failed addresses whose cooldown is over candidates again, returns how many
+    pub fn requeue_cooled(&mut self) -> usize {
+        let now = Instant::now();
+        let cooled: Vec<SocketAddr> = self
+            .failures
+            .iter()
+            .filter(|(addr, (_, retry_at))| {
+                *retry_at <= now && self.states.get(addr) == Some(&PeerState::Failed)
+            })
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in &cooled {
+            self.states.insert(*addr, PeerState::Known);
+            self.candidates.push_back(*addr);
+        }
+        cooled.len()
+    }
+    pub fn state(&self, addr: &SocketAddr) -> Option<PeerState> {
+        self.states.get(addr).copied()
+    }
+    /// Every address currently in `state`
+    pub fn addrs_in(&self, state: PeerState) -> Vec<SocketAddr> {
+        self.states
+            .iter()
+            .filter(|(_, s)| **s == state)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+    pub fn count(&self, state: PeerState) -> usize {
+        self.states.values().filter(|s| **s == state).count()
+    }
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn addresses_are_added_once() {
+        let mut pool = PeerPool::new();
+        assert!(pool.add(addr(1)));
+        assert!(!pool.add(addr(1)));
+        assert_eq!(pool.extend([addr(1), addr(2), addr(2), addr(3)]), 2);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.next_candidate(), Some(addr(1)));
+        assert_eq!(pool.next_candidate(), Some(addr(2)));
+        assert_eq!(pool.next_candidate(), Some(addr(3)));
+        assert_eq!(pool.next_candidate(), None);
+    }
+
+    #[test]
+    fn a_peer_goes_from_known_to_connected_to_failed() {
+        let mut pool = PeerPool::new();
+        pool.add(addr(1));
+        assert_eq!(pool.state(&addr(1)), Some(PeerState::Known));
+        pool.next_candidate();
+        assert_eq!(pool.state(&addr(1)), Some(PeerState::Connecting));
+        pool.mark_connected(addr(1));
+        assert_eq!(pool.state(&addr(1)), Some(PeerState::Connected));
+        assert_eq!(pool.cooldown(&addr(1)), None);
+        pool.mark_failed(addr(1));
+        assert_eq!(pool.state(&addr(1)), Some(PeerState::Failed));
+        assert_eq!(pool.addrs_in(PeerState::Failed), vec![addr(1)]);
+        // Still cooling down, so it isn't a candidate again yet
+        assert_eq!(pool.requeue_cooled(), 0);
+        assert_eq!(pool.next_candidate(), None);
+    }
+
+    #[test]
+    fn cooldown_doubles_with_each_failure_up_to_the_cap() {
+        let mut pool = PeerPool::new();
+        pool.add(addr(1));
+        let mut expected = BASE_COOLDOWN;
+        for _ in 0..10 {
+            pool.mark_failed(addr(1));
+            let cooldown = pool.cooldown(&addr(1)).unwrap();
+            assert!(cooldown <= expected, "{:?} > {:?}", cooldown, expected);
+            assert!(cooldown > expected - Duration::from_secs(1));
+            expected = (expected * 2).min(MAX_COOLDOWN);
+        }
+        assert_eq!(expected, MAX_COOLDOWN);
+        // Connecting clears the failures, the next one starts from the base again
+        pool.mark_connected(addr(1));
+        pool.mark_failed(addr(1));
+        assert!(pool.cooldown(&addr(1)).unwrap() <= BASE_COOLDOWN);
+    }
+
+    #[test]
+    fn a_peer_is_given_up_on_after_failing_advertised_pieces() {
+        let mut pool = PeerPool::new();
+        pool.add(addr(1));
+        for _ in 1..MAX_PIECE_FAILURES {
+            assert!(!pool.record_piece_failure(addr(1)));
+        }
+        // A served piece resets the count
+        pool.record_piece_success(addr(1));
+        for _ in 1..MAX_PIECE_FAILURES {
+            assert!(!pool.record_piece_failure(addr(1)));
+        }
+        // The client marks it failed first, the way it drops any peer that didn't serve
+        pool.mark_failed(addr(1));
+        assert!(pool.record_piece_failure(addr(1)));
+        assert_eq!(pool.state(&addr(1)), Some(PeerState::Unreliable));
+        assert_eq!(pool.requeue_cooled(), 0);
+        assert_eq!(pool.next_candidate(), None);
+    }
+
+    #[test]
+    fn addresses_of_the_other_family_are_never_added() {
+        let v6: SocketAddr = "[::1]:1".parse().unwrap();
+        let mut pool = PeerPool::with_family(IpFamily::V4);
+        assert_eq!(pool.extend([addr(1), v6]), 1);
+        assert_eq!(pool.state(&v6), None);
+        let mut pool = PeerPool::with_family(IpFamily::V6);
+        assert_eq!(pool.extend([addr(1), v6]), 1);
+        assert_eq!(pool.next_candidate(), Some(v6));
+        assert_eq!(PeerPool::new().extend([addr(1), v6]), 2);
+    }
+}
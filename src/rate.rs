@@ -0,0 +1,71 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+// Token bucket shared by every connection it limits, holding at most a second's worth of
+// tokens so an idle stretch doesn't turn into a burst
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_sec: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1) as f64;
+        Self {
+            bytes_per_sec,
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+    /// Waits until `bytes` may be sent, sends larger than the bucket go into debt that later
+    /// callers wait off
+    pub async fn acquire(&self, bytes: usize) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+            bucket.refilled_at = now;
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.bytes_per_sec)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_past_the_bucket_wait_at_the_configured_rate() {
+        let limit = RateLimiter::new(10_000);
+        // A full bucket lets the first second's worth through at once
+        let start = Instant::now();
+        limit.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // The next 2000 bytes take a fifth of a second to earn
+        let start = Instant::now();
+        limit.acquire(2_000).await;
+        let waited = start.elapsed();
+        assert!(
+            waited >= Duration::from_millis(190) && waited < Duration::from_millis(400),
+            "{:?}",
+            waited
+        );
+    }
+}
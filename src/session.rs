@@ -0,0 +1,200 @@
+use std::{path::PathBuf, sync::Arc};
+
+use tokio::sync::Semaphore;
+
+use crate::{
+    client::{Client, ClientBuilder},
+    rate::RateLimiter,
+    torrent::Torrent,
+};
+
+// Several torrents downloading side by side, sharing one budget of peer connections and one
+// upload rate. There's no listener yet, every connection is outbound
+pub struct Session<'a> {
+    budget: Arc<Semaphore>,
+    // Pieces in flight across every torrent, unbounded unless set
+    piece_slots: Option<Arc<Semaphore>>,
+    // Upload bandwidth of every torrent together, unlimited unless set
+    upload_limit: Option<Arc<RateLimiter>>,
+    clients: Vec<Client<'a>>,
+}
+
+impl<'a> Session<'a> {
+    pub fn new(max_connections: usize) -> Self {
+        Self {
+            budget: Arc::new(Semaphore::new(max_connections.max(1))),
+            piece_slots: None,
+            upload_limit: None,
+            clients: Vec::new(),
+        }
+    }
+    /// Bounds how many pieces the torrents built with `builder` fetch at once, all together
+    pub fn max_concurrent_pieces(mut self, max: usize) -> Self {
+        self.piece_slots = Some(Arc::new(Semaphore::new(max.max(1))));
+        self
+    }
+    /// Caps the upload bandwidth of the torrents built with `builder`, all together
+    pub fn upload_rate(mut self, bytes_per_sec: u64) -> Self {
+        self.upload_limit = Some(Arc::new(RateLimiter::new(bytes_per_sec)));
+        self
+    }
+    /// A builder already bound to this session's connection budget and shared limits
+    pub fn builder(&self, torrent: &'a Torrent) -> ClientBuilder<'a> {
+        let mut builder = ClientBuilder::new(torrent).connection_budget(self.budget.clone());
+        if let Some(slots) = &self.piece_slots {
+            builder = builder.piece_slots(slots.clone());
+        }
+        if let Some(limit) = &self.upload_limit {
+            builder = builder.upload_limit(limit.clone());
+        }
+        builder
+    }
+    /// Connects a torrent built with `builder` and returns its index in the session
+    pub async fn add(&mut self, builder: ClientBuilder<'a>) -> anyhow::Result<usize> {
+        let client = builder.build().await?;
+        self.clients.push(client);
+        Ok(self.clients.len() - 1)
+    }
+    pub fn clients(&self) -> &[Client<'a>] {
+        &self.clients
+    }
+    pub fn client_mut(&mut self, idx: usize) -> Option<&mut Client<'a>> {
+        self.clients.get_mut(idx)
+    }
+    /// Connections that can still be opened across the whole session
+    pub fn available_connections(&self) -> usize {
+        self.budget.available_permits()
+    }
+    /// Downloads every torrent at once, each to its own name, in the order they were added
+    pub async fn download_all(&mut self) -> Vec<anyhow::Result<PathBuf>> {
+        let mut downloads = Vec::new();
+        for client in &mut self.clients {
+            let path = client.file_name().to_string();
+            downloads.push(async move { client.download_file(path).await });
+        }
+        futures_util::future::join_all(downloads).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, MockPeer};
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    #[tokio::test]
+    async fn torrents_download_side_by_side_under_shared_limits() {
+        let first: Vec<u8> = (0..20_000).map(|n| n as u8).collect();
+        let second = vec![9u8; 30_000];
+        let (first_torrent, first_pieces) = mock::torrent(&first, 1 << 14);
+        let (second_torrent, second_pieces) = mock::torrent(&second, 1 << 14);
+        let first_peer = MockPeer::new(first_torrent.info_hash().unwrap(), first_pieces);
+        let second_peer = MockPeer::new(second_torrent.info_hash().unwrap(), second_pieces);
+        let first_addr = first_peer.spawn().await.unwrap();
+        let second_addr = second_peer.spawn().await.unwrap();
+
+        let mut session = Session::new(2).upload_rate(1 << 20);
+        let first_builder = session.builder(&first_torrent).peers([first_addr]);
+        let second_builder = session.builder(&second_torrent).peers([second_addr]);
+        // Both builders hold the session's limiter, not a copy of it
+        assert_eq!(Arc::strong_count(session.upload_limit.as_ref().unwrap()), 3);
+        session.add(first_builder).await.unwrap();
+        session.add(second_builder).await.unwrap();
+        assert_eq!(session.available_connections(), 0);
+
+        let first_data = session.client_mut(0).unwrap().download_to_vec().await;
+        let second_data = session.client_mut(1).unwrap().download_to_vec().await;
+        assert_eq!(first_data.unwrap(), first);
+        assert_eq!(second_data.unwrap(), second);
+    }
+
+    #[tokio::test]
+    async fn piece_slots_bound_the_pieces_in_flight_across_torrents() {
+        const PIECE: usize = 1 << 14;
+        const DELAY: Duration = Duration::from_millis(100);
+        let first = vec![1u8; 2 * PIECE];
+        let second = vec![2u8; 2 * PIECE];
+        let (first_torrent, first_pieces) = mock::torrent(&first, PIECE);
+        let (second_torrent, second_pieces) = mock::torrent(&second, PIECE);
+        // One block per piece, each answered after `DELAY`
+        let first_addr = MockPeer::new(first_torrent.info_hash().unwrap(), first_pieces)
+            .delay(DELAY)
+            .spawn()
+            .await
+            .unwrap();
+        let second_addr = MockPeer::new(second_torrent.info_hash().unwrap(), second_pieces)
+            .delay(DELAY)
+            .spawn()
+            .await
+            .unwrap();
+
+        let mut session = Session::new(4).max_concurrent_pieces(1);
+        let first_builder = session.builder(&first_torrent).peers([first_addr]);
+        let second_builder = session.builder(&second_torrent).peers([second_addr]);
+        session.add(first_builder).await.unwrap();
+        session.add(second_builder).await.unwrap();
+        let [first_client, second_client] = &mut session.clients[..] else {
+            panic!("Two clients were added");
+        };
+        let start = Instant::now();
+        let (first_data, second_data) = tokio::join!(
+            first_client.download_to_vec(),
+            second_client.download_to_vec()
+        );
+        assert_eq!(first_data.unwrap(), first);
+        assert_eq!(second_data.unwrap(), second);
+        // Side by side but one piece at a time, the four pieces took their delays one after
+        // the other
+        assert!(start.elapsed() >= 4 * DELAY, "{:?}", start.elapsed());
+        let slots = session.piece_slots.as_ref().unwrap();
+        assert_eq!(slots.available_permits(), 1);
+    }
+
+    #[tokio::test]
+    async fn failing_peers_are_replaced_without_going_over_the_budget() {
+        const PIECE: usize = 1 << 14;
+        let content: Vec<u8> = (0..4 * PIECE).map(|n| (n / 7) as u8).collect();
+        let (torrent, pieces) = mock::torrent(&content, PIECE);
+        let info_hash = torrent.info_hash().unwrap();
+        // Mock `n` only has a valid copy of piece `n`, so each piece drops the peer before it
+        let mut addrs = Vec::new();
+        let mut open = Vec::new();
+        let mut received = Vec::new();
+        for n in 0..4 {
+            let mut peer = MockPeer::new(info_hash, pieces.clone());
+            for idx in (0..4).filter(|idx| *idx != n) {
+                peer = peer.corrupt(idx);
+            }
+            open.push(peer.open_connections());
+            received.push(peer.received());
+            addrs.push(peer.spawn().await.unwrap());
+        }
+        let most_open = Arc::new(AtomicUsize::new(0));
+        let sampler = {
+            let most_open = most_open.clone();
+            tokio::spawn(async move {
+                loop {
+                    let live = open.iter().map(|n| n.load(Ordering::SeqCst)).sum();
+                    most_open.fetch_max(live, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                }
+            })
+        };
+
+        let mut session = Session::new(2);
+        let builder = session.builder(&torrent).peers(addrs);
+        session.add(builder).await.unwrap();
+        assert_eq!(session.available_connections(), 0);
+        let data = session.client_mut(0).unwrap().download_to_vec().await;
+        sampler.abort();
+        assert_eq!(data.unwrap(), content);
+        // Every mock was connected to in turn, never more than two at once
+        assert!(received.iter().all(|tags| !tags.lock().unwrap().is_empty()));
+        assert_eq!(most_open.load(Ordering::SeqCst), 2);
+        let live = session.clients()[0].peers_status().len();
+        assert_eq!(session.available_connections(), 2 - live);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{future::Future, net::SocketAddr, pin::Pin};
+
+use anyhow::bail;
+
+use crate::{torrent::Torrent, tracker::TrackerRequest};
+
+pub type Discovery<'a> = Pin<Box<dyn Future<Output = anyhow::Result<Vec<SocketAddr>>> + Send + 'a>>;
+
+// Anything that can turn an info hash into peer addresses, e.g. trackers, DHT or PEX
+pub trait PeerSource: Send + Sync {
+    fn discover<'a>(&'a self, info_hash: [u8; 20], request: &'a TrackerRequest) -> Discovery<'a>;
+}
+
+// A fixed list of peers, e.g. given on the command line
+impl PeerSource for Vec<SocketAddr> {
+    fn discover<'a>(&'a self, _: [u8; 20], _: &'a TrackerRequest) -> Discovery<'a> {
+        Box::pin(async move { Ok(self.clone()) })
+    }
+}
+
+// Every HTTP tracker of a torrent, see `Torrent::announce_all`
+pub struct TrackerSource {
+    torrent: Torrent,
+    http: reqwest::Client,
+}
+
+impl TrackerSource {
+    pub fn new(torrent: Torrent, http: reqwest::Client) -> Self {
+        Self { torrent, http }
+    }
+}
+
+impl PeerSource for TrackerSource {
+    fn discover<'a>(&'a self, _: [u8; 20], request: &'a TrackerRequest) -> Discovery<'a> {
+        Box::pin(async move {
+            let report = self.torrent.announce_all(&self.http, request).await;
+            if report.trackers.iter().all(|(_, result)| result.is_err()) {
+                bail!("Every tracker failed");
+            }
+            Ok(report.peers)
+        })
+    }
+}
+
+/// Queries every source and returns their peers merged, a failing source is skipped
+pub async fn discover_all(
+    sources: &[Box<dyn PeerSource>],
+    info_hash: [u8; 20],
+    request: &TrackerRequest,
+) -> Vec<SocketAddr> {
+    let mut peers = Vec::new();
+    for source in sources {
+        let Ok(found) = source.discover(info_hash, request).await else {
+            continue;
+        };
+        for addr in found {
+            if !peers.contains(&addr) {
+                peers.push(addr);
+            }
+        }
+    }
+    peers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Failing;
+
+    impl PeerSource for Failing {
+        fn discover<'a>(&'a self, _: [u8; 20], _: &'a TrackerRequest) -> Discovery<'a> {
+            Box::pin(async { bail!("Source is down") })
+        }
+    }
+
+    fn addrs(ports: &[u16]) -> Vec<SocketAddr> {
+        ports
+            .iter()
+            .map(|port| SocketAddr::from(([127, 0, 0, 1], *port)))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn sources_are_merged_without_duplicates() {
+        let sources: Vec<Box<dyn PeerSource>> = vec![
+            Box::new(addrs(&[1, 2, 3])),
+            Box::new(Failing),
+            Box::new(addrs(&[3, 4, 1])),
+        ];
+        let (torrent, _) = crate::mock::torrent(&[0; 10], 16);
+        let request = torrent.tracker_request();
+        let found = discover_all(&sources, [0; 20], &request).await;
+        assert_eq!(found, addrs(&[1, 2, 3, 4]));
+    }
+}
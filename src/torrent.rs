@@ -1,24 +1,66 @@
-use std::net::SocketAddrV4;
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
 
-use anyhow::Context;
+use anyhow::{bail, Context};
+use encoding_rs::Encoding;
 use hashes::Hashes;
+use raw::RawBytes;
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
 use sha1::{Digest, Sha1};
 
-use crate::tracker::{TrackerRequest, TrackerResponse};
+use crate::merkle::{self, Hash};
+use crate::tracker::{AnnounceReport, ScrapeStats, TrackerError, TrackerRequest, TrackerResponse};
 
 #[derive(Debug, Clone, Deserialize)]
-pub(crate) struct Torrent {
-    pub announce: String,
+pub struct Torrent {
+    // Trackerless torrents leave it out and only carry DHT `nodes`
+    #[serde(default)]
+    pub announce: Option<String>,
+    // Tiers of backup trackers (BEP 12)
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Option<Vec<Vec<String>>>,
+    // Character set of the names inside `info`, when they aren't UTF-8
+    #[serde(default)]
+    pub encoding: Option<String>,
     pub info: Info,
+    // v2 only, the piece hashes of every file larger than a piece, keyed by its pieces root
+    #[serde(rename = "piece layers", default)]
+    pub piece_layers: Option<HashMap<RawBytes, RawBytes>>,
+    // Web seeds (BEP 19), either a single URL or a list of them
+    #[serde(rename = "url-list", default)]
+    pub url_list: Option<UrlList>,
+    // DHT bootstrap nodes (BEP 5), each a `[host, port]` list
+    #[serde(default)]
+    pub nodes: Option<Vec<(String, u16)>>,
+    // The info dict exactly as it appears in the file, see `Torrent::from_bytes`
+    #[serde(skip)]
+    raw_info: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum UrlList {
+    One(String),
+    Many(Vec<String>),
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
-pub(crate) struct Info {
-    pub name: String,
+pub struct Info {
+    // Kept as raw bytes so names in legacy encodings still parse and hash correctly
+    pub name: RawBytes,
+    // Redundant UTF-8 rendering of `name`, kept so the info hash stays intact
+    #[serde(
+        rename = "name.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub name_utf8: Option<RawBytes>,
     #[serde(rename = "piece length")]
     pub plength: usize,
     pub pieces: Hashes,
+    // Private torrents (BEP 27) only get peers from their trackers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub private: Option<u8>,
     #[serde(flatten)]
     pub keys: Keys,
 }
@@ -33,13 +75,40 @@ pub enum Keys {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     length: usize,
-    path: Vec<String>,
+    path: Vec<RawBytes>,
+    #[serde(
+        rename = "path.utf-8",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    path_utf8: Option<Vec<RawBytes>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    pub path: PathBuf,
+    pub length: usize,
+    // Byte offset of the file within the concatenated content
+    pub offset: usize,
 }
 
 impl Torrent {
+    /// Parses a .torrent file, keeping the info dict's own bytes for hashing and serving it
+    pub fn from_bytes(raw: &[u8]) -> anyhow::Result<Self> {
+        let mut torrent: Torrent = serde_bencode::from_bytes(raw)?;
+        torrent.raw_info = Some(info_bytes(raw)?.to_vec());
+        Ok(torrent)
+    }
+    /// The bencoded info dict, as found in the file when parsed with `from_bytes`. Otherwise
+    /// it's re-encoded, which drops any key `Info` doesn't know about
+    pub fn info_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        match &self.raw_info {
+            Some(raw) => Ok(raw.clone()),
+            None => Ok(serde_bencode::to_bytes(&self.info)?),
+        }
+    }
     pub fn info_hash(&self) -> anyhow::Result<[u8; 20]> {
-        let info = &self.info;
-        let ser = serde_bencode::to_bytes(info)?;
+        let ser = self.info_bytes()?;
         let mut hasher = Sha1::new();
         hasher.update(&ser);
         let result = hasher
@@ -54,39 +123,547 @@ impl Torrent {
         let pieces = &self.info.pieces.0;
         Ok(pieces.iter().map(hex::encode).collect())
     }
+    /// The raw SHA1 a piece must hash to, none past the last piece
+    pub fn piece_hash(&self, index: usize) -> Option<[u8; 20]> {
+        self.info.pieces.0.get(index).copied()
+    }
+    pub fn piece_count(&self) -> usize {
+        self.info.pieces.0.len()
+    }
     pub fn length(&self) -> usize {
         let keys = &self.info.keys;
         match keys {
             Keys::SingleFile { length } => *length,
-            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+            // Saturates so an absurd torrent can't panic before `validate` rejects it
+            Keys::MultiFile { files } => files
+                .iter()
+                .fold(0, |total: usize, file| total.saturating_add(file.length)),
         }
     }
-    pub async fn peers(&self) -> anyhow::Result<Vec<SocketAddrV4>> {
-        let info_hash = self.info_hash()?;
-        let info_hash = urlencode(&info_hash);
-
-        let data = TrackerRequest {
+    /// Multi file torrents are written as a directory named after `info.name`
+    pub fn is_multi_file(&self) -> bool {
+        matches!(self.info.keys, Keys::MultiFile { .. })
+    }
+    pub fn is_private(&self) -> bool {
+        self.info.private == Some(1)
+    }
+    pub fn display_name(&self) -> String {
+        match &self.info.name_utf8 {
+            Some(name) => String::from_utf8_lossy(&name.0).into_owned(),
+            None => self.decode_text(&self.info.name.0),
+        }
+    }
+    fn decode_text(&self, raw: &[u8]) -> String {
+        let encoding = self
+            .encoding
+            .as_deref()
+            .and_then(|label| Encoding::for_label(label.as_bytes()));
+        match encoding {
+            Some(encoding) => encoding.decode(raw).0.into_owned(),
+            None => String::from_utf8_lossy(raw).into_owned(),
+        }
+    }
+    /// Checks a v2 piece against the merkle root of the file it belongs to
+    pub fn verify_v2_piece(
+        &self,
+        pieces_root: &Hash,
+        file_length: usize,
+        idx: usize,
+        data: &[u8],
+    ) -> anyhow::Result<bool> {
+        let plength = self.info.plength;
+        if plength < merkle::BLOCK_SIZE || !plength.is_power_of_two() {
+            bail!("Invalid v2 piece length : {}", plength);
+        }
+        let leaves = merkle::leaves(data);
+        // Files up to a piece long have no layer, the root covers their blocks directly
+        if file_length <= plength {
+            let width = leaves.len();
+            return Ok(idx == 0 && merkle::root(leaves, width, [0; 32]) == *pieces_root);
+        }
+        let layer = self
+            .piece_layers
+            .as_ref()
+            .and_then(|layers| layers.get(&RawBytes(pieces_root.to_vec())))
+            .context("Torrent has no piece layer for this file")?;
+        let piece_count = file_length.div_ceil(plength);
+        if layer.0.len() != piece_count * 32 {
+            bail!(
+                "Piece layer has {} bytes, expected {}",
+                layer.0.len(),
+                piece_count * 32
+            );
+        }
+        let layer: Vec<Hash> = layer
+            .0
+            .chunks_exact(32)
+            .map(|hash| hash.try_into().expect("Can't panic"))
+            .collect();
+        let blocks_per_piece = plength / merkle::BLOCK_SIZE;
+        // Pieces past the end of the file stand for subtrees of zero leaves
+        let pad = merkle::root(Vec::new(), blocks_per_piece, [0; 32]);
+        if merkle::root(layer.clone(), piece_count, pad) != *pieces_root {
+            bail!("Piece layer doesn't match the file's pieces root");
+        }
+        let Some(expected) = layer.get(idx) else {
+            return Ok(false);
+        };
+        Ok(merkle::root(leaves, blocks_per_piece, [0; 32]) == *expected)
+    }
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let length = match &self.info.keys {
+            Keys::SingleFile { length } => Some(*length),
+            Keys::MultiFile { files } => files
+                .iter()
+                .try_fold(0usize, |total, file| total.checked_add(file.length)),
+        };
+        let Some(length) = length.filter(|length| *length as u64 <= MAX_CONTENT_LENGTH) else {
+            bail!(
+                "Torrent content is larger than {} bytes",
+                MAX_CONTENT_LENGTH
+            );
+        };
+        let piece_count = self.piece_count();
+        if self.info.plength == 0 {
+            bail!("Piece length is zero");
+        }
+        if self.info.plength > MAX_PIECE_LENGTH {
+            bail!(
+                "Piece length {} is larger than {} bytes",
+                self.info.plength,
+                MAX_PIECE_LENGTH
+            );
+        }
+        if length > 0 && piece_count == 0 {
+            bail!(
+                "Torrent has {} bytes of content but no piece hashes",
+                length
+            );
+        }
+        let expected = length.div_ceil(self.info.plength);
+        if piece_count != expected {
+            bail!(
+                "Torrent has {} piece hashes, expected {} for {} bytes",
+                piece_count,
+                expected,
+                length
+            );
+        }
+        Ok(())
+    }
+    pub fn files(&self) -> Vec<FileEntry> {
+        let info = &self.info;
+        match &info.keys {
+            Keys::SingleFile { length } => vec![FileEntry {
+                path: PathBuf::from(self.display_name()),
+                length: *length,
+                offset: 0,
+            }],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|file| {
+                        let mut path = PathBuf::from(self.display_name());
+                        match &file.path_utf8 {
+                            Some(parts) => path.extend(
+                                parts
+                                    .iter()
+                                    .map(|part| String::from_utf8_lossy(&part.0).into_owned()),
+                            ),
+                            None => {
+                                path.extend(file.path.iter().map(|part| self.decode_text(&part.0)))
+                            }
+                        }
+                        let entry = FileEntry {
+                            path,
+                            length: file.length,
+                            offset,
+                        };
+                        offset = offset.saturating_add(file.length);
+                        entry
+                    })
+                    .collect()
+            }
+        }
+    }
+    pub async fn peers(&self, http: &reqwest::Client) -> anyhow::Result<Vec<SocketAddr>> {
+        let report = self.announce_all(http, &self.tracker_request()).await;
+        if report.trackers.iter().all(|(_, result)| result.is_err()) {
+            let failures: Vec<String> = report
+                .trackers
+                .iter()
+                .filter_map(|(url, result)| Some(format!("{} : {}", url, result.as_ref().err()?)))
+                .collect();
+            bail!("Every tracker failed ({})", failures.join(", "));
+        }
+        Ok(report.peers)
+    }
+    /// Asks for the non-compact peer list, slower but it tells which peer id is behind each
+    /// address, for debugging a swarm
+    pub async fn peers_verbose(
+        &self,
+        http: &reqwest::Client,
+    ) -> anyhow::Result<Vec<(SocketAddr, Option<[u8; 20]>)>> {
+        let mut request = self.tracker_request();
+        request.compact = 0;
+        let response = self.announce(http, &request).await?;
+        Ok(response.peers_with_ids())
+    }
+    /// Announce parameters for a fresh download of this torrent
+    pub fn tracker_request(&self) -> TrackerRequest {
+        TrackerRequest {
             peer_id: String::from("66196841112650955225"),
             port: 6681,
             uploaded: 0,
             downloaded: 0,
             left: self.length(),
             compact: 1,
+            tracker_id: None,
+            event: None,
+            numwant: None,
+        }
+    }
+    pub async fn announce(
+        &self,
+        http: &reqwest::Client,
+        request: &TrackerRequest,
+    ) -> anyhow::Result<TrackerResponse> {
+        let trackers = self.trackers();
+        if trackers.is_empty() {
+            bail!("Torrent is trackerless, it has no announce URL");
+        }
+        // Tier by tier, the first tracker that answers wins whatever transport it uses
+        let mut failures = Vec::new();
+        for url in trackers {
+            match self.announce_to(url, http, request).await {
+                Ok(response) => return Ok(response),
+                Err(err) => failures.push(format!("{} : {}", url, err)),
+            }
+        }
+        bail!("Every tracker failed ({})", failures.join(", "))
+    }
+    /// Announces to every tracker and reports how each of them fared
+    pub async fn announce_all(
+        &self,
+        http: &reqwest::Client,
+        request: &TrackerRequest,
+    ) -> AnnounceReport {
+        let mut report = AnnounceReport::default();
+        for url in self.trackers() {
+            let result = match self.announce_to(url, http, request).await {
+                Ok(response) => {
+                    let addrs = response.resolve_addrs().await;
+                    let count = addrs.len();
+                    for addr in addrs {
+                        if !report.peers.contains(&addr) {
+                            report.peers.push(addr);
+                        }
+                    }
+                    Ok(count)
+                }
+                Err(err) => Err(err),
+            };
+            report.trackers.push((url.to_string(), result));
+        }
+        report
+    }
+    pub fn webseeds(&self) -> Vec<&str> {
+        match &self.url_list {
+            Some(UrlList::One(url)) => vec![url.as_str()],
+            Some(UrlList::Many(urls)) => urls.iter().map(String::as_str).collect(),
+            None => Vec::new(),
+        }
+    }
+    /// Every tracker URL, tier by tier, falling back to `announce` without an announce-list
+    pub fn trackers(&self) -> Vec<&str> {
+        match &self.announce_list {
+            Some(tiers) if !tiers.is_empty() => {
+                tiers.iter().flatten().map(String::as_str).collect()
+            }
+            _ => self.announce.iter().map(String::as_str).collect(),
+        }
+    }
+    // Picks the transport from the URL scheme, announce-lists freely mix them across tiers
+    async fn announce_to(
+        &self,
+        url: &str,
+        http: &reqwest::Client,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let scheme = url
+            .split_once("://")
+            .map(|(scheme, _)| scheme.to_ascii_lowercase());
+        match scheme.as_deref() {
+            Some("http" | "https") => self.announce_http(url, http, request).await,
+            Some("udp") => Err(TrackerError::Unsupported(
+                "UDP trackers aren't supported yet".into(),
+            )),
+            _ => Err(TrackerError::Unsupported(format!(
+                "Unknown tracker scheme in {}",
+                url
+            ))),
+        }
+    }
+    async fn announce_http(
+        &self,
+        url: &str,
+        http: &reqwest::Client,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse, TrackerError> {
+        let info_hash = self
+            .info_hash()
+            .map_err(|err| TrackerError::Request(err.to_string()))?;
+        let info_hash = urlencode(&info_hash);
+
+        let url_params = serde_urlencoded::to_string(request)
+            .map_err(|err| TrackerError::Request(err.to_string()))?;
+        let params = format!("{}&info_hash={}", url_params, info_hash);
+        let mut url = url.to_string();
+        for _ in 0..MAX_REDIRECTS {
+            let response = http.get(announce_url(&url, &params)?).send().await?;
+            // Following the redirect ourselves keeps the binary info_hash from being re-encoded
+            if response.status().is_redirection() {
+                url = redirect_target(&response)?;
+                continue;
+            }
+            let response = response.bytes().await?;
+            return TrackerResponse::decode(&response);
+        }
+        Err(TrackerError::Request(format!(
+            "More than {} redirects",
+            MAX_REDIRECTS
+        )))
+    }
+    /// Magnet URI with the hex info hash, the display name and every tracker
+    pub fn magnet_link(&self) -> anyhow::Result<String> {
+        let mut magnet = format!(
+            "magnet:?xt=urn:btih:{}&dn={}",
+            hex::encode(self.info_hash()?),
+            percent_encode(&self.display_name())
+        );
+        let mut trackers: Vec<&str> = self.announce.iter().map(String::as_str).collect();
+        for tracker in self.trackers() {
+            if !trackers.contains(&tracker) {
+                trackers.push(tracker);
+            }
+        }
+        for tracker in trackers {
+            magnet.push_str("&tr=");
+            magnet.push_str(&percent_encode(tracker));
+        }
+        Ok(magnet)
+    }
+    pub async fn scrape(&self, http: &reqwest::Client) -> anyhow::Result<ScrapeStats> {
+        let info_hash = self.info_hash()?;
+        // By convention the scrape URL swaps the trailing `announce` path segment for `scrape`
+        let announce = self
+            .announce
+            .as_deref()
+            .context("Torrent is trackerless, it has no announce URL")?;
+        let (base, last) = announce
+            .rsplit_once('/')
+            .context("Announce URL has no path")?;
+        let Some(rest) = last.strip_prefix("announce") else {
+            bail!("Tracker doesn't support scrape : {}", announce);
         };
-        let url_params = serde_urlencoded::to_string(&data).context("Params")?;
+        let separator = if rest.contains('?') { '&' } else { '?' };
         let url = format!(
-            "{}?{}&info_hash={}",
-            &self.announce, &url_params, &info_hash
+            "{}/scrape{}{}info_hash={}",
+            base,
+            rest,
+            separator,
+            urlencode(&info_hash)
         );
-        let response = reqwest::get(url).await.context("Query tracker")?;
-        let response = response.bytes().await.context("Fetch tracker response")?;
-        let response: TrackerResponse =
-            serde_bencode::from_bytes(&response).context("Parsing response")?;
+        let response = http.get(url).send().await.context("Query scrape")?;
+        let response = response.bytes().await.context("Fetch scrape response")?;
+        ScrapeStats::decode(&response, &info_hash)
+    }
+}
+
+const MAX_REDIRECTS: usize = 5;
+
+// Declared lengths come from the torrent file, anything past these is rejected before any
+// offset is computed from them
+const MAX_PIECE_LENGTH: usize = 1 << 28;
+const MAX_CONTENT_LENGTH: u64 = 1 << 50;
+
+// Our parameters go after any the tracker embedded in its URL, e.g. a passkey, and the port and
+// path are kept as they are
+fn announce_url(base: &str, params: &str) -> Result<reqwest::Url, TrackerError> {
+    let mut url = reqwest::Url::parse(base)
+        .map_err(|err| TrackerError::Request(format!("Invalid announce URL {} : {}", base, err)))?;
+    let query = match url.query().map(|query| query.trim_matches('&')) {
+        Some(existing) if !existing.is_empty() => format!("{}&{}", existing, params),
+        _ => params.to_string(),
+    };
+    // Already percent-encoded, `set_query` leaves the escapes alone
+    url.set_query(Some(&query));
+    url.set_fragment(None);
+    Ok(url)
+}
+
+// New announce URL of a redirect, without the announce parameters we add back ourselves
+fn redirect_target(response: &reqwest::Response) -> Result<String, TrackerError> {
+    let location = response
+        .headers()
+        .get(reqwest::header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .ok_or_else(|| TrackerError::Invalid("Redirect without a location".to_string()))?;
+    let target = response
+        .url()
+        .join(location)
+        .map_err(|err| TrackerError::Invalid(err.to_string()))?;
+    let target = target.as_str();
+    let Some((base, query)) = target.split_once('?') else {
+        return Ok(target.to_string());
+    };
+    // Keep whatever else the tracker put there, e.g. a passkey
+    const OURS: [&str; 10] = [
+        "info_hash",
+        "peer_id",
+        "port",
+        "uploaded",
+        "downloaded",
+        "left",
+        "compact",
+        "trackerid",
+        "numwant",
+        "event",
+    ];
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !OURS.contains(&pair.split('=').next().unwrap_or("")))
+        .collect();
+    if kept.is_empty() {
+        Ok(base.to_string())
+    } else {
+        Ok(format!("{}?{}", base, kept.join("&")))
+    }
+}
+
+/// Hashes the `info` dict exactly as it appears in the file, without decoding it
+pub fn info_hash_from_bytes(raw: &[u8]) -> anyhow::Result<[u8; 20]> {
+    let mut hasher = Sha1::new();
+    hasher.update(info_bytes(raw)?);
+    Ok(hasher.finalize().into())
+}
+
+// The `info` value of a bencoded torrent file
+fn info_bytes(raw: &[u8]) -> anyhow::Result<&[u8]> {
+    if raw.first() != Some(&b'd') {
+        bail!("Torrent file is not a bencoded dictionary");
+    }
+    let mut pos = 1;
+    while raw.get(pos).context("Unterminated dictionary")? != &b'e' {
+        let key_end = skip_bencode(raw, pos)?;
+        let key = &raw[pos..key_end];
+        let value_end = skip_bencode(raw, key_end)?;
+        if key == b"4:info" {
+            return Ok(&raw[key_end..value_end]);
+        }
+        pos = value_end;
+    }
+    bail!("Torrent file has no info dictionary")
+}
+
+/// Renders any bencoded value as an indented tree, binary strings as hex and `pieces` as a
+/// hash count
+pub fn dump_bencode(raw: &[u8]) -> anyhow::Result<String> {
+    let value: Value = serde_bencode::from_bytes(raw).context("Parsing bencode")?;
+    let mut out = String::new();
+    dump_value(&mut out, &value, None, 0);
+    Ok(out.trim_start().to_string())
+}
+
+fn dump_value(out: &mut String, value: &Value, key: Option<&[u8]>, depth: usize) {
+    match value {
+        Value::Dict(dict) if !dict.is_empty() => {
+            out.push('\n');
+            let mut entries: Vec<_> = dict.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            for (key, value) in entries {
+                let key_text = match std::str::from_utf8(key) {
+                    Ok(text) if !text.chars().any(char::is_control) => text.to_string(),
+                    _ => dump_bytes(key),
+                };
+                out.push_str(&format!("{}{}:", "  ".repeat(depth), key_text));
+                dump_value(out, value, Some(key), depth + 1);
+            }
+        }
+        Value::List(list) if !list.is_empty() => {
+            out.push('\n');
+            for value in list {
+                out.push_str(&format!("{}-", "  ".repeat(depth)));
+                dump_value(out, value, None, depth + 1);
+            }
+        }
+        Value::Dict(_) => out.push_str(" {}\n"),
+        Value::List(_) => out.push_str(" []\n"),
+        Value::Int(int) => out.push_str(&format!(" {}\n", int)),
+        // Thousands of concatenated hashes say nothing when printed
+        Value::Bytes(bytes) if key == Some(b"pieces".as_slice()) => {
+            out.push_str(&format!(" <{} piece hashes>\n", bytes.len() / 20))
+        }
+        Value::Bytes(bytes) => out.push_str(&format!(" {}\n", dump_bytes(bytes))),
+    }
+}
 
-        Ok(response.peers.0)
+// Text is quoted, anything else is shown as hex
+fn dump_bytes(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) if !text.chars().any(char::is_control) => format!("{:?}", text),
+        _ => format!("<{}>", hex::encode(bytes)),
     }
 }
 
+// Returns the position right after the bencoded value starting at `pos`
+fn skip_bencode(raw: &[u8], pos: usize) -> anyhow::Result<usize> {
+    match raw.get(pos).context("Unexpected end of bencode")? {
+        b'i' => {
+            let len = raw[pos..]
+                .iter()
+                .position(|b| *b == b'e')
+                .context("Unterminated integer")?;
+            Ok(pos + len + 1)
+        }
+        b'l' | b'd' => {
+            let mut pos = pos + 1;
+            while raw.get(pos).context("Unterminated list")? != &b'e' {
+                pos = skip_bencode(raw, pos)?;
+            }
+            Ok(pos + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = raw[pos..]
+                .iter()
+                .position(|b| *b == b':')
+                .context("Unterminated string length")?;
+            let len: usize = std::str::from_utf8(&raw[pos..pos + colon])?.parse()?;
+            // The length is whatever the input says, it may be close to usize::MAX
+            let end = (pos + colon + 1)
+                .checked_add(len)
+                .filter(|end| *end <= raw.len())
+                .context("String runs past the end of the bencode")?;
+            Ok(end)
+        }
+        other => bail!("Unexpected bencode byte : {}", other),
+    }
+}
+
+// Escapes everything but RFC 3986 unreserved characters
+pub(crate) fn percent_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for &byte in text.as_bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
 fn urlencode(t: &[u8; 20]) -> String {
     let mut encoded = String::with_capacity(3 * t.len());
     for &byte in t {
@@ -112,7 +689,7 @@ mod hashes {
         where
             E: serde::de::Error,
         {
-            if v.len() % 20 != 0 {
+            if !v.len().is_multiple_of(20) {
                 return Err(E::custom(format!("Length is : {}", v.len())));
             }
             Ok(Hashes(
@@ -141,3 +718,606 @@ mod hashes {
         }
     }
 }
+
+mod raw {
+    use serde::{de::Visitor, Deserialize, Serialize};
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    pub struct RawBytes(pub Vec<u8>);
+
+    struct RawBytesVisitor;
+
+    impl<'de> Visitor<'de> for RawBytesVisitor {
+        type Value = RawBytes;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str("expecting a byte string")
+        }
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(RawBytes(v.to_vec()))
+        }
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(RawBytes(v.as_bytes().to_vec()))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for RawBytes {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(RawBytesVisitor)
+        }
+    }
+    impl Serialize for RawBytes {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::mock;
+
+    #[test]
+    fn info_hash_covers_keys_info_doesnt_know() {
+        let info = b"d6:lengthi5e4:name1:a12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa6:source3:xyze";
+        let mut raw = b"d4:info".to_vec();
+        raw.extend(info);
+        raw.push(b'e');
+        let expected: [u8; 20] = Sha1::digest(info).into();
+        let torrent = Torrent::from_bytes(&raw).unwrap();
+        assert_eq!(torrent.info_hash().unwrap(), expected);
+        assert_eq!(info_hash_from_bytes(&raw).unwrap(), expected);
+        assert_eq!(torrent.info_bytes().unwrap(), info);
+        // Re-encoding loses `source`, and with it the hash
+        let decoded: Torrent = serde_bencode::from_bytes(&raw).unwrap();
+        assert_ne!(decoded.info_hash().unwrap(), expected);
+    }
+
+    #[test]
+    fn info_hash_matches_the_reference_for_real_and_unusual_files() {
+        let sample = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent")).unwrap();
+        let torrent = Torrent::from_bytes(&sample).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash().unwrap()),
+            "d69f91e6b2ae4c542468d1073a71d4ea13879a7f"
+        );
+        // Keys out of order plus one `Info` doesn't know, hashed as written, not as re-sorted
+        let mut raw =
+            b"d4:infod4:name5:a.txt12:piece lengthi32768e6:lengthi12e6:pieces20:".to_vec();
+        raw.extend([1u8; 20]);
+        raw.extend(b"6:source4:TESTee");
+        let torrent = Torrent::from_bytes(&raw).unwrap();
+        assert_eq!(
+            hex::encode(torrent.info_hash().unwrap()),
+            "ba811308e113bb4a14aaea7d716da3d90770461e"
+        );
+    }
+
+    #[test]
+    fn v2_pieces_verify_against_their_piece_layer() {
+        const BLOCK: usize = merkle::BLOCK_SIZE;
+        // Five blocks in pieces of two, the last piece is half empty
+        let content: Vec<u8> = (0..5u8).flat_map(|n| vec![n; BLOCK]).collect();
+        let pieces_root: Hash =
+            hex::decode("02d0b015ee763c012ad0246da46d035d5aeaef792982375394e32a28c7d02712")
+                .unwrap()
+                .try_into()
+                .unwrap();
+        let layer: Vec<u8> = content
+            .chunks(2 * BLOCK)
+            .flat_map(|piece| merkle::root(merkle::leaves(piece), 2, [0; 32]))
+            .collect();
+        let mut raw = format!(
+            "d4:infod6:lengthi{}e4:name1:v12:piece lengthi{}e6:pieces60:",
+            content.len(),
+            2 * BLOCK
+        )
+        .into_bytes();
+        raw.extend([0u8; 60]);
+        raw.extend(b"e12:piece layersd32:");
+        raw.extend(pieces_root);
+        raw.extend(format!("{}:", layer.len()).into_bytes());
+        raw.extend(&layer);
+        raw.extend(b"ee");
+        let torrent = Torrent::from_bytes(&raw).unwrap();
+
+        for (idx, piece) in content.chunks(2 * BLOCK).enumerate() {
+            assert!(torrent
+                .verify_v2_piece(&pieces_root, content.len(), idx, piece)
+                .unwrap());
+        }
+        let mut corrupt = content[..2 * BLOCK].to_vec();
+        corrupt[0] ^= 1;
+        assert!(!torrent
+            .verify_v2_piece(&pieces_root, content.len(), 0, &corrupt)
+            .unwrap());
+        // A piece checked at another index doesn't pass
+        assert!(!torrent
+            .verify_v2_piece(&pieces_root, content.len(), 1, &content[..2 * BLOCK])
+            .unwrap());
+    }
+
+    #[test]
+    fn skip_bencode_rejects_string_lengths_past_the_end() {
+        assert_eq!(skip_bencode(b"4:spamX", 0).unwrap(), 6);
+        assert!(skip_bencode(b"5:spam", 0).is_err());
+        let huge = format!("{}:spam", usize::MAX);
+        assert!(skip_bencode(huge.as_bytes(), 0).is_err());
+    }
+
+    // A tracker answering its nth connection with `responses[n]`, the request targets are
+    // recorded in order
+    async fn http_tracker(responses: Vec<Vec<u8>>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let targets = Arc::new(Mutex::new(Vec::new()));
+        let recorded = targets.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buf = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend(&buf[..n]);
+                }
+                let request = String::from_utf8_lossy(&request);
+                let target = request.split(' ').nth(1).unwrap_or("").to_string();
+                recorded.lock().unwrap().push(target);
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        (base, targets)
+    }
+
+    fn redirect(location: &str) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            location
+        )
+        .into_bytes()
+    }
+
+    fn ok(body: &[u8]) -> Vec<u8> {
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        response.extend(body);
+        response
+    }
+
+    fn announced() -> Vec<u8> {
+        ok(b"d8:intervali1800e5:peers0:e")
+    }
+
+    fn no_redirects() -> reqwest::Client {
+        reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn announce_follows_a_redirect_with_the_binary_info_hash() {
+        let (torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let (base, targets) = http_tracker(vec![redirect("/moved/announce"), announced()]).await;
+        let response = torrent
+            .announce_to(
+                &format!("{}/announce", base),
+                &no_redirects(),
+                &torrent.tracker_request(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.interval, 1800);
+        let targets = targets.lock().unwrap();
+        assert_eq!(targets.len(), 2);
+        let info_hash = format!("info_hash={}", urlencode(&torrent.info_hash().unwrap()));
+        assert!(targets[1].starts_with("/moved/announce?"), "{}", targets[1]);
+        assert!(targets[1].contains(&info_hash), "{}", targets[1]);
+    }
+
+    #[tokio::test]
+    async fn announce_gives_up_after_too_many_redirects() {
+        let (torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let (base, targets) = http_tracker(vec![redirect("/announce"); MAX_REDIRECTS]).await;
+        let err = torrent
+            .announce_to(
+                &format!("{}/announce", base),
+                &no_redirects(),
+                &torrent.tracker_request(),
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("redirects"), "{}", err);
+        assert_eq!(targets.lock().unwrap().len(), MAX_REDIRECTS);
+    }
+
+    #[tokio::test]
+    async fn tracker_id_is_echoed_once_across_a_redirect() {
+        let (torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let first = ok(b"d8:intervali1800e5:peers0:10:tracker id2:t1e");
+        // The tracker's stale copies of our parameters go, its passkey stays
+        let moved = redirect("/moved?passkey=abc&trackerid=old&numwant=1&event=started");
+        let (base, targets) = http_tracker(vec![first, moved, announced()]).await;
+        let url = format!("{}/announce", base);
+        let http = no_redirects();
+        let mut state = crate::tracker::TrackerState::default();
+        let mut request = torrent.tracker_request();
+        state.record(&torrent.announce_to(&url, &http, &request).await.unwrap());
+        assert!(!targets.lock().unwrap()[0].contains("trackerid"));
+
+        request.tracker_id = state.tracker_id().map(str::to_string);
+        request.numwant = Some(30);
+        request.event = Some(crate::tracker::Event::Started);
+        torrent.announce_to(&url, &http, &request).await.unwrap();
+        let targets = targets.lock().unwrap();
+        let query = targets[2].strip_prefix("/moved?").unwrap();
+        let pairs: Vec<&str> = query.split('&').collect();
+        for pair in ["passkey=abc", "trackerid=t1", "numwant=30", "event=started"] {
+            assert_eq!(pairs.iter().filter(|p| **p == pair).count(), 1, "{}", query);
+        }
+        assert_eq!(
+            pairs.iter().filter(|p| p.starts_with("trackerid=")).count(),
+            1
+        );
+        assert_eq!(
+            pairs.iter().filter(|p| p.starts_with("numwant=")).count(),
+            1
+        );
+        assert_eq!(pairs.iter().filter(|p| p.starts_with("event=")).count(), 1);
+    }
+
+    #[test]
+    fn announce_params_go_after_an_existing_query() {
+        let url = |base| announce_url(base, "port=6681&left=0").unwrap().to_string();
+        assert_eq!(
+            url("http://tracker.example:8080/announce?passkey=abc"),
+            "http://tracker.example:8080/announce?passkey=abc&port=6681&left=0"
+        );
+        assert_eq!(
+            url("http://tracker.example/a/announce.php?passkey=abc&#frag"),
+            "http://tracker.example/a/announce.php?passkey=abc&port=6681&left=0"
+        );
+        assert_eq!(
+            url("https://tracker.example:8443/tracker?"),
+            "https://tracker.example:8443/tracker?port=6681&left=0"
+        );
+        assert!(announce_url("not a url", "port=6681").is_err());
+    }
+
+    #[test]
+    fn dump_renders_the_structure_with_pieces_as_a_count() {
+        let mut raw = b"d8:announce12:http://t/ann13:announce-listll12:http://t/annee".to_vec();
+        raw.extend(b"4:infod6:lengthi100e4:name4:mock12:piece lengthi16384e6:pieces40:");
+        raw.extend([0xee; 40]);
+        raw.extend(b"e4:salt2:\x00\xffe");
+        let expected = "\
+announce: \"http://t/ann\"
+announce-list:
+  -
+    - \"http://t/ann\"
+info:
+  length: 100
+  name: \"mock\"
+  piece length: 16384
+  pieces: <2 piece hashes>
+salt: <00ff>
+";
+        assert_eq!(dump_bencode(&raw).unwrap(), expected);
+        assert!(dump_bencode(b"d4:info").is_err());
+    }
+
+    #[test]
+    fn files_list_paths_and_cumulative_offsets() {
+        let files = [
+            ("a.txt", &[1u8; 10][..]),
+            ("sub/b.bin", &[2u8; 300][..]),
+            ("c", &[3u8; 5][..]),
+        ];
+        let (torrent, _) = mock::multi_file_torrent(&files, 1 << 14);
+        let listed: Vec<(PathBuf, usize, usize)> = torrent
+            .files()
+            .into_iter()
+            .map(|file| (file.path, file.length, file.offset))
+            .collect();
+        assert_eq!(
+            listed,
+            vec![
+                (PathBuf::from("mock/a.txt"), 10, 0),
+                (PathBuf::from("mock/sub/b.bin"), 300, 10),
+                (PathBuf::from("mock/c"), 5, 310),
+            ]
+        );
+        // A single file torrent is its one entry
+        let (torrent, _) = mock::torrent(&[0; 100], 1 << 14);
+        let single = torrent.files();
+        assert_eq!(single.len(), 1);
+        assert_eq!((single[0].length, single[0].offset), (100, 0));
+        assert_eq!(single[0].path, PathBuf::from("mock"));
+    }
+
+    #[tokio::test]
+    async fn scrape_reads_the_swarm_counts_from_the_scrape_url() {
+        let torrent = |announce: &str| {
+            let mut raw = format!("d8:announce{}:{}", announce.len(), announce).into_bytes();
+            raw.extend(b"4:infod6:lengthi100e4:name4:mock12:piece lengthi16384e6:pieces20:");
+            raw.extend([7; 20]);
+            raw.extend(b"ee");
+            Torrent::from_bytes(&raw).unwrap()
+        };
+        let unsupported = torrent("http://tracker.example/tracker");
+        let err = unsupported.scrape(&no_redirects()).await.unwrap_err();
+        assert!(
+            err.to_string().contains("doesn't support scrape"),
+            "{}",
+            err
+        );
+
+        // The announce URL is outside the info dict, so the hash is the same for every URL
+        let info_hash = unsupported.info_hash().unwrap();
+        let mut body = b"d5:filesd20:".to_vec();
+        body.extend(info_hash);
+        body.extend(b"d8:completei5e10:downloadedi50e10:incompletei3eeee");
+        let (base, targets) = http_tracker(vec![ok(&body)]).await;
+        let torrent = torrent(&format!("{}/announce?passkey=abc", base));
+        let stats = torrent.scrape(&no_redirects()).await.unwrap();
+        assert_eq!(
+            (stats.complete, stats.incomplete, stats.downloaded),
+            (5, 3, 50)
+        );
+        let target = &targets.lock().unwrap()[0];
+        assert_eq!(
+            *target,
+            format!("/scrape?passkey=abc&info_hash={}", urlencode(&info_hash))
+        );
+    }
+
+    #[tokio::test]
+    async fn peers_and_peers6_of_one_response_are_merged() {
+        let (torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let mut body = b"d8:intervali1800e5:peers12:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+        body.extend(b"6:peers618:");
+        body.extend(std::net::Ipv6Addr::LOCALHOST.octets());
+        body.extend([0x1a, 0xe3]);
+        body.push(b'e');
+        let (base, _) = http_tracker(vec![ok(&body)]).await;
+        let response = torrent
+            .announce_to(
+                &format!("{}/announce", base),
+                &no_redirects(),
+                &torrent.tracker_request(),
+            )
+            .await
+            .unwrap();
+        let addrs: Vec<SocketAddr> = ["10.0.0.1:6881", "10.0.0.2:6882", "[::1]:6883"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        assert_eq!(response.addrs(), addrs);
+    }
+
+    #[test]
+    fn a_shift_jis_name_is_decoded_with_the_declared_encoding() {
+        let torrent = |encoding: &[u8]| {
+            let mut raw = encoding.to_vec();
+            // 日本 in Shift_JIS, not valid UTF-8
+            raw.extend(b"4:infod6:lengthi1e4:name4:\x93\xfa\x96\x7b12:piece lengthi16384e");
+            raw.extend(b"6:pieces20:");
+            raw.extend([7; 20]);
+            raw.extend(b"ee");
+            Torrent::from_bytes(&raw).unwrap()
+        };
+        let declared = torrent(b"d8:encoding9:Shift_JIS");
+        assert_eq!(declared.display_name(), "日本");
+        assert_eq!(declared.files()[0].path, PathBuf::from("日本"));
+        // Without it the name still parses, lossily
+        let undeclared = torrent(b"d");
+        assert!(undeclared.display_name().contains('\u{fffd}'));
+        assert_eq!(
+            undeclared.info_hash().unwrap(),
+            declared.info_hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn magnet_link_of_the_sample_torrent() {
+        let sample = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/sample.torrent")).unwrap();
+        let mut torrent = Torrent::from_bytes(&sample).unwrap();
+        assert_eq!(
+            torrent.magnet_link().unwrap(),
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce"
+        );
+        // The announce URL comes first and isn't repeated for the tier that also lists it
+        torrent.announce_list = Some(vec![
+            vec!["udp://tracker.example:80".to_string()],
+            vec![torrent.announce.clone().unwrap()],
+        ]);
+        assert_eq!(
+            torrent.magnet_link().unwrap(),
+            "magnet:?xt=urn:btih:d69f91e6b2ae4c542468d1073a71d4ea13879a7f&dn=sample.txt\
+             &tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce\
+             &tr=udp%3A%2F%2Ftracker.example%3A80"
+        );
+    }
+
+    #[test]
+    fn a_trackerless_torrent_keeps_its_dht_nodes() {
+        let mut raw = b"d4:infod6:lengthi1e4:name1:a12:piece lengthi16384e6:pieces20:".to_vec();
+        raw.extend([7; 20]);
+        raw.extend(b"e5:nodesll11:router.testi6881eel9:127.0.0.1i6882eeee");
+        let torrent = Torrent::from_bytes(&raw).unwrap();
+        assert_eq!(torrent.announce, None);
+        assert!(torrent.trackers().is_empty());
+        assert_eq!(
+            torrent.nodes,
+            Some(vec![
+                ("router.test".to_string(), 6881),
+                ("127.0.0.1".to_string(), 6882)
+            ])
+        );
+    }
+
+    #[test]
+    fn utf8_names_and_paths_are_preferred_without_changing_the_hash() {
+        let mut info = b"d5:filesld6:lengthi3e4:pathl4:\x93\xfa\x96\x7be".to_vec();
+        info.extend("10:path.utf-8l6:日本ee".as_bytes());
+        info.extend(b"e4:name3:old");
+        info.extend("10:name.utf-86:名前".as_bytes());
+        info.extend(b"12:piece lengthi16384e6:pieces20:");
+        info.extend([7; 20]);
+        info.push(b'e');
+        let mut raw = b"d4:info".to_vec();
+        raw.extend(&info);
+        raw.push(b'e');
+        let torrent = Torrent::from_bytes(&raw).unwrap();
+        assert_eq!(torrent.display_name(), "名前");
+        let files = torrent.files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].path, PathBuf::from("名前/日本"));
+        let expected: [u8; 20] = Sha1::digest(&info).into();
+        assert_eq!(torrent.info_hash().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn announce_all_reports_every_tracker() {
+        let compact = |ports: &[u16]| {
+            let mut body = format!("d8:intervali1800e5:peers{}:", ports.len() * 6).into_bytes();
+            for port in ports {
+                body.extend([10, 0, 0, 1]);
+                body.extend(port.to_be_bytes());
+            }
+            body.push(b'e');
+            ok(&body)
+        };
+        let (first, _) = http_tracker(vec![compact(&[1, 2])]).await;
+        let (refusing, _) = http_tracker(vec![ok(b"d14:failure reason6:bannede")]).await;
+        let (last, _) = http_tracker(vec![compact(&[2, 3])]).await;
+        let (mut torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let urls = [
+            format!("{}/announce", first),
+            format!("{}/announce", refusing),
+            "udp://tracker.example:6969".to_string(),
+            format!("{}/announce", last),
+        ];
+        torrent.announce_list = Some(vec![urls[..2].to_vec(), urls[2..].to_vec()]);
+        let report = torrent
+            .announce_all(&no_redirects(), &torrent.tracker_request())
+            .await;
+        let reported: Vec<&str> = report
+            .trackers
+            .iter()
+            .map(|(url, _)| url.as_str())
+            .collect();
+        assert_eq!(
+            reported,
+            urls.iter().map(String::as_str).collect::<Vec<_>>()
+        );
+        let results: Vec<_> = report.trackers.iter().map(|(_, result)| result).collect();
+        assert!(matches!(results[0], Ok(2)));
+        assert!(matches!(results[1], Err(TrackerError::Failure(reason)) if reason == "banned"));
+        assert!(matches!(results[2], Err(TrackerError::Unsupported(_))));
+        assert!(matches!(results[3], Ok(2)));
+        let ports: Vec<u16> = report.peers.iter().map(SocketAddr::port).collect();
+        assert_eq!(ports, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn a_non_compact_announce_reads_the_dictionary_peers() {
+        let (torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let body = b"d8:intervali1800e5:peersld2:ip8:10.0.0.77:peer id20:aaaaaaaaaaaaaaaaaaaa\
+4:porti6881eed2:ip3:::17:peer id20:bbbbbbbbbbbbbbbbbbbb4:porti6882eeee";
+        let (base, targets) = http_tracker(vec![ok(body)]).await;
+        let mut request = torrent.tracker_request();
+        request.compact = 0;
+        let response = torrent
+            .announce_to(&format!("{}/announce", base), &no_redirects(), &request)
+            .await
+            .unwrap();
+        assert!(targets.lock().unwrap()[0].contains("compact=0"));
+        let peers: Vec<(SocketAddr, Option<[u8; 20]>)> = response.peers_with_ids();
+        assert_eq!(
+            peers,
+            vec![
+                ("10.0.0.7:6881".parse().unwrap(), Some([b'a'; 20])),
+                ("[::1]:6882".parse().unwrap(), Some([b'b'; 20])),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn announce_falls_back_from_a_udp_tier_to_an_http_one() {
+        let mut body = b"d8:intervali1800e5:peers6:".to_vec();
+        body.extend([10, 0, 0, 1, 0x1a, 0xe1]);
+        body.push(b'e');
+        let (base, targets) = http_tracker(vec![ok(&body)]).await;
+        let (mut torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let udp = "udp://tracker.example:6969/announce".to_string();
+        let http = format!("{}/announce", base);
+        torrent.announce_list = Some(vec![vec![udp.clone()], vec![http]]);
+        let request = torrent.tracker_request();
+        let response = torrent.announce(&no_redirects(), &request).await.unwrap();
+        assert_eq!(response.addrs(), vec!["10.0.0.1:6881".parse().unwrap()]);
+        assert_eq!(targets.lock().unwrap().len(), 1);
+        // What the first tier failed with
+        let failure = torrent.announce_to(&udp, &no_redirects(), &request).await;
+        assert!(matches!(failure, Err(TrackerError::Unsupported(_))));
+        // With the HTTP tier gone too, both failures are reported
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = format!("http://{}/announce", closed.local_addr().unwrap());
+        drop(closed);
+        torrent.announce_list = Some(vec![vec![udp.clone()], vec![dead.clone()]]);
+        let err = torrent
+            .announce(&no_redirects(), &request)
+            .await
+            .unwrap_err()
+            .to_string();
+        let unsupported = format!("{} : Unsupported tracker", udp);
+        assert!(err.contains(&unsupported), "{}", err);
+        assert!(err.contains(&dead), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn peers_verbose_asks_for_the_dictionary_form_and_keeps_peer_ids() {
+        let (mut torrent, _) = mock::torrent(&[1u8; 100], 1 << 14);
+        let body = b"d8:intervali1800e5:peersld2:ip8:10.0.0.77:peer id20:aaaaaaaaaaaaaaaaaaaa\
+4:porti6881eed2:ip8:10.0.0.84:porti6882eeee";
+        let (base, targets) = http_tracker(vec![ok(body)]).await;
+        torrent.announce = Some(format!("{}/announce", base));
+        let peers = torrent.peers_verbose(&no_redirects()).await.unwrap();
+        let target = targets.lock().unwrap()[0].clone();
+        assert!(target.contains("compact=0"), "{}", target);
+        assert_eq!(
+            peers,
+            vec![
+                ("10.0.0.7:6881".parse().unwrap(), Some([b'a'; 20])),
+                ("10.0.0.8:6882".parse().unwrap(), None),
+            ]
+        );
+    }
+}
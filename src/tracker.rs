@@ -1,5 +1,18 @@
-use peers::Peers;
+use std::{fmt, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, bail, Context};
+use futures_util::future::join_all;
+use peers::{Peers, Peers6};
+use reqwest::dns::{Name, Resolve};
 use serde::{Deserialize, Serialize};
+use serde_bencode::value::Value;
+use tokio::{net::TcpStream, time::Instant};
+
+use crate::dns;
+
+// How long the addresses of a peer given by hostname get to answer
+const HOST_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone, Serialize)]
 pub struct TrackerRequest {
     pub peer_id: String,
@@ -8,20 +21,305 @@ pub struct TrackerRequest {
     pub downloaded: usize,
     pub left: usize,
     pub compact: u8,
+    // Echoed back from a previous response of the same tracker
+    #[serde(rename = "trackerid", skip_serializing_if = "Option::is_none")]
+    pub tracker_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event: Option<Event>,
+    // How many peers we'd like back, trackers pick their own default without it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub numwant: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+    Started,
+    Completed,
+    Stopped,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
     pub interval: usize,
+    // Seconds the client must wait between announces, even manual ones
+    #[serde(rename = "min interval", default)]
+    pub min_interval: Option<usize>,
+    // Seeders and leechers, when the tracker reports them with the announce
+    #[serde(default)]
+    pub complete: Option<usize>,
+    #[serde(default)]
+    pub incomplete: Option<usize>,
+    #[serde(rename = "tracker id", default)]
+    pub tracker_id: Option<String>,
+    // Non-fatal, the rest of the response is still valid
+    #[serde(rename = "warning message", default)]
+    pub warning_message: Option<String>,
+    #[serde(default)]
     pub peers: Peers,
+    #[serde(default)]
+    pub peers6: Peers6,
+}
+
+impl TrackerResponse {
+    pub fn decode(raw: &[u8]) -> Result<Self, TrackerError> {
+        #[derive(Deserialize)]
+        struct Failure {
+            #[serde(rename = "failure reason")]
+            reason: String,
+        }
+        if let Ok(failure) = serde_bencode::from_bytes::<Failure>(raw) {
+            return Err(TrackerError::Failure(failure.reason));
+        }
+        serde_bencode::from_bytes(raw).map_err(|err| TrackerError::Invalid(err.to_string()))
+    }
+    // IPv4 and IPv6 peers come in separate keys of the same response
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.peers
+            .addrs
+            .iter()
+            .chain(&self.peers6.addrs)
+            .copied()
+            .collect()
+    }
+    /// Bytes past the last whole entry of the compact peer lists, which were ignored
+    pub fn trailing_bytes(&self) -> usize {
+        self.peers.trailing + self.peers6.trailing
+    }
+    /// Every peer address with its peer id, which only a non-compact response carries
+    pub fn peers_with_ids(&self) -> Vec<(SocketAddr, Option<[u8; 20]>)> {
+        self.addrs()
+            .into_iter()
+            .map(|addr| (addr, self.peers.ids.get(&addr).copied()))
+            .collect()
+    }
+    /// Every peer address, with the peers given by hostname resolved to whichever of their
+    /// addresses answers first, those that don't resolve or answer are skipped
+    pub async fn resolve_addrs(&self) -> Vec<SocketAddr> {
+        self.resolve_addrs_with(&dns::Resolver).await
+    }
+    /// Same as `resolve_addrs`, looking the hostnames up with `resolver`
+    pub async fn resolve_addrs_with(&self, resolver: &dyn Resolve) -> Vec<SocketAddr> {
+        let lookups = self.peers.hosts.iter().map(|(host, port)| async move {
+            let name: Name = host.parse()?;
+            let addrs: Vec<SocketAddr> = resolver
+                .resolve(name)
+                .await
+                .map_err(|err| anyhow!(err))?
+                .map(|addr| SocketAddr::new(addr.ip(), *port))
+                .collect();
+            // One address per host, instead of connecting to the same peer once per family
+            let race = dns::connect_staggered(&addrs, dns::CONNECT_STAGGER, TcpStream::connect);
+            let (addr, _) = tokio::time::timeout(HOST_CONNECT_TIMEOUT, race).await??;
+            anyhow::Ok(addr)
+        });
+        let mut addrs = self.addrs();
+        addrs.extend(join_all(lookups).await.into_iter().flatten());
+        addrs
+    }
+}
+
+#[derive(Debug)]
+pub enum TrackerError {
+    // The tracker couldn't be reached or the request couldn't be built
+    Request(String),
+    // The tracker answered with a `failure reason`
+    Failure(String),
+    // The response isn't a valid announce response
+    Invalid(String),
+    // The tracker speaks a protocol we can't announce over
+    Unsupported(String),
+}
+
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrackerError::Request(err) => write!(f, "Tracker request failed : {}", err),
+            TrackerError::Failure(reason) => write!(f, "Tracker refused the announce : {}", reason),
+            TrackerError::Invalid(err) => write!(f, "Invalid tracker response : {}", err),
+            TrackerError::Unsupported(err) => write!(f, "Unsupported tracker : {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TrackerError {}
+
+impl From<reqwest::Error> for TrackerError {
+    fn from(err: reqwest::Error) -> Self {
+        TrackerError::Request(err.to_string())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AnnounceReport {
+    // Peers from every tracker that answered, deduplicated
+    pub peers: Vec<SocketAddr>,
+    // Each tracker URL with the number of peers it returned, or why it failed
+    pub trackers: Vec<(String, Result<usize, TrackerError>)>,
+}
+
+// What the client remembers between announces to the same tracker
+#[derive(Debug, Default)]
+pub struct TrackerState {
+    last_announce: Option<Instant>,
+    min_interval: Duration,
+    // Regular re-announce period the tracker asked for
+    interval: Duration,
+    // `completed` goes out once per download, never again after
+    sent_completed: bool,
+    // Announces that failed in a row since the last successful one
+    failures: u32,
+    // Replaces the torrent's peer_id once it has been rotated
+    peer_id: Option<String>,
+    // Stateful trackers expect this back on every later announce
+    tracker_id: Option<String>,
+    // Seeders and leechers as of the last announce
+    complete: Option<usize>,
+    incomplete: Option<usize>,
+    // Warning the last announce came with, if any
+    warning: Option<String>,
+}
+
+impl TrackerState {
+    pub fn record(&mut self, response: &TrackerResponse) {
+        self.last_announce = Some(Instant::now());
+        self.min_interval = Duration::from_secs(response.min_interval.unwrap_or(0) as u64);
+        self.interval = Duration::from_secs(response.interval as u64);
+        self.failures = 0;
+        self.complete = response.complete;
+        self.incomplete = response.incomplete;
+        self.warning = response.warning_message.clone();
+        // A response without an id doesn't invalidate the one we already have
+        if let Some(tracker_id) = &response.tracker_id {
+            self.tracker_id = Some(tracker_id.clone());
+        }
+    }
+    /// Counts a failed announce, picking a fresh peer_id after `rotate_after` failures in a row.
+    /// Returns true when it did, the new one is in `peer_id`
+    pub fn record_failure(&mut self, rotate_after: Option<u32>) -> bool {
+        self.failures += 1;
+        let Some(rotate_after) = rotate_after else {
+            return false;
+        };
+        if self.failures < rotate_after {
+            return false;
+        }
+        let peer_id: String = (0..20)
+            .map(|_| char::from(b'0' + rand::random::<u8>() % 10))
+            .collect();
+        self.peer_id = Some(peer_id);
+        self.failures = 0;
+        true
+    }
+    pub fn peer_id(&self) -> Option<&str> {
+        self.peer_id.as_deref()
+    }
+    pub fn tracker_id(&self) -> Option<&str> {
+        self.tracker_id.as_deref()
+    }
+    /// Warning message of the last successful announce
+    pub fn warning(&self) -> Option<&str> {
+        self.warning.as_deref()
+    }
+    /// Seeders and leechers reported by the last announce, if the tracker sent them
+    pub fn swarm(&self) -> (Option<usize>, Option<usize>) {
+        (self.complete, self.incomplete)
+    }
+    /// Whether the tracker's regular interval has passed since the last announce
+    pub fn announce_due(&self) -> bool {
+        self.last_announce
+            .is_some_and(|last| last.elapsed() >= self.interval)
+    }
+    /// When the regular interval since the last announce runs out
+    pub fn announce_due_at(&self) -> Option<Instant> {
+        self.last_announce.map(|last| last + self.interval)
+    }
+    /// Returns true the first time only
+    pub fn take_completed(&mut self) -> bool {
+        !std::mem::replace(&mut self.sent_completed, true)
+    }
+    pub fn has_announced(&self) -> bool {
+        self.last_announce.is_some()
+    }
+    /// Earliest point the tracker allows us to announce again
+    pub fn next_announce(&self) -> Option<Instant> {
+        self.last_announce.map(|last| last + self.min_interval)
+    }
+    pub async fn wait_for_announce(&self) {
+        if let Some(next) = self.next_announce() {
+            tokio::time::sleep_until(next).await;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    // Seeders
+    pub complete: usize,
+    // Leechers
+    pub incomplete: usize,
+    // Number of times the torrent was fully downloaded
+    pub downloaded: usize,
+}
+
+impl ScrapeStats {
+    // The `files` dict is keyed by raw info hashes, which don't fit a derived struct
+    pub fn decode(raw: &[u8], info_hash: &[u8; 20]) -> anyhow::Result<Self> {
+        let Value::Dict(root) = serde_bencode::from_bytes(raw).context("Parsing scrape")? else {
+            bail!("Scrape response is not a dictionary");
+        };
+        if let Some(Value::Bytes(reason)) = root.get(b"failure reason".as_slice()) {
+            bail!("Scrape failed : {}", String::from_utf8_lossy(reason));
+        }
+        let Some(Value::Dict(files)) = root.get(b"files".as_slice()) else {
+            bail!("Scrape response has no files");
+        };
+        let Some(Value::Dict(stats)) = files.get(info_hash.as_slice()) else {
+            bail!("Tracker has no scrape data for this torrent");
+        };
+        let field = |key: &[u8]| match stats.get(key) {
+            Some(Value::Int(count)) => Ok(*count as usize),
+            _ => Err(anyhow!(
+                "Scrape is missing : {}",
+                String::from_utf8_lossy(key)
+            )),
+        };
+        Ok(Self {
+            complete: field(b"complete")?,
+            incomplete: field(b"incomplete")?,
+            downloaded: field(b"downloaded")?,
+        })
+    }
 }
 
 mod peers {
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    };
 
-    use serde::{de::Visitor, Deserialize, Serialize};
-    #[derive(Debug, Clone)]
-    pub struct Peers(pub Vec<SocketAddrV4>);
+    use serde::{
+        de::{SeqAccess, Visitor},
+        Deserialize, Serialize,
+    };
+    use serde_bencode::value::Value;
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers {
+        pub addrs: Vec<SocketAddr>,
+        // Dictionary peers whose `ip` is a hostname, resolved before connecting
+        pub hosts: Vec<(String, u16)>,
+        // Only the dictionary form carries peer ids
+        pub ids: HashMap<SocketAddr, [u8; 20]>,
+        // Bytes after the last whole compact entry, some trackers append a stray one
+        pub trailing: usize,
+    }
+
+    // Compact IPv6 peers from the `peers6` key, 16 bytes of ip followed by 2 bytes of port
+    #[derive(Debug, Clone, Default)]
+    pub struct Peers6 {
+        pub addrs: Vec<SocketAddr>,
+        pub trailing: usize,
+    }
 
     struct PeersVisitor;
 
@@ -35,19 +333,80 @@ mod peers {
         where
             E: serde::de::Error,
         {
-            if v.len() % 6 != 0 {
-                return Err(E::custom(format!("Length is : {}", v.len())));
-            }
-            Ok(Peers(
-                v.chunks_exact(6)
+            // Some trackers append a stray byte, which shouldn't cost every other peer
+            Ok(Peers {
+                addrs: v
+                    .chunks_exact(6)
                     .map(|chunk| {
-                        SocketAddrV4::new(
-                            Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]),
+                        SocketAddr::new(
+                            Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]).into(),
                             u16::from_be_bytes(chunk[4..].try_into().expect("Can't panic")),
                         )
                     })
                     .collect(),
-            ))
+                hosts: Vec::new(),
+                ids: HashMap::new(),
+                trailing: v.len() % 6,
+            })
+        }
+        // Trackers may ignore `compact=1`, so the dictionary form is accepted either way
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: SeqAccess<'de>,
+        {
+            let mut peers = Peers::default();
+            while let Some(entry) = seq.next_element::<PeerEntry>()? {
+                let Ok(ip) = entry.ip.parse::<IpAddr>() else {
+                    peers.hosts.push((entry.ip, entry.port));
+                    continue;
+                };
+                let addr = SocketAddr::new(ip, entry.port);
+                peers.addrs.push(addr);
+                if let Some(Value::Bytes(id)) = entry.peer_id {
+                    if let Ok(id) = id.try_into() {
+                        peers.ids.insert(addr, id);
+                    }
+                }
+            }
+            Ok(peers)
+        }
+    }
+
+    // Non-compact form, a list of dictionaries
+    #[derive(Deserialize)]
+    struct PeerEntry {
+        ip: String,
+        port: u16,
+        #[serde(rename = "peer id", default)]
+        peer_id: Option<Value>,
+    }
+
+    struct Peers6Visitor;
+
+    impl<'de> Visitor<'de> for Peers6Visitor {
+        type Value = Peers6;
+        fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+            formatter.write_str(
+                "expecting 18 bytes, the first 16 are the ip and the last 2 are the port",
+            )
+        }
+        fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+        where
+            E: serde::de::Error,
+        {
+            Ok(Peers6 {
+                addrs: v
+                    .chunks_exact(18)
+                    .map(|chunk| {
+                        let ip: [u8; 16] = chunk[..16].try_into().expect("Can't panic");
+                        SocketAddr::new(
+                            Ipv6Addr::from(ip).into(),
+                            u16::from_be_bytes(chunk[16..].try_into().expect("Can't panic")),
+                        )
+                    })
+                    .collect(),
+                trailing: v.len() % 18,
+            })
         }
     }
 
@@ -59,17 +418,156 @@ mod peers {
             deserializer.deserialize_bytes(PeersVisitor)
         }
     }
+    impl<'de> Deserialize<'de> for Peers6 {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: serde::Deserializer<'de>,
+        {
+            deserializer.deserialize_bytes(Peers6Visitor)
+        }
+    }
     impl Serialize for Peers {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
             S: serde::Serializer,
         {
-            let mut single_slice = Vec::with_capacity(self.0.len() * 6);
-            for peer in &self.0 {
-                single_slice.extend(peer.ip().octets());
-                single_slice.extend(peer.port().to_be_bytes());
+            let mut single_slice = Vec::with_capacity(self.addrs.len() * 6);
+            for peer in &self.addrs {
+                if let SocketAddr::V4(peer) = peer {
+                    single_slice.extend(peer.ip().octets());
+                    single_slice.extend(peer.port().to_be_bytes());
+                }
             }
             serializer.serialize_bytes(&single_slice)
         }
     }
+    impl Serialize for Peers6 {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            let mut single_slice = Vec::with_capacity(self.addrs.len() * 18);
+            for peer in &self.addrs {
+                if let SocketAddr::V6(peer) = peer {
+                    single_slice.extend(peer.ip().octets());
+                    single_slice.extend(peer.port().to_be_bytes());
+                }
+            }
+            serializer.serialize_bytes(&single_slice)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An announce response around a compact `peers` string
+    fn response(peers: &[u8]) -> Vec<u8> {
+        let mut raw = format!("d8:intervali1800e5:peers{}:", peers.len()).into_bytes();
+        raw.extend(peers);
+        raw.push(b'e');
+        raw
+    }
+
+    #[test]
+    fn warning_message_is_kept_with_the_rest_of_the_response() {
+        let raw = b"d8:intervali900e15:warning message10:slow down!5:peers0:e";
+        let response = TrackerResponse::decode(raw).unwrap();
+        assert_eq!(response.warning_message.as_deref(), Some("slow down!"));
+        assert_eq!(response.interval, 900);
+        let mut state = TrackerState::default();
+        state.record(&response);
+        assert_eq!(state.warning(), Some("slow down!"));
+        // The next announce without one clears it
+        state.record(&TrackerResponse::decode(b"d8:intervali900e5:peers0:e").unwrap());
+        assert_eq!(state.warning(), None);
+    }
+
+    #[tokio::test]
+    async fn an_announce_before_min_interval_waits_for_it() {
+        let mut state = TrackerState::default();
+        let started = Instant::now();
+        state.wait_for_announce().await;
+        assert!(started.elapsed() < Duration::from_millis(100));
+
+        let raw = b"d8:intervali1800e12:min intervali1e5:peers0:e";
+        state.record(&TrackerResponse::decode(raw).unwrap());
+        assert!(!state.announce_due());
+        let started = Instant::now();
+        state.wait_for_announce().await;
+        assert!(started.elapsed() >= Duration::from_millis(900));
+    }
+
+    #[test]
+    fn peer_id_rotates_after_the_configured_failures() {
+        let mut state = TrackerState::default();
+        assert!(!state.record_failure(None));
+        assert!(!state.record_failure(Some(3)));
+        assert_eq!(state.peer_id(), None);
+        assert!(state.record_failure(Some(3)));
+        let peer_id = state.peer_id().unwrap();
+        assert_eq!(peer_id.len(), 20);
+        assert!(peer_id.bytes().all(|b| b.is_ascii_digit()));
+    }
+
+    #[test]
+    fn a_stray_byte_after_compact_peers_is_counted_not_fatal() {
+        let mut peers = vec![127, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2];
+        peers.push(0xff);
+        let response = TrackerResponse::decode(&response(&peers)).unwrap();
+        assert_eq!(
+            response.addrs(),
+            vec![
+                "127.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+        assert_eq!(response.trailing_bytes(), 1);
+    }
+
+    #[test]
+    fn swarm_counts_come_from_the_last_announce() {
+        let raw = b"d8:completei12e10:incompletei34e8:intervali1800e5:peers0:e";
+        let reported = TrackerResponse::decode(raw).unwrap();
+        assert_eq!(
+            (reported.complete, reported.incomplete),
+            (Some(12), Some(34))
+        );
+        let mut state = TrackerState::default();
+        assert_eq!(state.swarm(), (None, None));
+        state.record(&reported);
+        assert_eq!(state.swarm(), (Some(12), Some(34)));
+        // A tracker that stops reporting them doesn't leave stale counts behind
+        state.record(&TrackerResponse::decode(&response(&[])).unwrap());
+        assert_eq!(state.swarm(), (None, None));
+    }
+
+    #[tokio::test]
+    async fn hostname_peers_are_looked_up_with_the_given_resolver() {
+        // Only knows `peer.test`, which it points at a local listener
+        struct Hosts(SocketAddr);
+        impl Resolve for Hosts {
+            fn resolve(&self, name: Name) -> reqwest::dns::Resolving {
+                let found = (name.as_str() == "peer.test").then_some(self.0);
+                Box::pin(async move {
+                    let addr = found.ok_or("unknown host")?;
+                    Ok(Box::new(std::iter::once(addr)) as reqwest::dns::Addrs)
+                })
+            }
+        }
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        let raw = format!(
+            "d8:intervali1800e5:peersld2:ip9:peer.test4:porti{}ee\
+             d2:ip12:missing.test4:porti1eed2:ip8:10.0.0.14:porti6881eeee",
+            local.port()
+        );
+        let response = TrackerResponse::decode(raw.as_bytes()).unwrap();
+        let addrs = response
+            .resolve_addrs_with(&Hosts(SocketAddr::from(([127, 0, 0, 1], 0))))
+            .await;
+        // The resolved address takes the port the tracker gave, the unknown host is skipped
+        assert_eq!(addrs, vec!["10.0.0.1:6881".parse().unwrap(), local]);
+    }
 }
@@ -0,0 +1,121 @@
+use anyhow::{bail, Context};
+use reqwest::{header, StatusCode};
+
+use crate::torrent::{percent_encode, Keys, Torrent};
+
+// Part of a piece as it's stored in one file on a web seed (BEP 19)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub url: String,
+    // Byte range within that file
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Maps the content range `start..end` to the files that hold it on the web seed at `base`
+pub fn spans(torrent: &Torrent, base: &str, start: usize, end: usize) -> Vec<Span> {
+    let single = matches!(torrent.info.keys, Keys::SingleFile { .. });
+    torrent
+        .files()
+        .into_iter()
+        .filter(|file| file.offset < end && file.offset + file.length > start)
+        .map(|file| {
+            // A single file torrent's URL names the file itself, unless it ends with a slash
+            let url = if single && !base.ends_with('/') {
+                base.to_string()
+            } else {
+                let parts: Vec<String> = file
+                    .path
+                    .iter()
+                    .map(|part| percent_encode(&part.to_string_lossy()))
+                    .collect();
+                format!("{}/{}", base.trim_end_matches('/'), parts.join("/"))
+            };
+            Span {
+                url,
+                start: start.max(file.offset) - file.offset,
+                end: end.min(file.offset + file.length) - file.offset,
+            }
+        })
+        .collect()
+}
+
+/// Fetches every span and concatenates them, failing on any short or misplaced response
+pub async fn fetch(http: &reqwest::Client, spans: &[Span]) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for span in spans {
+        data.extend(fetch_range(http, span).await?);
+    }
+    Ok(data)
+}
+
+async fn fetch_range(http: &reqwest::Client, span: &Span) -> anyhow::Result<Vec<u8>> {
+    let response = http
+        .get(&span.url)
+        .header(
+            header::RANGE,
+            format!("bytes={}-{}", span.start, span.end - 1),
+        )
+        .send()
+        .await
+        .context("Query web seed")?;
+    let status = response.status();
+    let partial = match status {
+        StatusCode::PARTIAL_CONTENT => {
+            let range = response
+                .headers()
+                .get(header::CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok())
+                .context("Web seed sent a partial response without a Content-Range")?;
+            let expected = format!("bytes {}-{}/", span.start, span.end - 1);
+            if !range.starts_with(&expected) {
+                bail!("Web seed sent the wrong range : {}", range);
+            }
+            true
+        }
+        // The server ignored the range and sends the whole file
+        StatusCode::OK => false,
+        status => bail!("Web seed answered : {}", status),
+    };
+    let body = response.bytes().await.context("Fetch web seed response")?;
+    let body = if partial {
+        &body[..]
+    } else {
+        body.get(span.start..span.end)
+            .context("Web seed file is shorter than expected")?
+    };
+    if body.len() != span.end - span.start {
+        bail!(
+            "Web seed sent {} bytes, expected {}",
+            body.len(),
+            span.end - span.start
+        );
+    }
+    Ok(body.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use super::*;
+    use crate::mock::{self, WebseedFault};
+
+    #[tokio::test]
+    async fn a_truncated_range_is_an_error() {
+        let content: Vec<u8> = (0..1000u32).map(|n| n as u8).collect();
+        let (url, requests) = mock::webseed(content.clone(), vec![(0, WebseedFault::Truncated)])
+            .await
+            .unwrap();
+        let http = reqwest::Client::new();
+        let spans = [Span {
+            url,
+            start: 100,
+            end: 600,
+        }];
+        let err = fetch(&http, &spans).await.unwrap_err();
+        assert!(err.to_string().contains("expected 500"), "{}", err);
+        assert_eq!(fetch(&http, &spans).await.unwrap(), content[100..600]);
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+}